@@ -0,0 +1,155 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Longest reason a child can give
+pub const MAX_REASON_LEN: usize = 500;
+
+/// Extra time a child asks for when they don't say how much
+pub const DEFAULT_REQUEST_MINUTES: u32 = 30;
+
+/// Most requests one device can have waiting for an answer
+pub const MAX_PENDING_PER_DEVICE: usize = 5;
+
+// How long answered requests are kept, for the child's page and the parents' list
+const DECIDED_RETENTION_DAYS: i64 = 30;
+
+/// Where an access request stands
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessStatus {
+    Pending,
+    /// A parent granted extra time on the rule
+    Approved,
+    Denied,
+}
+
+/// A child's request to lift a block for a while, sent from their own device
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+#[schema(example = json!({
+    "id": "1700000000000",
+    "rule_id": "1642781234567",
+    "mac": "aa:bb:cc:dd:ee:ff",
+    "device_name": "Emma's iPad",
+    "apps": ["youtube"],
+    "reason": "Need a video for my science project",
+    "minutes": 30,
+    "status": "approved",
+    "requested_at": "2024-01-15T17:55:00Z",
+    "decided_by": "mom",
+    "decided_at": "2024-01-15T18:00:00Z",
+    "granted_minutes": 20
+}))]
+pub struct AccessRequest {
+    /// Unique identifier
+    pub id: String,
+    /// Rule the child wants lifted
+    pub rule_id: String,
+    /// Device the request came from
+    pub mac: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Name the controller knows the device by
+    pub device_name: Option<String>,
+    #[serde(default)]
+    /// What the rule blocked when the request was made
+    pub apps: Vec<String>,
+    /// Why the child is asking
+    pub reason: String,
+    /// Minutes asked for
+    pub minutes: u32,
+    pub status: AccessStatus,
+    /// When the request was made (ISO 8601)
+    pub requested_at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Account that approved or denied it
+    pub decided_by: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// When it was approved or denied (ISO 8601)
+    pub decided_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Minutes actually granted, which may differ from those asked for
+    pub granted_minutes: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Note from the parent shown to the child
+    pub note: Option<String>,
+}
+
+impl AccessRequest {
+    // Answered before `cutoff`. Requests with an unreadable answer time count
+    // as old, so they don't stay forever.
+    fn decided_before(&self, cutoff: DateTime<Utc>) -> bool {
+        self.status != AccessStatus::Pending
+            && self.decided_at
+                .as_deref()
+                .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+                .map(|t| t < cutoff)
+                .unwrap_or(true)
+    }
+}
+
+/// Drop requests answered longer ago than the retention period. Returns how
+/// many went.
+pub fn expire_decided(requests: &mut Vec<AccessRequest>, now: DateTime<Utc>) -> usize {
+    let cutoff = now - Duration::days(DECIDED_RETENTION_DAYS);
+    let before = requests.len();
+    requests.retain(|r| !r.decided_before(cutoff));
+    before - requests.len()
+}
+
+/// Requests from a device still waiting for an answer
+pub fn pending_for(requests: &[AccessRequest], mac: &str) -> usize {
+    requests.iter().filter(|r| r.mac == mac && r.status == AccessStatus::Pending).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(id: &str, mac: &str, status: AccessStatus, decided_at: Option<DateTime<Utc>>) -> AccessRequest {
+        AccessRequest {
+            id: id.to_string(),
+            rule_id: "1".to_string(),
+            mac: mac.to_string(),
+            device_name: None,
+            apps: vec!["youtube".to_string()],
+            reason: "homework".to_string(),
+            minutes: DEFAULT_REQUEST_MINUTES,
+            status,
+            requested_at: Utc::now().to_rfc3339(),
+            decided_by: decided_at.map(|_| "mom".to_string()),
+            decided_at: decided_at.map(|t| t.to_rfc3339()),
+            granted_minutes: None,
+            note: None,
+        }
+    }
+
+    #[test]
+    fn answered_requests_expire_after_the_retention_period() {
+        let now = Utc::now();
+        let old = now - Duration::days(DECIDED_RETENTION_DAYS + 1);
+        let mut requests = vec![
+            request("old-approved", "a", AccessStatus::Approved, Some(old)),
+            request("old-denied", "a", AccessStatus::Denied, Some(old)),
+            request("recent", "a", AccessStatus::Denied, Some(now - Duration::days(1))),
+            request("waiting", "a", AccessStatus::Pending, None),
+        ];
+
+        assert_eq!(expire_decided(&mut requests, now), 2);
+        let kept: Vec<_> = requests.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(kept, ["recent", "waiting"]);
+        assert_eq!(expire_decided(&mut requests, now), 0);
+    }
+
+    #[test]
+    fn pending_requests_are_counted_per_device() {
+        let requests = vec![
+            request("1", "a", AccessStatus::Pending, None),
+            request("2", "a", AccessStatus::Pending, None),
+            request("3", "a", AccessStatus::Denied, Some(Utc::now())),
+            request("4", "b", AccessStatus::Pending, None),
+        ];
+        assert_eq!(pending_for(&requests, "a"), 2);
+        assert_eq!(pending_for(&requests, "b"), 1);
+        assert_eq!(pending_for(&requests, "c"), 0);
+    }
+}
@@ -0,0 +1,536 @@
+use crate::storage;
+use crate::unifi::{UnifiClient, UnifiError};
+use log::{info, warn};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fs;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use utoipa::ToSchema;
+
+// Last catalog fetched from the controller, kept in the data directory
+pub const CATALOG_FILE: &str = "app_catalog.json";
+
+// Hand-edited additions to the catalog: extra apps, aliases and bundles
+pub const OVERLAY_FILE: &str = "apps.toml";
+
+// Apps offered before the controller's DPI database has been read. Their
+// names are also what the UI's quick actions ask for.
+const BUILTIN_APPS: &[(&str, &str, &str)] = &[
+    ("fortnite", "655369", "Gaming"),
+    ("roblox", "851993", "Gaming"),
+    ("minecraft", "655370", "Gaming"),
+    ("twitch", "655328", "Gaming"),
+    ("discord", "655365", "Gaming"),
+    ("youtube", "851969", "Video"),
+    ("tiktok", "855327", "Video"),
+    ("netflix", "655324", "Video"),
+    ("instagram", "655311", "Social"),
+    ("snapchat", "655301", "Social"),
+];
+
+/// App that can be blocked
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+#[schema(example = json!({
+    "name": "YouTube",
+    "id": "851969",
+    "category": "Media streaming services"
+}))]
+pub struct CatalogApp {
+    /// Name as shown by the controller
+    pub name: String,
+    /// DPI application ID
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// DPI category
+    pub category: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// Other names accepted in block rules, from apps.toml
+    pub aliases: Vec<String>,
+}
+
+/// Named group of apps that block rules can use like a single app
+#[derive(Serialize, Clone, Debug, ToSchema)]
+#[schema(example = json!({
+    "name": "gaming",
+    "apps": ["fortnite", "roblox", "mc", "steam"]
+}))]
+pub struct AppBundle {
+    pub name: String,
+    /// App names or aliases in the bundle
+    pub apps: Vec<String>,
+}
+
+// Contents of apps.toml:
+//
+//   [apps.steam]                  # app the controller doesn't list
+//   id = "655400"
+//   category = "Gaming"
+//   aliases = ["valve"]
+//
+//   [apps.youtube]                # recategorize an existing app
+//   category = "Video"
+//
+//   [aliases]
+//   mc = "minecraft"
+//
+//   [bundles]
+//   gaming = ["fortnite", "roblox", "mc", "steam"]
+#[derive(Clone, Default)]
+struct Overlay {
+    apps: Vec<OverlayApp>,
+    aliases: Vec<(String, String)>,
+    bundles: Vec<AppBundle>,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OverlayApp {
+    /// From the `[apps.<name>]` header
+    #[serde(skip)]
+    name: String,
+    #[serde(default, deserialize_with = "dpi_id")]
+    id: Option<String>,
+    category: Option<String>,
+    #[serde(default)]
+    aliases: Vec<String>,
+}
+
+// apps.toml as written, with each table's entries in file order
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OverlayFile {
+    #[serde(default, deserialize_with = "in_file_order")]
+    apps: Vec<(String, OverlayApp)>,
+    #[serde(default, deserialize_with = "in_file_order")]
+    aliases: Vec<(String, String)>,
+    #[serde(default, deserialize_with = "in_file_order")]
+    bundles: Vec<(String, Vec<String>)>,
+}
+
+impl Overlay {
+    fn load(path: &Path) -> Result<Self, String> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Self::parse(&content).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn parse(content: &str) -> Result<Self, String> {
+        let file: OverlayFile = toml::from_str(content).map_err(|e| e.to_string())?;
+        Ok(Self {
+            apps: file.apps.into_iter().map(|(name, app)| OverlayApp { name, ..app }).collect(),
+            aliases: file.aliases,
+            bundles: file.bundles.into_iter().map(|(name, apps)| AppBundle { name, apps }).collect(),
+        })
+    }
+}
+
+/// Blockable apps, from the controller when available, plus apps.toml
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AppCatalog {
+    /// Apps from the controller or built in, before apps.toml is applied
+    pub apps: Vec<CatalogApp>,
+    /// "builtin" or "controller"
+    pub source: String,
+    pub updated_at: String,
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    overlay_path: PathBuf,
+    #[serde(skip)]
+    overlay: Overlay,
+    // `apps` with the overlay applied, which is what lookups use
+    #[serde(skip)]
+    merged: Vec<CatalogApp>,
+}
+
+impl AppCatalog {
+    /// The cached controller catalog, or the built-in apps if there is none,
+    /// merged with the overlay file. Fails only if the overlay is invalid.
+    pub fn load(path: PathBuf, overlay_path: PathBuf) -> Result<Self, String> {
+        let overlay = Overlay::load(&overlay_path)?;
+
+        let mut catalog = None;
+        if path.exists() {
+            let cached = fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|content| serde_json::from_str::<AppCatalog>(&content).map_err(|e| e.to_string()));
+            match cached {
+                Ok(cached) => {
+                    info!("📦 Loaded {} apps from cached catalog", cached.apps.len());
+                    catalog = Some(cached);
+                }
+                Err(e) => warn!("⚠️ Ignoring unreadable app catalog {}: {}", path.display(), e),
+            }
+        }
+
+        let mut catalog = catalog.unwrap_or_else(|| Self {
+            apps: builtin_apps(),
+            source: "builtin".to_string(),
+            updated_at: chrono::Utc::now().to_rfc3339(),
+            path: PathBuf::new(),
+            overlay_path: PathBuf::new(),
+            overlay: Overlay::default(),
+            merged: Vec::new(),
+        });
+        catalog.path = path;
+        catalog.overlay_path = overlay_path;
+        catalog.overlay = overlay;
+        catalog.merge();
+        if !catalog.overlay.apps.is_empty() || !catalog.overlay.aliases.is_empty() || !catalog.overlay.bundles.is_empty() {
+            info!(
+                "📦 Applied {}: {} apps, {} aliases, {} bundles",
+                catalog.overlay_path.display(),
+                catalog.overlay.apps.len(),
+                catalog.overlay.aliases.len(),
+                catalog.overlay.bundles.len()
+            );
+        }
+        Ok(catalog)
+    }
+
+    /// Read the overlay file again, keeping the current one if it's invalid
+    pub fn reload_overlay(&mut self) -> Result<(), String> {
+        self.overlay = Overlay::load(&self.overlay_path)?;
+        self.merge();
+        Ok(())
+    }
+
+    pub fn bundles(&self) -> &[AppBundle] {
+        &self.overlay.bundles
+    }
+
+    // Rebuild `merged` from `apps` and the overlay
+    fn merge(&mut self) {
+        let mut merged = self.apps.clone();
+
+        for custom in &self.overlay.apps {
+            let existing = merged.iter().position(|a| same_name(&a.name, &custom.name));
+            match (existing, &custom.id) {
+                (Some(index), _) => {
+                    let app = &mut merged[index];
+                    if let Some(id) = &custom.id {
+                        app.id = id.clone();
+                    }
+                    if custom.category.is_some() {
+                        app.category = custom.category.clone();
+                    }
+                    app.aliases.extend(custom.aliases.iter().cloned());
+                }
+                (None, Some(id)) => merged.push(CatalogApp {
+                    name: custom.name.clone(),
+                    id: id.clone(),
+                    category: custom.category.clone(),
+                    aliases: custom.aliases.clone(),
+                }),
+                (None, None) => warn!("⚠️ apps.toml: [apps.{}] has no id and matches no known app", custom.name),
+            }
+        }
+
+        for (alias, target) in &self.overlay.aliases {
+            match merged.iter_mut().find(|a| same_name(&a.name, target)) {
+                Some(app) => app.aliases.push(alias.clone()),
+                None => warn!("⚠️ apps.toml: alias '{}' points at unknown app '{}'", alias, target),
+            }
+        }
+
+        merged.sort_by_key(|a| a.name.to_lowercase());
+        self.merged = merged;
+    }
+
+    /// Replace the catalog with the controller's DPI database and cache it
+    pub async fn refresh(&mut self, unifi: &UnifiClient, site: &str) -> Result<usize, UnifiError> {
+        let categories = unifi.list_dpi_categories(site).await?;
+        let mut apps: Vec<CatalogApp> = unifi.list_dpi_applications(site).await?
+            .into_iter()
+            .map(|app| CatalogApp {
+                id: app.dpi_id().to_string(),
+                category: categories.iter()
+                    .find(|c| Some(c.id) == app.category_id || c.id == app.dpi_id() >> 16)
+                    .map(|c| c.name.clone()),
+                name: app.name,
+                aliases: Vec::new(),
+            })
+            .collect();
+        if apps.is_empty() {
+            return Err(UnifiError::InvalidResponse("DPI application list is empty".to_string()));
+        }
+
+        // Keep built-in names the controller spells differently, so quick actions still resolve
+        for builtin in builtin_apps() {
+            if !apps.iter().any(|a| same_name(&a.name, &builtin.name)) {
+                apps.push(builtin);
+            }
+        }
+        apps.sort_by_key(|a| a.name.to_lowercase());
+
+        self.apps = apps;
+        self.source = "controller".to_string();
+        self.updated_at = chrono::Utc::now().to_rfc3339();
+        self.merge();
+        match serde_json::to_string_pretty(self) {
+            Ok(content) => {
+                if let Err(e) = storage::write_atomic(&self.path, content.as_bytes()) {
+                    warn!("⚠️ Failed to cache app catalog: {}", e);
+                }
+            }
+            Err(e) => warn!("⚠️ Failed to serialize app catalog: {}", e),
+        }
+        Ok(self.merged.len())
+    }
+
+    /// App by name or alias
+    pub fn find(&self, name: &str) -> Option<&CatalogApp> {
+        self.merged
+            .iter()
+            .find(|a| same_name(&a.name, name))
+            .or_else(|| self.merged.iter().find(|a| a.aliases.iter().any(|alias| same_name(alias, name))))
+    }
+
+    /// DPI IDs for the given app or bundle names, or the names that aren't
+    /// in the catalog
+    pub fn resolve(&self, names: &[String]) -> Result<Vec<String>, Vec<String>> {
+        let mut ids: Vec<String> = Vec::new();
+        let mut unknown = Vec::new();
+        for name in names {
+            let apps = match self.overlay.bundles.iter().find(|b| same_name(&b.name, name)) {
+                Some(bundle) => bundle.apps.clone(),
+                None => vec![name.clone()],
+            };
+            for app_name in apps {
+                match self.find(&app_name) {
+                    Some(app) if !ids.contains(&app.id) => ids.push(app.id.clone()),
+                    Some(_) => {}
+                    None if app_name == *name => unknown.push(app_name),
+                    None => unknown.push(format!("{} (in bundle {})", app_name, name)),
+                }
+            }
+        }
+        if unknown.is_empty() { Ok(ids) } else { Err(unknown) }
+    }
+
+    /// Apps whose name or an alias contains `query` and, if given, in `category`
+    pub fn search(&self, query: Option<&str>, category: Option<&str>) -> Vec<CatalogApp> {
+        let query = query.map(normalize).unwrap_or_default();
+        self.merged
+            .iter()
+            .filter(|a| {
+                normalize(&a.name).contains(&query) || a.aliases.iter().any(|alias| normalize(alias).contains(&query))
+            })
+            .filter(|a| match category {
+                Some(category) => a.category.as_deref().map(|c| c.eq_ignore_ascii_case(category)).unwrap_or(false),
+                None => true,
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+fn builtin_apps() -> Vec<CatalogApp> {
+    BUILTIN_APPS
+        .iter()
+        .map(|(name, id, category)| CatalogApp {
+            name: name.to_string(),
+            id: id.to_string(),
+            category: Some(category.to_string()),
+            aliases: Vec::new(),
+        })
+        .collect()
+}
+
+// Names match ignoring case, spaces and punctuation ("You Tube" is "youtube")
+fn same_name(a: &str, b: &str) -> bool {
+    normalize(a) == normalize(b)
+}
+
+fn normalize(name: &str) -> String {
+    name.chars().filter(|c| c.is_alphanumeric()).flat_map(char::to_lowercase).collect()
+}
+
+// DPI IDs are strings in apps.toml, but a bare number is what people write
+fn dpi_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Id {
+        Text(String),
+        Number(u64),
+    }
+    Ok(Option::<Id>::deserialize(deserializer)?.map(|id| match id {
+        Id::Text(id) => id,
+        Id::Number(id) => id.to_string(),
+    }))
+}
+
+// A table's entries as (key, value) pairs, in the order they were written
+fn in_file_order<'de, D, T>(deserializer: D) -> Result<Vec<(String, T)>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    struct Entries<T>(PhantomData<T>);
+
+    impl<'de, T: Deserialize<'de>> serde::de::Visitor<'de> for Entries<T> {
+        type Value = Vec<(String, T)>;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("a table")
+        }
+
+        fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut entries = Vec::new();
+            while let Some(entry) = map.next_entry()? {
+                entries.push(entry);
+            }
+            Ok(entries)
+        }
+    }
+
+    deserializer.deserialize_map(Entries(PhantomData))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::Client;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    // A console whose DPI database has YouTube and Fortnite. Fortnite's ID
+    // already includes its category, YouTube's needs it added.
+    async fn controller(applications: serde_json::Value) -> (MockServer, UnifiClient) {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/auth/login"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/v2/api/site/default/dpi/categories"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                { "id": 4, "name": "Media streaming services" },
+                { "id": 10, "name": "Games" }
+            ])))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/v2/api/site/default/dpi/applications"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(applications))
+            .mount(&server)
+            .await;
+        let unifi = UnifiClient::login(Client::new(), &server.uri(), "admin", "secret").await.unwrap();
+        (server, unifi)
+    }
+
+    #[test]
+    fn overlay_reads_apps_aliases_and_bundles() {
+        let overlay = Overlay::parse(
+            r#"
+            [apps.steam]                  # app the controller doesn't list
+            id = "655400"
+            category = "Gaming"
+            aliases = ["valve"]
+
+            [apps.youtube]
+            category = "Video"
+
+            [aliases]
+            mc = "minecraft"
+
+            [bundles]
+            gaming = ["fortnite", "roblox", "mc", "steam"]
+            homework = [
+                "khan academy",  # trailing comma and comments are fine
+            ]
+            "#,
+        )
+        .unwrap();
+
+        let names: Vec<_> = overlay.apps.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, ["steam", "youtube"]);
+        assert_eq!(overlay.apps[0].id.as_deref(), Some("655400"));
+        assert_eq!(overlay.apps[0].aliases, ["valve"]);
+        assert_eq!(overlay.apps[1].id, None);
+        assert_eq!(overlay.aliases, [("mc".to_string(), "minecraft".to_string())]);
+        let bundles: Vec<_> = overlay.bundles.iter().map(|b| b.name.as_str()).collect();
+        assert_eq!(bundles, ["gaming", "homework"]);
+        assert_eq!(overlay.bundles[1].apps, ["khan academy"]);
+    }
+
+    #[test]
+    fn overlay_accepts_numeric_ids() {
+        let overlay = Overlay::parse("[apps.steam]\nid = 655400\n").unwrap();
+        assert_eq!(overlay.apps[0].id.as_deref(), Some("655400"));
+    }
+
+    #[test]
+    fn overlay_rejects_what_it_does_not_understand() {
+        for content in [
+            "id = \"1\"",                           // outside any section
+            "[games]\nfortnite = \"1\"",            // unknown section
+            "[apps.steam]\nicon = \"steam.png\"",   // unknown key
+            "[aliases]\nmc = [\"minecraft\"]",      // alias to a list
+            "[bundles]\ngaming = \"fortnite\"",     // bundle that isn't a list
+            "[bundles]\ngaming = [\"fortnite\"",    // unterminated array
+            "[apps.steam]\nid = \"1\"\nid = \"2\"", // duplicate key
+        ] {
+            assert!(Overlay::parse(content).is_err(), "{}", content);
+        }
+    }
+
+    #[tokio::test]
+    async fn refresh_reads_the_dpi_database_and_applies_the_overlay() {
+        let dir = tempfile::tempdir().unwrap();
+        let overlay_path = dir.path().join(OVERLAY_FILE);
+        fs::write(&overlay_path, r#"
+            [apps.youtube]
+            category = "Video"
+
+            [apps.steam]
+            id = "655400"
+
+            [aliases]
+            yt = "youtube"
+
+            [bundles]
+            gaming = ["fortnite", "steam"]
+        "#).unwrap();
+        let mut catalog = AppCatalog::load(dir.path().join(CATALOG_FILE), overlay_path).unwrap();
+        assert_eq!(catalog.source, "builtin");
+
+        let (_server, unifi) = controller(serde_json::json!([
+            { "id": 5, "name": "YouTube", "cat": 4 },
+            { "id": 655369, "name": "Fortnite" }
+        ])).await;
+        catalog.refresh(&unifi, "default").await.unwrap();
+        assert_eq!(catalog.source, "controller");
+
+        // The category goes in the upper 16 bits of the ID
+        let youtube = catalog.find("yt").unwrap();
+        assert_eq!(youtube.name, "YouTube");
+        assert_eq!(youtube.id, ((4 << 16) | 5).to_string());
+        assert_eq!(youtube.category.as_deref(), Some("Video"));
+        let fortnite = catalog.find("fortnite").unwrap();
+        assert_eq!(fortnite.category.as_deref(), Some("Games"));
+        // Built-in apps the controller doesn't list stay available
+        assert_eq!(catalog.find("netflix").unwrap().id, "655324");
+        assert_eq!(catalog.resolve(&["gaming".to_string()]).unwrap(), ["655369", "655400"]);
+
+        // The controller's catalog is cached for the next start
+        let cached = AppCatalog::load(dir.path().join(CATALOG_FILE), dir.path().join(OVERLAY_FILE)).unwrap();
+        assert_eq!(cached.source, "controller");
+        assert_eq!(cached.find("yt").unwrap().id, youtube.id);
+    }
+
+    #[tokio::test]
+    async fn empty_dpi_database_keeps_the_current_catalog() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut catalog = AppCatalog::load(dir.path().join(CATALOG_FILE), dir.path().join(OVERLAY_FILE)).unwrap();
+        let (_server, unifi) = controller(serde_json::json!([])).await;
+
+        assert!(catalog.refresh(&unifi, "default").await.is_err());
+        assert_eq!(catalog.source, "builtin");
+        assert_eq!(catalog.find("youtube").unwrap().id, "851969");
+        assert!(!dir.path().join(CATALOG_FILE).exists());
+    }
+}
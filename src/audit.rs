@@ -0,0 +1,341 @@
+use crate::auth::{CurrentUser, Role};
+use crate::storage;
+use chrono::{DateTime, Duration, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use utoipa::ToSchema;
+
+// Audit log file name, inside the configured data directory. One JSON entry
+// per line, so recording one is an append rather than a rewrite.
+pub const AUDIT_LOG_FILE: &str = "audit_log.jsonl";
+
+// How often old entries are trimmed while the app runs
+const PRUNE_INTERVAL_HOURS: i64 = 24;
+
+/// What was done to the rules, or to what lifts or adds blocks
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Create,
+    /// Apps, devices or timing changed, including through a profile
+    Update,
+    Enable,
+    Disable,
+    Unblock,
+    /// Removed by the scheduler when its time ran out
+    Expire,
+    UnblockAll,
+    Sync,
+    Cleanup,
+    /// Extra time lifted a block
+    Grant,
+    /// Extra time ended, early or when it ran out, and the block came back
+    EndGrant,
+    /// A child's request was approved, granting time
+    Approve,
+    /// A child's request was turned down
+    Deny,
+    /// A daily budget was created or deleted
+    Budget,
+}
+
+/// One change to the rules, kept after the rule itself is gone
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+#[schema(example = json!({
+    "timestamp": "2024-01-15T18:00:00Z",
+    "actor": "dad",
+    "role": "co_parent",
+    "action": "unblock",
+    "rule_id": "1642781234567",
+    "rule": {"id": "1642781234567", "apps": ["youtube"], "rule_type": "permanent"},
+    "success": false,
+    "message": "Failed to delete rule from UniFi: HTTP 500"
+}))]
+pub struct AuditEntry {
+    /// When it happened (ISO 8601)
+    pub timestamp: String,
+    /// Account that made the change; `scheduler` and `budget` for automatic ones
+    pub actor: String,
+    pub role: Role,
+    pub action: AuditAction,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rule_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    /// The rule as it was after the change, or as removed. Unblock-all
+    /// records every rule it removed, budget changes the budget, and
+    /// answers to children the request.
+    pub rule: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    /// The rule before an update
+    pub previous: Option<serde_json::Value>,
+    /// Whether the controller accepted the change
+    pub success: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// What the controller answered: its error, or a summary of the outcome
+    pub message: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(user: &CurrentUser, action: AuditAction) -> Self {
+        Self {
+            timestamp: Utc::now().to_rfc3339(),
+            actor: user.username.clone(),
+            role: user.role,
+            action,
+            rule_id: None,
+            rule: None,
+            previous: None,
+            success: true,
+            message: None,
+        }
+    }
+
+    pub fn rule(mut self, rule_id: &str, snapshot: &impl Serialize) -> Self {
+        self.rule_id = Some(rule_id.to_string());
+        self.snapshot(snapshot)
+    }
+
+    /// Record a snapshot without a single rule it belongs to
+    pub fn snapshot(mut self, snapshot: &impl Serialize) -> Self {
+        self.rule = serde_json::to_value(snapshot).ok();
+        self
+    }
+
+    pub fn previous(mut self, snapshot: &impl Serialize) -> Self {
+        self.previous = serde_json::to_value(snapshot).ok();
+        self
+    }
+
+    pub fn failed(mut self, error: impl Into<String>) -> Self {
+        self.success = false;
+        self.message = Some(error.into());
+        self
+    }
+
+    pub fn message(mut self, message: impl Into<String>) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+
+    fn timestamp(&self) -> Option<DateTime<Utc>> {
+        DateTime::parse_from_rfc3339(&self.timestamp)
+            .ok()
+            .map(|t| t.with_timezone(&Utc))
+    }
+}
+
+/// How much of the audit log to keep; zero means no limit
+#[derive(Clone, Copy, Debug)]
+pub struct Retention {
+    pub days: u32,
+    pub max_entries: usize,
+}
+
+/// Append-only record of rule changes, trimmed to the retention settings
+pub struct AuditLog {
+    path: PathBuf,
+    retention: Retention,
+    last_pruned: DateTime<Utc>,
+}
+
+impl AuditLog {
+    pub fn open(path: PathBuf, retention: Retention) -> Result<Self, String> {
+        let mut log = Self {
+            path,
+            retention,
+            last_pruned: Utc::now(),
+        };
+        log.prune()?;
+        Ok(log)
+    }
+
+    /// Append an entry. A failed write is reported but not returned, since
+    /// by now the change has already been made on the controller.
+    pub fn record(&mut self, entry: AuditEntry) {
+        if let Err(e) = self.append(&entry) {
+            warn!("⚠️ Failed to write audit log entry for {:?} by {}: {}", entry.action, entry.actor, e);
+        }
+        if Utc::now() - self.last_pruned >= Duration::hours(PRUNE_INTERVAL_HOURS) {
+            if let Err(e) = self.prune() {
+                warn!("⚠️ {}", e);
+            }
+        }
+    }
+
+    fn append(&self, entry: &AuditEntry) -> Result<(), String> {
+        let mut line = serde_json::to_string(entry).map_err(|e| e.to_string())?;
+        line.push('\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| e.to_string())?;
+        // End a line cut short by a crash first, so it doesn't swallow this one
+        if file.metadata().map_err(|e| e.to_string())?.len() > 0 {
+            let mut last = [0u8; 1];
+            file.seek(SeekFrom::End(-1))
+                .and_then(|_| file.read_exact(&mut last))
+                .map_err(|e| e.to_string())?;
+            if last[0] != b'\n' {
+                line.insert(0, '\n');
+            }
+        }
+        file.write_all(line.as_bytes())
+            .and_then(|_| file.sync_data())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Every entry, oldest first. Lines that can't be parsed, such as one cut
+    /// short by a crash, are skipped.
+    pub fn entries(&self) -> Result<Vec<AuditEntry>, String> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let content = fs::read_to_string(&self.path)
+            .map_err(|e| format!("Failed to read {}: {}", self.path.display(), e))?;
+        Ok(content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+
+    // Drop entries older than the retention period, then the oldest beyond
+    // the entry limit. The file is only rewritten when something goes.
+    fn prune(&mut self) -> Result<(), String> {
+        self.last_pruned = Utc::now();
+        if self.retention.days == 0 && self.retention.max_entries == 0 {
+            return Ok(());
+        }
+
+        let entries = self.entries()?;
+        let cutoff = Utc::now() - Duration::days(self.retention.days as i64);
+        let mut kept: Vec<&AuditEntry> = entries
+            .iter()
+            .filter(|e| self.retention.days == 0 || e.timestamp().map(|t| t >= cutoff).unwrap_or(false))
+            .collect();
+        if self.retention.max_entries > 0 && kept.len() > self.retention.max_entries {
+            kept.drain(..kept.len() - self.retention.max_entries);
+        }
+        if kept.len() == entries.len() {
+            return Ok(());
+        }
+
+        let mut content = String::new();
+        for entry in kept.iter() {
+            content.push_str(&serde_json::to_string(entry).map_err(|e| e.to_string())?);
+            content.push('\n');
+        }
+        storage::write_atomic(&self.path, content.as_bytes())
+            .map_err(|e| format!("Failed to trim audit log: {}", e))?;
+        info!("🗂️ Trimmed {} old audit log entries", entries.len() - kept.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user() -> CurrentUser {
+        CurrentUser { username: "mom".to_string(), role: Role::Parent }
+    }
+
+    // An entry made `days_ago`
+    fn entry(rule_id: &str, days_ago: i64) -> AuditEntry {
+        AuditEntry {
+            timestamp: (Utc::now() - Duration::days(days_ago)).to_rfc3339(),
+            ..AuditEntry::new(&user(), AuditAction::Create).rule(rule_id, &serde_json::json!({ "id": rule_id }))
+        }
+    }
+
+    fn rule_ids(log: &AuditLog) -> Vec<String> {
+        log.entries().unwrap().into_iter().filter_map(|e| e.rule_id).collect()
+    }
+
+    // A log file holding `entries`, opened with `retention`
+    fn log_with(dir: &std::path::Path, entries: &[AuditEntry], retention: Retention) -> AuditLog {
+        let path = dir.join(AUDIT_LOG_FILE);
+        let unlimited = AuditLog { path: path.clone(), retention: Retention { days: 0, max_entries: 0 }, last_pruned: Utc::now() };
+        for entry in entries {
+            unlimited.append(entry).unwrap();
+        }
+        AuditLog::open(path, retention).unwrap()
+    }
+
+    #[test]
+    fn entries_are_appended_and_read_back_oldest_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = AuditLog::open(dir.path().join(AUDIT_LOG_FILE), Retention { days: 90, max_entries: 100 }).unwrap();
+        assert!(log.entries().unwrap().is_empty());
+
+        log.record(entry("1", 0));
+        log.record(AuditEntry::new(&user(), AuditAction::Unblock).rule("2", &"rule").failed("HTTP 500"));
+        let entries = log.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].action, AuditAction::Create);
+        assert_eq!(entries[1].rule_id.as_deref(), Some("2"));
+        assert!(!entries[1].success);
+        assert_eq!(entries[1].message.as_deref(), Some("HTTP 500"));
+    }
+
+    #[test]
+    fn a_line_cut_short_is_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = AuditLog::open(dir.path().join(AUDIT_LOG_FILE), Retention { days: 0, max_entries: 0 }).unwrap();
+        log.record(entry("1", 0));
+        fs::write(&log.path, format!("{}{{\"timestamp\": \"20", fs::read_to_string(&log.path).unwrap())).unwrap();
+        log.record(entry("2", 0));
+        assert_eq!(rule_ids(&log), ["1", "2"]);
+    }
+
+    #[test]
+    fn entries_older_than_the_retention_period_are_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut unreadable = entry("4", 0);
+        unreadable.timestamp = "yesterday".to_string();
+        let entries = [entry("1", 100), entry("2", 29), entry("3", 0), unreadable];
+
+        let log = log_with(dir.path(), &entries, Retention { days: 30, max_entries: 0 });
+        assert_eq!(rule_ids(&log), ["2", "3"]);
+    }
+
+    #[test]
+    fn only_the_newest_entries_beyond_the_limit_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let entries = [entry("1", 3), entry("2", 2), entry("3", 1), entry("4", 0)];
+
+        let log = log_with(dir.path(), &entries, Retention { days: 0, max_entries: 2 });
+        assert_eq!(rule_ids(&log), ["3", "4"]);
+    }
+
+    #[test]
+    fn no_retention_keeps_everything() {
+        let dir = tempfile::tempdir().unwrap();
+        let entries = [entry("1", 1000), entry("2", 0)];
+
+        let log = log_with(dir.path(), &entries, Retention { days: 0, max_entries: 0 });
+        assert_eq!(rule_ids(&log), ["1", "2"]);
+    }
+
+    #[test]
+    fn recording_trims_the_log_once_a_day() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = log_with(dir.path(), &[], Retention { days: 0, max_entries: 2 });
+        for rule_id in ["1", "2", "3"] {
+            log.record(entry(rule_id, 0));
+        }
+        // Trimmed at open, not again until a day has passed
+        assert_eq!(rule_ids(&log), ["1", "2", "3"]);
+
+        log.last_pruned -= Duration::hours(PRUNE_INTERVAL_HOURS);
+        log.record(entry("4", 0));
+        assert_eq!(rule_ids(&log), ["3", "4"]);
+    }
+}
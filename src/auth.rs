@@ -0,0 +1,543 @@
+use crate::error::ApiError;
+use crate::storage;
+use crate::AppState;
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::OsRng;
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderMap};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use base64::engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD};
+use base64::Engine;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use utoipa::ToSchema;
+
+// Accounts for this app's own login, kept in the data directory
+pub const USERS_FILE: &str = "users.json";
+pub const SESSION_COOKIE: &str = "puc_session";
+const SESSION_HOURS: i64 = 12;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const MIN_PASSWORD_LEN: usize = 8;
+
+// Failed sign-ins from one address allowed before each further one has to
+// wait, starting at one second and doubling up to the maximum
+const FREE_LOGIN_FAILURES: u32 = 5;
+const MAX_LOGIN_DELAY_SECS: i64 = 15 * 60;
+// Failures are forgotten after this long without another attempt
+const LOGIN_FAILURE_WINDOW_HOURS: i64 = 1;
+
+// API routes reachable without signing in
+const PUBLIC_PATHS: &[&str] = &["/api/auth/status", "/api/auth/login", "/api/auth/setup"];
+
+// API routes for children's devices, which know who they are by their address
+const KIDS_PATH_PREFIX: &str = "/api/kids/";
+
+/// What an account may do
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Full control, including accounts and controller maintenance.
+    /// Also the role of accounts created before roles existed.
+    #[default]
+    Parent,
+    /// Day-to-day blocking, but can't lift permanent rules or run maintenance
+    CoParent,
+    /// Can look at devices and rules but change nothing
+    ReadOnly,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Parent => write!(f, "parent"),
+            Role::CoParent => write!(f, "co_parent"),
+            Role::ReadOnly => write!(f, "read_only"),
+        }
+    }
+}
+
+/// Actions guarded by role
+#[derive(Clone, Copy)]
+pub enum Permission {
+    CreateRule,
+    RemoveRule,
+    RemovePermanentRule,
+    UnblockAll,
+    Sync,
+    Cleanup,
+    ConnectController,
+    ManageProfiles,
+    ManageAccounts,
+    ViewAudit,
+    GrantTime,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self {
+            Permission::CreateRule => "create rules",
+            Permission::RemoveRule => "remove rules",
+            Permission::RemovePermanentRule => "remove permanent rules",
+            Permission::UnblockAll => "unblock everything",
+            Permission::Sync => "sync rules",
+            Permission::Cleanup => "clean up controller rules",
+            Permission::ConnectController => "connect to the controller",
+            Permission::ManageProfiles => "change profiles",
+            Permission::ManageAccounts => "manage accounts",
+            Permission::ViewAudit => "read the audit log",
+            Permission::GrantTime => "grant extra time",
+        };
+        write!(f, "{}", action)
+    }
+}
+
+impl Role {
+    pub fn allows(self, permission: Permission) -> bool {
+        match self {
+            Role::Parent => true,
+            Role::CoParent => matches!(
+                permission,
+                Permission::CreateRule | Permission::RemoveRule | Permission::Sync | Permission::GrantTime
+            ),
+            Role::ReadOnly => false,
+        }
+    }
+}
+
+/// Local account allowed to use the app
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Account {
+    pub username: String,
+    #[serde(default)]
+    pub role: Role,
+    /// pbkdf2-sha256$<iterations>$<salt>$<hash>
+    password_hash: String,
+    created_at: String,
+    #[serde(default)]
+    api_tokens: Vec<ApiToken>,
+}
+
+// Long-lived bearer token for scripts; only its SHA-256 is stored
+#[derive(Serialize, Deserialize, Clone)]
+struct ApiToken {
+    name: String,
+    token_hash: String,
+    created_at: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct UsersFile {
+    accounts: Vec<Account>,
+}
+
+struct Session {
+    username: String,
+    expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Signed-in user, attached to requests that passed `require_login`
+#[derive(Clone)]
+pub struct CurrentUser {
+    pub username: String,
+    pub role: Role,
+}
+
+impl CurrentUser {
+    pub fn require(&self, permission: Permission) -> Result<(), ApiError> {
+        if self.role.allows(permission) {
+            Ok(())
+        } else {
+            warn!("⛔ {} tried to {}", self, permission);
+            Err(ApiError::forbidden(format!("The {} role cannot {}", self.role, permission)))
+        }
+    }
+}
+
+impl fmt::Display for CurrentUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.username, self.role)
+    }
+}
+
+/// Accounts on disk plus the browser sessions opened since startup
+pub struct AuthStore {
+    path: PathBuf,
+    users: UsersFile,
+    sessions: HashMap<String, Session>,
+    failed_logins: HashMap<IpAddr, FailedLogins>,
+}
+
+// Recent failed sign-ins from one address
+struct FailedLogins {
+    count: u32,
+    last: chrono::DateTime<chrono::Utc>,
+}
+
+impl FailedLogins {
+    // When the next attempt is allowed
+    fn retry_at(&self) -> chrono::DateTime<chrono::Utc> {
+        let over = self.count.saturating_sub(FREE_LOGIN_FAILURES);
+        if over == 0 {
+            return self.last;
+        }
+        let delay = 1i64.checked_shl(over - 1).unwrap_or(i64::MAX).min(MAX_LOGIN_DELAY_SECS);
+        self.last + chrono::Duration::seconds(delay)
+    }
+}
+
+impl AuthStore {
+    pub fn load(path: PathBuf) -> Result<Self, String> {
+        let users = if path.exists() {
+            let content = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&content)
+                .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?
+        } else {
+            UsersFile::default()
+        };
+        Ok(Self {
+            path,
+            users,
+            sessions: HashMap::new(),
+            failed_logins: HashMap::new(),
+        })
+    }
+
+    /// True until the first account has been created
+    pub fn needs_setup(&self) -> bool {
+        self.users.accounts.is_empty()
+    }
+
+    /// Add an account whose password was hashed with `hash_password`, which
+    /// is slow enough that callers should do it without holding the store
+    pub fn create_account(&mut self, username: &str, password_hash: String, role: Role) -> Result<(), String> {
+        let username = username.trim();
+        if username.is_empty() {
+            return Err("Username is required".to_string());
+        }
+        if self.account(username).is_some() {
+            return Err(format!("Account {} already exists", username));
+        }
+
+        self.users.accounts.push(Account {
+            username: username.to_string(),
+            role,
+            password_hash,
+            created_at: chrono::Utc::now().to_rfc3339(),
+            api_tokens: vec![],
+        });
+        self.save()?;
+        info!("👤 Created account {} ({})", username, role);
+        Ok(())
+    }
+
+    pub fn set_role(&mut self, username: &str, role: Role) -> Result<(), String> {
+        let parents = self.users.accounts.iter().filter(|a| a.role == Role::Parent).count();
+        let account = self.account_mut(username).ok_or("Account not found")?;
+        if account.role == Role::Parent && role != Role::Parent && parents == 1 {
+            return Err("At least one parent account is required".to_string());
+        }
+        account.role = role;
+        self.save()?;
+        info!("👤 {} is now {}", username, role);
+        Ok(())
+    }
+
+    /// Stored hash to check a sign-in against with `verify_password`
+    pub fn password_hash(&self, username: &str) -> Option<String> {
+        self.account(username).map(|a| a.password_hash.clone())
+    }
+
+    /// Admit a sign-in attempt from an address, or say how long it has to
+    /// wait. The attempt counts as failed until `finish_login` says
+    /// otherwise, so parallel guesses can't all slip past the backoff.
+    pub fn start_login(&mut self, ip: IpAddr) -> Result<(), chrono::Duration> {
+        let now = chrono::Utc::now();
+        self.failed_logins.retain(|_, f| now - f.last < chrono::Duration::hours(LOGIN_FAILURE_WINDOW_HOURS));
+        if let Some(failures) = self.failed_logins.get(&ip) {
+            let delay = failures.retry_at() - now;
+            if delay > chrono::Duration::zero() {
+                return Err(delay);
+            }
+        }
+        let failures = self.failed_logins.entry(ip).or_insert(FailedLogins { count: 0, last: now });
+        failures.count += 1;
+        failures.last = now;
+        Ok(())
+    }
+
+    /// Forget an address's failures after a good password, or start its
+    /// wait from now after a wrong one
+    pub fn finish_login(&mut self, ip: IpAddr, success: bool) {
+        if success {
+            self.failed_logins.remove(&ip);
+        } else if let Some(failures) = self.failed_logins.get_mut(&ip) {
+            failures.last = chrono::Utc::now();
+        }
+    }
+
+    pub fn start_session(&mut self, username: &str) -> String {
+        let now = chrono::Utc::now();
+        self.sessions.retain(|_, s| s.expires_at > now);
+
+        let token = random_token();
+        self.sessions.insert(token.clone(), Session {
+            username: username.to_string(),
+            expires_at: now + chrono::Duration::hours(SESSION_HOURS),
+        });
+        token
+    }
+
+    pub fn end_session(&mut self, token: &str) {
+        self.sessions.remove(token);
+    }
+
+    /// Issue a named API token; the plaintext is only ever returned here
+    pub fn create_api_token(&mut self, username: &str, name: &str) -> Result<String, String> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err("Token name is required".to_string());
+        }
+        let token = random_token();
+        let account = self.account_mut(username).ok_or("Account not found")?;
+        if account.api_tokens.iter().any(|t| t.name == name) {
+            return Err(format!("Token {} already exists", name));
+        }
+        account.api_tokens.push(ApiToken {
+            name: name.to_string(),
+            token_hash: token_hash(&token),
+            created_at: chrono::Utc::now().to_rfc3339(),
+        });
+        self.save()?;
+        Ok(token)
+    }
+
+    pub fn revoke_api_token(&mut self, username: &str, name: &str) -> Result<(), String> {
+        let account = self.account_mut(username).ok_or("Account not found")?;
+        let before = account.api_tokens.len();
+        account.api_tokens.retain(|t| t.name != name);
+        if account.api_tokens.len() == before {
+            return Err(format!("Token {} not found", name));
+        }
+        self.save()
+    }
+
+    /// Account behind a session or API token, if it is still valid
+    pub fn authenticate(&self, token: &str) -> Option<CurrentUser> {
+        let account = match self.sessions.get(token) {
+            Some(session) if session.expires_at > chrono::Utc::now() => self.account(&session.username),
+            Some(_) => None,
+            None => {
+                let hash = token_hash(token);
+                self.users.accounts
+                    .iter()
+                    .find(|a| a.api_tokens.iter().any(|t| constant_time_eq(t.token_hash.as_bytes(), hash.as_bytes())))
+            }
+        };
+        // Looked up on every request so role changes apply immediately
+        account.map(|a| CurrentUser {
+            username: a.username.clone(),
+            role: a.role,
+        })
+    }
+
+    fn account(&self, username: &str) -> Option<&Account> {
+        self.users.accounts.iter().find(|a| a.username == username)
+    }
+
+    fn account_mut(&mut self, username: &str) -> Option<&mut Account> {
+        self.users.accounts.iter_mut().find(|a| a.username == username)
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let content = serde_json::to_string_pretty(&self.users)
+            .map_err(|e| format!("Failed to serialize accounts: {}", e))?;
+        storage::write_atomic(Path::new(&self.path), content.as_bytes())
+            .map_err(|e| format!("Failed to write accounts: {}", e))
+    }
+}
+
+/// Middleware rejecting `/api/*` requests that carry no valid session or token
+pub async fn require_login(State(state): State<AppState>, mut request: Request, next: Next) -> Response {
+    let path = request.uri().path();
+    if !path.starts_with("/api/") || PUBLIC_PATHS.contains(&path) || path.starts_with(KIDS_PATH_PREFIX) {
+        return next.run(request).await;
+    }
+
+    let user = match request_token(request.headers()) {
+        Some(token) => state.auth.lock().await.authenticate(&token),
+        None => None,
+    };
+    match user {
+        Some(user) => {
+            request.extensions_mut().insert(user);
+            next.run(request).await
+        }
+        None => ApiError::unauthorized("Sign in to the parental control app first").into_response(),
+    }
+}
+
+/// Token from an `Authorization: Bearer` header or the session cookie
+pub fn request_token(headers: &HeaderMap) -> Option<String> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|t| t.trim().to_string());
+    if bearer.is_some() {
+        return bearer;
+    }
+
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|c| c.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value.to_string())
+}
+
+pub fn session_cookie(token: &str) -> String {
+    format!("{}={}; HttpOnly; SameSite=Strict; Path=/; Max-Age={}", SESSION_COOKIE, token, SESSION_HOURS * 3600)
+}
+
+pub fn expired_session_cookie() -> String {
+    format!("{}=; HttpOnly; SameSite=Strict; Path=/; Max-Age=0", SESSION_COOKIE)
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn token_hash(token: &str) -> String {
+    BASE64.encode(Sha256::digest(token.as_bytes()))
+}
+
+pub fn validate_password(password: &str) -> Result<(), String> {
+    if password.len() < MIN_PASSWORD_LEN {
+        return Err(format!("Password must be at least {} characters", MIN_PASSWORD_LEN));
+    }
+    Ok(())
+}
+
+/// Salted PBKDF2 hash in the form stored in `users.json`. Takes a noticeable
+/// fraction of a second, so run it on a blocking thread.
+pub fn hash_password(password: &str) -> String {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let hash = pbkdf2_sha256(password.as_bytes(), &salt, PBKDF2_ITERATIONS);
+    format!("pbkdf2-sha256${}${}${}", PBKDF2_ITERATIONS, BASE64.encode(salt), BASE64.encode(hash))
+}
+
+/// Check a password against a stored hash, or against nothing so that
+/// unknown usernames take as long as wrong passwords. Blocking, like
+/// `hash_password`.
+pub fn verify_password(password: &str, stored: Option<&str>) -> bool {
+    let Some(stored) = stored else {
+        hash_password(password);
+        return false;
+    };
+    let parts: Vec<&str> = stored.split('$').collect();
+    let (iterations, salt, expected) = match parts.as_slice() {
+        ["pbkdf2-sha256", iterations, salt, hash] => (iterations.parse::<u32>(), BASE64.decode(salt), BASE64.decode(hash)),
+        _ => return false,
+    };
+    match (iterations, salt, expected) {
+        (Ok(iterations), Ok(salt), Ok(expected)) => {
+            constant_time_eq(&pbkdf2_sha256(password.as_bytes(), &salt, iterations), &expected)
+        }
+        _ => false,
+    }
+}
+
+// PBKDF2 with HMAC-SHA256, producing a single 32-byte block
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, iterations, &mut hash);
+    hash
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // RFC 7914 section 11, first 32 bytes of each derived key
+    #[test]
+    fn pbkdf2_matches_rfc_7914_vectors() {
+        assert_eq!(
+            hex(&pbkdf2_sha256(b"passwd", b"salt", 1)),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc"
+        );
+        assert_eq!(
+            hex(&pbkdf2_sha256(b"Password", b"NaCl", 80000)),
+            "4ddcd8f60b98be21830cee5ef22701f9641a4418d04c0414aeff08876b34ab56"
+        );
+    }
+
+    #[test]
+    fn password_round_trip() {
+        let stored = hash_password("correct horse");
+        assert!(verify_password("correct horse", Some(&stored)));
+        assert!(!verify_password("wrong horse", Some(&stored)));
+        assert!(!verify_password("correct horse", None));
+        assert!(!verify_password("correct horse", Some("plaintext")));
+    }
+
+    #[test]
+    fn failed_logins_back_off() {
+        let mut auth = AuthStore::load(PathBuf::from("/nonexistent/users.json")).unwrap();
+        let ip = IpAddr::from([192, 168, 1, 50]);
+        for _ in 0..FREE_LOGIN_FAILURES + 1 {
+            assert!(auth.start_login(ip).is_ok());
+            auth.finish_login(ip, false);
+        }
+        assert!(auth.start_login(ip).is_err());
+        assert!(auth.start_login(IpAddr::from([192, 168, 1, 51])).is_ok());
+
+        auth.failed_logins.get_mut(&ip).unwrap().last -= chrono::Duration::seconds(2);
+        assert!(auth.start_login(ip).is_ok());
+        auth.finish_login(ip, true);
+        assert!(!auth.failed_logins.contains_key(&ip));
+    }
+
+    #[test]
+    fn roles_allow_exactly_their_permissions() {
+        // (permission, parent, co-parent, read-only)
+        let matrix = [
+            (Permission::CreateRule, true, true, false),
+            (Permission::RemoveRule, true, true, false),
+            (Permission::RemovePermanentRule, true, false, false),
+            (Permission::UnblockAll, true, false, false),
+            (Permission::Sync, true, true, false),
+            (Permission::Cleanup, true, false, false),
+            (Permission::ConnectController, true, false, false),
+            (Permission::ManageProfiles, true, false, false),
+            (Permission::ManageAccounts, true, false, false),
+            (Permission::ViewAudit, true, false, false),
+            (Permission::GrantTime, true, true, false),
+        ];
+        for (permission, parent, co_parent, read_only) in matrix {
+            assert_eq!(Role::Parent.allows(permission), parent, "parent may {}", permission);
+            assert_eq!(Role::CoParent.allows(permission), co_parent, "co-parent may {}", permission);
+            assert_eq!(Role::ReadOnly.allows(permission), read_only, "read-only may {}", permission);
+        }
+    }
+}
@@ -0,0 +1,665 @@
+use crate::unifi::{ClientDevice, FirewallGroup, FirewallRule, TargetDevice, TrafficRule, UnifiClient, UnifiError};
+use crate::RULE_NAME_PREFIX;
+use log::{debug, warn};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+// Strategy for new rules that don't name one, for controllers without the v2 API
+const BLOCK_STRATEGY_ENV: &str = "PUC_BLOCK_STRATEGY";
+
+/// How blocks are expressed on the controller
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockStrategy {
+    /// Traffic rules from the v2 API (UniFi Network 7+), matched by app and client MAC
+    TrafficRules,
+    /// Legacy WAN_IN/LAN_IN firewall rules with app categories, for older controllers.
+    /// Also the strategy of rules stored before strategies existed.
+    #[default]
+    FirewallRules,
+}
+
+/// What a new block should do, independent of strategy
+pub struct BlockPlan<'a> {
+    pub rule_id: &'a str,
+    pub apps: &'a [String],
+    pub app_ids: &'a [String],
+    /// Domains blocked with a separate traffic rule, from `normalize_domains`
+    pub domains: &'a [String],
+    /// Lowercased MACs; empty means every device
+    pub device_macs: &'a [String],
+    /// Cut the devices off the internet entirely instead of blocking apps
+    pub pause: bool,
+    pub enabled: bool,
+}
+
+/// Controller objects created for a block
+#[derive(Default)]
+pub struct CreatedBlock {
+    pub unifi_rule_id: Option<String>,
+    pub unifi_group_id: Option<String>,
+    pub unifi_domain_rule_id: Option<String>,
+    /// Devices paused with the station manager, on controllers without traffic rules
+    pub unifi_paused_macs: Vec<String>,
+}
+
+/// A device blocked with the station manager for a pause. Kept apart from
+/// the rules, so cleanup can still unblock it after its rule is forgotten.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PausedStation {
+    pub site: String,
+    pub mac: String,
+}
+
+impl BlockStrategy {
+    /// Strategy for new rules: traffic rules unless configured otherwise
+    pub fn from_env() -> Self {
+        match std::env::var(BLOCK_STRATEGY_ENV).as_deref().map(str::trim) {
+            Ok("firewall_rules") => BlockStrategy::FirewallRules,
+            Ok("traffic_rules") | Ok("") | Err(_) => BlockStrategy::TrafficRules,
+            Ok(other) => {
+                warn!("⚠️ Unknown {} '{}', using traffic_rules", BLOCK_STRATEGY_ENV, other);
+                BlockStrategy::TrafficRules
+            }
+        }
+    }
+
+    // Apps and domains need separate controller rules, since a traffic rule
+    // matches on one kind of target. Callers check that domains and pauses
+    // are possible with this strategy first.
+    pub async fn create(self, unifi: &UnifiClient, site: &str, plan: &BlockPlan<'_>) -> Result<CreatedBlock, String> {
+        if plan.pause {
+            return self.create_pause(unifi, site, plan).await;
+        }
+
+        let mut created = if plan.app_ids.is_empty() {
+            CreatedBlock::default()
+        } else {
+            match self {
+                BlockStrategy::TrafficRules => create_traffic_rule(unifi, site, plan).await?,
+                BlockStrategy::FirewallRules => create_firewall_rule(unifi, site, plan).await?,
+            }
+        };
+
+        if !plan.domains.is_empty() {
+            match create_domain_rule(unifi, site, plan).await {
+                Ok(unifi_domain_rule_id) => created.unifi_domain_rule_id = unifi_domain_rule_id,
+                Err(e) => {
+                    // Don't leave half a block behind
+                    let _ = self.remove(unifi, site, &created).await;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(created)
+    }
+
+    // A pause is a traffic rule matching all internet traffic. Legacy firewall
+    // rules can't match by MAC, so older controllers block the devices
+    // outright through the station manager instead.
+    async fn create_pause(self, unifi: &UnifiClient, site: &str, plan: &BlockPlan<'_>) -> Result<CreatedBlock, String> {
+        match self {
+            BlockStrategy::TrafficRules => {
+                debug!("⏸️ Creating pause traffic rule at: {} (site {})", unifi.base_url(), site);
+                let traffic_rule = TrafficRule {
+                    target_devices: target_devices(plan.device_macs),
+                    enabled: plan.enabled,
+                    ..TrafficRule::block(pause_rule_name(plan.rule_id), "INTERNET")
+                };
+                let created = unifi.create_traffic_rule(site, &traffic_rule).await
+                    .map_err(|e| format!("Failed to create pause traffic rule: {}", e))?;
+                Ok(CreatedBlock {
+                    unifi_rule_id: created.id,
+                    ..Default::default()
+                })
+            }
+            BlockStrategy::FirewallRules => {
+                let created = CreatedBlock {
+                    unifi_paused_macs: plan.device_macs.to_vec(),
+                    ..Default::default()
+                };
+                if plan.enabled {
+                    if let Err(e) = set_stations_blocked(unifi, site, plan.device_macs, true).await {
+                        let _ = set_stations_blocked(unifi, site, plan.device_macs, false).await;
+                        return Err(format!("Failed to pause devices: {}", e));
+                    }
+                }
+                Ok(created)
+            }
+        }
+    }
+
+    // Remove everything a block created. The firewall rule goes first since
+    // UniFi refuses to delete a group that is still in use.
+    pub async fn remove(self, unifi: &UnifiClient, site: &str, block: &CreatedBlock) -> Result<(), UnifiError> {
+        if let Some(ref unifi_domain_rule_id) = block.unifi_domain_rule_id {
+            unifi.delete_traffic_rule(site, unifi_domain_rule_id).await?;
+        }
+        set_stations_blocked(unifi, site, &block.unifi_paused_macs, false).await?;
+        if let Some(ref unifi_rule_id) = block.unifi_rule_id {
+            match self {
+                BlockStrategy::TrafficRules => unifi.delete_traffic_rule(site, unifi_rule_id).await?,
+                BlockStrategy::FirewallRules => unifi.delete_firewall_rule(site, unifi_rule_id).await?,
+            }
+        }
+        if let Some(ref unifi_group_id) = block.unifi_group_id {
+            unifi.delete_firewall_group(site, unifi_group_id).await?;
+        }
+        Ok(())
+    }
+
+    // Turn a block on or off. The controller expects the full rule on PUT,
+    // so rules are fetched and modified.
+    pub async fn set_enabled(self, unifi: &UnifiClient, site: &str, block: &CreatedBlock, enabled: bool) -> Result<(), UnifiError> {
+        if let Some(ref unifi_rule_id) = block.unifi_rule_id {
+            match self {
+                BlockStrategy::TrafficRules => set_traffic_rule_enabled(unifi, site, unifi_rule_id, enabled).await?,
+                BlockStrategy::FirewallRules => {
+                    let mut firewall_rule = unifi.get_firewall_rule(site, unifi_rule_id).await?;
+                    firewall_rule.enabled = enabled;
+                    unifi.update_firewall_rule(site, &firewall_rule).await?;
+                }
+            }
+        }
+        // Domain rules are always traffic rules
+        if let Some(ref unifi_domain_rule_id) = block.unifi_domain_rule_id {
+            set_traffic_rule_enabled(unifi, site, unifi_domain_rule_id, enabled).await?;
+        }
+        set_stations_blocked(unifi, site, &block.unifi_paused_macs, enabled).await
+    }
+
+    // Change which apps an existing block matches. The controller rule is
+    // renamed too, so sync still recognises it by its apps.
+    pub async fn update_apps(
+        self,
+        unifi: &UnifiClient,
+        site: &str,
+        block: &CreatedBlock,
+        apps: &[String],
+        app_ids: &[String],
+    ) -> Result<(), String> {
+        let unifi_rule_id = block.unifi_rule_id.as_deref().ok_or("Rule has no app block on the controller")?;
+        match self {
+            BlockStrategy::TrafficRules => {
+                let mut traffic_rule = unifi.get_traffic_rule(site, unifi_rule_id).await.map_err(|e| e.to_string())?;
+                traffic_rule.app_ids = parse_app_ids(app_ids)?;
+                traffic_rule.description = rule_name(apps);
+                unifi.update_traffic_rule(site, &traffic_rule).await.map_err(|e| e.to_string())
+            }
+            BlockStrategy::FirewallRules => {
+                let mut firewall_rule = unifi.get_firewall_rule(site, unifi_rule_id).await.map_err(|e| e.to_string())?;
+                firewall_rule.app_category_ids = app_ids.to_vec();
+                firewall_rule.name = rule_name(apps);
+                unifi.update_firewall_rule(site, &firewall_rule).await.map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    // Point an existing block at a new set of devices, e.g. when a profile
+    // gains a phone. Firewall rules match the devices' current addresses, so
+    // ones that aren't connected are added by `refresh_device_groups` once
+    // they are. `enabled` is whether the block is currently in force, which
+    // paused devices need to know.
+    pub async fn retarget(
+        self,
+        unifi: &UnifiClient,
+        site: &str,
+        block: &mut CreatedBlock,
+        device_macs: &[String],
+        enabled: bool,
+    ) -> Result<(), String> {
+        if let Some(ref unifi_domain_rule_id) = block.unifi_domain_rule_id {
+            retarget_traffic_rule(unifi, site, unifi_domain_rule_id, device_macs).await?;
+        }
+
+        if !block.unifi_paused_macs.is_empty() {
+            let released: Vec<String> = block.unifi_paused_macs.iter()
+                .filter(|mac| !device_macs.contains(mac))
+                .cloned()
+                .collect();
+            set_stations_blocked(unifi, site, &released, false).await.map_err(|e| e.to_string())?;
+            if enabled {
+                set_stations_blocked(unifi, site, device_macs, true).await.map_err(|e| e.to_string())?;
+            }
+            block.unifi_paused_macs = device_macs.to_vec();
+        }
+
+        let Some(ref unifi_rule_id) = block.unifi_rule_id else {
+            return Ok(());
+        };
+        match self {
+            BlockStrategy::TrafficRules => retarget_traffic_rule(unifi, site, unifi_rule_id, device_macs).await,
+            BlockStrategy::FirewallRules => {
+                let unifi_group_id = block.unifi_group_id.as_deref().ok_or("Rule has no device group")?;
+                let clients = unifi.list_clients(site).await.map_err(|e| e.to_string())?;
+                let mut group = unifi.get_firewall_group(site, unifi_group_id).await.map_err(|e| e.to_string())?;
+                group.group_members = device_ips(&clients, device_macs);
+                unifi.update_firewall_group(site, &group).await.map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+/// ID of a firewall rule's address group, and the MACs it should cover
+pub type DeviceGroup = (String, Vec<String>);
+
+/// Bring the address groups of firewall rules on a site up to date with the
+/// current addresses of their devices. Addresses
+/// change with DHCP and as devices come and go, so the scheduler calls this
+/// on every tick. Returns how many groups changed.
+pub async fn refresh_device_groups(
+    unifi: &UnifiClient,
+    site: &str,
+    groups: &[DeviceGroup],
+) -> Result<usize, UnifiError> {
+    let clients = unifi.list_clients(site).await?;
+    let existing = unifi.list_firewall_groups(site).await?;
+    let mut changed = 0;
+    for (group_id, device_macs) in groups {
+        // A group that's gone is left for cleanup to report
+        let Some(group) = existing.iter().find(|g| g.id.as_deref() == Some(group_id.as_str())) else {
+            continue;
+        };
+        let ips = device_ips(&clients, device_macs);
+        let mut current = group.group_members.clone();
+        let mut wanted = ips.clone();
+        current.sort();
+        wanted.sort();
+        if current == wanted {
+            continue;
+        }
+        unifi.update_firewall_group(site, &FirewallGroup { group_members: ips, ..group.clone() }).await?;
+        debug!("🔁 Device group {} now holds {}", group_id, wanted.join(", "));
+        changed += 1;
+    }
+    Ok(changed)
+}
+
+// Name given to the controller rule, used to recognise our rules later.
+// Domain rules are named after their domains.
+pub fn rule_name(apps: &[String]) -> String {
+    format!("{} {}", RULE_NAME_PREFIX, apps.join(", "))
+}
+
+// Name of the traffic rule behind a pause, which has no apps to name it by
+pub fn pause_rule_name(rule_id: &str) -> String {
+    format!("{} pause {}", RULE_NAME_PREFIX, rule_id)
+}
+
+/// Lowercase, validate and de-duplicate a list of domains. Pasted URLs are
+/// cut down to their host. Subdomains are always blocked too, so a leading
+/// "*." is accepted and dropped.
+pub fn normalize_domains(domains: &[String]) -> Result<Vec<String>, String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for domain in domains {
+        let name = domain.trim().to_lowercase();
+        let name = name.split_once("://").map(|(_, rest)| rest).unwrap_or(&name);
+        let name = name.split(['/', '?', '#', ':']).next().unwrap_or_default();
+        let name = name.trim_start_matches("*.").trim_start_matches('.').trim_end_matches('.');
+        let valid = name.contains('.')
+            && name.len() <= 253
+            && name.split('.').all(|label| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && !label.starts_with('-')
+                    && !label.ends_with('-')
+                    && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            });
+        if !valid {
+            return Err(format!("Invalid domain: {}", domain));
+        }
+        if !normalized.iter().any(|d| d == name) {
+            normalized.push(name.to_string());
+        }
+    }
+    Ok(normalized)
+}
+
+// Traffic rules created by this tool, or none on controllers without the v2 API
+pub async fn list_our_traffic_rules(unifi: &UnifiClient, site: &str) -> Result<Vec<TrafficRule>, UnifiError> {
+    match unifi.list_traffic_rules(site).await {
+        Ok(rules) => Ok(rules.into_iter().filter(|r| r.description.starts_with(RULE_NAME_PREFIX)).collect()),
+        Err(UnifiError::Status(StatusCode::NOT_FOUND, _)) => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
+
+// Traffic rules match clients by MAC, so no address group is needed
+fn target_devices(device_macs: &[String]) -> Vec<TargetDevice> {
+    if device_macs.is_empty() {
+        vec![TargetDevice::all_clients()]
+    } else {
+        device_macs.iter().map(|mac| TargetDevice::client(mac)).collect()
+    }
+}
+
+async fn set_traffic_rule_enabled(unifi: &UnifiClient, site: &str, unifi_rule_id: &str, enabled: bool) -> Result<(), UnifiError> {
+    let mut traffic_rule = unifi.get_traffic_rule(site, unifi_rule_id).await?;
+    traffic_rule.enabled = enabled;
+    unifi.update_traffic_rule(site, &traffic_rule).await
+}
+
+// Block or unblock each device with the station manager
+async fn set_stations_blocked(unifi: &UnifiClient, site: &str, macs: &[String], blocked: bool) -> Result<(), UnifiError> {
+    for mac in macs {
+        if blocked {
+            unifi.block_station(site, mac).await?;
+        } else {
+            unifi.unblock_station(site, mac).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn retarget_traffic_rule(unifi: &UnifiClient, site: &str, unifi_rule_id: &str, device_macs: &[String]) -> Result<(), String> {
+    let mut traffic_rule = unifi.get_traffic_rule(site, unifi_rule_id).await.map_err(|e| e.to_string())?;
+    traffic_rule.target_devices = target_devices(device_macs);
+    unifi.update_traffic_rule(site, &traffic_rule).await.map_err(|e| e.to_string())
+}
+
+// Traffic rules take numeric DPI IDs
+fn parse_app_ids(app_ids: &[String]) -> Result<Vec<u64>, String> {
+    app_ids
+        .iter()
+        .map(|id| id.parse::<u64>().map_err(|_| format!("Invalid app ID: {}", id)))
+        .collect()
+}
+
+async fn create_traffic_rule(unifi: &UnifiClient, site: &str, plan: &BlockPlan<'_>) -> Result<CreatedBlock, String> {
+    let app_ids = parse_app_ids(plan.app_ids)?;
+
+    debug!("🚦 Creating traffic rule at: {} (site {})", unifi.base_url(), site);
+    let traffic_rule = TrafficRule {
+        app_ids,
+        target_devices: target_devices(plan.device_macs),
+        enabled: plan.enabled,
+        ..TrafficRule::block(rule_name(plan.apps), "APP")
+    };
+
+    let created = unifi.create_traffic_rule(site, &traffic_rule).await
+        .map_err(|e| format!("Failed to create traffic rule: {}", e))?;
+    Ok(CreatedBlock {
+        unifi_rule_id: created.id,
+        ..Default::default()
+    })
+}
+
+async fn create_domain_rule(unifi: &UnifiClient, site: &str, plan: &BlockPlan<'_>) -> Result<Option<String>, String> {
+    debug!("🌐 Creating domain traffic rule at: {} (site {})", unifi.base_url(), site);
+    let mut traffic_rule = TrafficRule {
+        target_devices: target_devices(plan.device_macs),
+        enabled: plan.enabled,
+        ..TrafficRule::block(rule_name(plan.domains), "DOMAIN")
+    };
+    traffic_rule.extra.insert(
+        "domains".to_string(),
+        plan.domains
+            .iter()
+            .map(|domain| serde_json::json!({ "domain": domain, "ports": [], "port_ranges": [] }))
+            .collect(),
+    );
+
+    let created = unifi.create_traffic_rule(site, &traffic_rule).await
+        .map_err(|e| format!("Failed to create domain traffic rule: {}", e))?;
+    Ok(created.id)
+}
+
+async fn create_firewall_rule(unifi: &UnifiClient, site: &str, plan: &BlockPlan<'_>) -> Result<CreatedBlock, String> {
+    // Rules aimed at specific devices match them through a per-rule address group
+    let unifi_group_id = if plan.device_macs.is_empty() {
+        None
+    } else {
+        // Devices that aren't connected have no address yet; they join the
+        // group when they connect, through `refresh_device_groups`
+        let clients = unifi.list_clients(site).await.map_err(|e| e.to_string())?;
+        let group = unifi.create_firewall_group(site, &FirewallGroup {
+            name: format!("{} {} devices", RULE_NAME_PREFIX, plan.rule_id),
+            group_type: "address-group".to_string(),
+            group_members: device_ips(&clients, plan.device_macs),
+            ..Default::default()
+        }).await.map_err(|e| format!("Failed to create device group: {}", e))?;
+        group.id
+    };
+
+    debug!("🔥 Creating firewall rule at: {} (site {})", unifi.base_url(), site);
+
+    // Device-targeted rules filter on LAN source addresses, which only LAN_IN sees
+    let (ruleset, src_firewallgroup_ids) = match unifi_group_id {
+        Some(ref group_id) => ("LAN_IN", vec![group_id.clone()]),
+        None => ("WAN_IN", vec![]),
+    };
+
+    let firewall_rule = FirewallRule {
+        name: rule_name(plan.apps),
+        ruleset: ruleset.to_string(),
+        rule_index: 2000,
+        action: "drop".to_string(),
+        src_firewallgroup_ids,
+        app_category_ids: plan.app_ids.to_vec(),
+        enabled: plan.enabled,
+        ..Default::default()
+    };
+
+    match unifi.create_firewall_rule(site, &firewall_rule).await {
+        Ok(created) => Ok(CreatedBlock {
+            unifi_rule_id: created.id,
+            unifi_group_id,
+            ..Default::default()
+        }),
+        Err(e) => {
+            if let Some(ref group_id) = unifi_group_id {
+                let _ = unifi.delete_firewall_group(site, group_id).await;
+            }
+            Err(format!("Failed to create firewall rule: {}", e))
+        }
+    }
+}
+
+// Current IP addresses of the targeted devices that are connected
+fn device_ips(clients: &[ClientDevice], macs: &[String]) -> Vec<String> {
+    macs.iter()
+        .filter_map(|mac| clients.iter().find(|c| c.mac.eq_ignore_ascii_case(mac)))
+        .filter_map(|c| c.ip.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::Client;
+    use wiremock::matchers::{body_partial_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const PHONE: &str = "aa:bb:cc:00:00:01";
+    const TABLET: &str = "aa:bb:cc:00:00:02";
+
+    fn envelope(data: serde_json::Value) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(serde_json::json!({ "meta": { "rc": "ok" }, "data": data }))
+    }
+
+    async fn controller(clients: serde_json::Value) -> (MockServer, UnifiClient) {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/auth/login"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/s/default/stat/sta"))
+            .respond_with(envelope(clients))
+            .mount(&server)
+            .await;
+        let unifi = UnifiClient::login(Client::new(), &server.uri(), "admin", "secret").await.unwrap();
+        (server, unifi)
+    }
+
+    #[tokio::test]
+    async fn firewall_rule_for_an_offline_device_leaves_it_out_of_the_group() {
+        // Only the phone is connected
+        let (server, unifi) = controller(serde_json::json!([{ "mac": PHONE, "ip": "192.168.1.20" }])).await;
+        Mock::given(method("POST"))
+            .and(path("/proxy/network/api/s/default/rest/firewallgroup"))
+            .and(body_partial_json(serde_json::json!({ "group_members": ["192.168.1.20"] })))
+            .respond_with(envelope(serde_json::json!([{ "_id": "g1", "name": "group", "group_type": "address-group" }])))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/proxy/network/api/s/default/rest/firewallrule"))
+            .and(body_partial_json(serde_json::json!({ "ruleset": "LAN_IN", "src_firewallgroup_ids": ["g1"] })))
+            .respond_with(envelope(serde_json::json!([{
+                "_id": "r1", "name": "rule", "ruleset": "LAN_IN", "rule_index": 2000, "action": "drop"
+            }])))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let devices = [PHONE.to_string(), TABLET.to_string()];
+        let plan = BlockPlan {
+            rule_id: "1",
+            apps: &["youtube".to_string()],
+            app_ids: &["327680".to_string()],
+            domains: &[],
+            device_macs: &devices,
+            pause: false,
+            enabled: true,
+        };
+        let created = BlockStrategy::FirewallRules.create(&unifi, "default", &plan).await.unwrap();
+        assert_eq!(created.unifi_rule_id.as_deref(), Some("r1"));
+        assert_eq!(created.unifi_group_id.as_deref(), Some("g1"));
+    }
+
+    #[tokio::test]
+    async fn device_groups_follow_devices_as_they_connect_and_change_address() {
+        // The tablet has come online and the phone got a new lease
+        let (server, unifi) = controller(serde_json::json!([
+            { "mac": PHONE, "ip": "192.168.1.25" },
+            { "mac": TABLET, "ip": "192.168.1.31" },
+        ]))
+        .await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/s/default/rest/firewallgroup"))
+            .respond_with(envelope(serde_json::json!([
+                { "_id": "stale", "name": "a", "group_type": "address-group", "group_members": ["192.168.1.20"] },
+                { "_id": "current", "name": "b", "group_type": "address-group", "group_members": ["192.168.1.31"] },
+            ])))
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path("/proxy/network/api/s/default/rest/firewallgroup/stale"))
+            .and(body_partial_json(serde_json::json!({ "group_members": ["192.168.1.25", "192.168.1.31"] })))
+            .respond_with(envelope(serde_json::json!([])))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path("/proxy/network/api/s/default/rest/firewallgroup/current"))
+            .respond_with(envelope(serde_json::json!([])))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let groups = [
+            ("stale".to_string(), vec![PHONE.to_string(), TABLET.to_string()]),
+            ("current".to_string(), vec![TABLET.to_string()]),
+            ("deleted".to_string(), vec![PHONE.to_string()]),
+        ];
+        assert_eq!(refresh_device_groups(&unifi, "default", &groups).await.unwrap(), 1);
+    }
+
+    fn pause_plan(device_macs: &[String]) -> BlockPlan<'_> {
+        BlockPlan {
+            rule_id: "1",
+            apps: &[],
+            app_ids: &[],
+            domains: &[],
+            device_macs,
+            pause: true,
+            enabled: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn traffic_rule_pause_blocks_all_internet_traffic_of_the_devices() {
+        let (server, unifi) = controller(serde_json::json!([])).await;
+        Mock::given(method("POST"))
+            .and(path("/proxy/network/v2/api/site/default/trafficrules"))
+            .and(body_partial_json(serde_json::json!({
+                "description": pause_rule_name("1"),
+                "matching_target": "INTERNET",
+                "target_devices": [{ "client_mac": PHONE }],
+                "enabled": true,
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "_id": "t1", "description": pause_rule_name("1"), "action": "BLOCK", "matching_target": "INTERNET"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let created = BlockStrategy::TrafficRules.create(&unifi, "default", &pause_plan(&[PHONE.to_string()])).await.unwrap();
+        assert_eq!(created.unifi_rule_id.as_deref(), Some("t1"));
+        assert!(created.unifi_paused_macs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn firewall_pause_blocks_the_devices_until_it_is_removed() {
+        let (server, unifi) = controller(serde_json::json!([])).await;
+        for (cmd, mac) in [("block-sta", PHONE), ("block-sta", TABLET), ("unblock-sta", PHONE), ("unblock-sta", TABLET)] {
+            Mock::given(method("POST"))
+                .and(path("/proxy/network/api/s/default/cmd/stamgr"))
+                .and(body_partial_json(serde_json::json!({ "cmd": cmd, "mac": mac })))
+                .respond_with(envelope(serde_json::json!([])))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+
+        let devices = [PHONE.to_string(), TABLET.to_string()];
+        let created = BlockStrategy::FirewallRules.create(&unifi, "default", &pause_plan(&devices)).await.unwrap();
+        assert_eq!(created.unifi_paused_macs, devices);
+        assert!(created.unifi_rule_id.is_none());
+
+        BlockStrategy::FirewallRules.remove(&unifi, "default", &created).await.unwrap();
+    }
+
+    fn domains(domains: &[&str]) -> Vec<String> {
+        domains.iter().map(|d| d.to_string()).collect()
+    }
+
+    #[test]
+    fn pasted_urls_are_cut_down_to_their_host() {
+        assert_eq!(
+            normalize_domains(&domains(&["https://www.Roblox.com/games/123?ref=home", "http://rbxcdn.com:8080", "roblox.com#top"])).unwrap(),
+            ["www.roblox.com", "rbxcdn.com", "roblox.com"]
+        );
+    }
+
+    #[test]
+    fn wildcards_and_trailing_dots_are_dropped() {
+        assert_eq!(
+            normalize_domains(&domains(&["*.roblox.com", ".rbxcdn.com", "example.org.", " Example.NET "])).unwrap(),
+            ["roblox.com", "rbxcdn.com", "example.org", "example.net"]
+        );
+    }
+
+    #[test]
+    fn duplicates_are_kept_once_in_order() {
+        assert_eq!(
+            normalize_domains(&domains(&["roblox.com", "rbxcdn.com", "*.ROBLOX.com", "https://roblox.com/"])).unwrap(),
+            ["roblox.com", "rbxcdn.com"]
+        );
+    }
+
+    #[test]
+    fn invalid_domains_are_rejected() {
+        for invalid in ["", "localhost", "https://", "exa mple.com", "-roblox.com", "roblox-.com", "roblox..com", "rob_lox.com"] {
+            assert_eq!(normalize_domains(&domains(&["roblox.com", invalid])), Err(format!("Invalid domain: {}", invalid)));
+        }
+        let long_label = format!("{}.com", "a".repeat(64));
+        assert!(normalize_domains(&[long_label]).is_err());
+        let long_name = format!("{}.com", vec!["a".repeat(63); 4].join("."));
+        assert!(normalize_domains(&[long_name]).is_err());
+    }
+}
@@ -0,0 +1,163 @@
+use crate::schedule::{self, default_timezone};
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Traffic through a budget's apps, in bytes per scheduler tick, above which
+/// the tick counts as time spent. Keeps background syncing from using it up.
+pub const ACTIVE_BYTES_PER_TICK: u64 = 64 * 1024;
+
+fn default_reset_at() -> String {
+    "00:00".to_string()
+}
+
+/// Daily allowance of time a profile's devices may spend on some apps, or
+/// online at all. Once it is used up the apps are blocked until the next reset.
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+#[schema(example = json!({
+    "id": "1700000000000",
+    "profile": "emma",
+    "apps": ["youtube"],
+    "minutes_per_day": 120,
+    "reset_at": "00:00",
+    "timezone": "America/New_York",
+    "site": "default",
+    "used_seconds": 1830,
+    "day": "2024-01-15"
+}))]
+pub struct Budget {
+    /// Unique identifier
+    pub id: String,
+    /// Profile whose devices share the allowance
+    pub profile: String,
+    #[serde(default)]
+    /// Apps the time is counted on; empty counts all time online
+    pub apps: Vec<String>,
+    /// Allowance per day in minutes
+    pub minutes_per_day: u32,
+    #[serde(default = "default_reset_at")]
+    /// Local time the allowance starts over (HH:MM)
+    pub reset_at: String,
+    #[serde(default = "default_timezone")]
+    /// IANA timezone of `reset_at`
+    pub timezone: String,
+    /// UniFi site the profile's devices are on
+    pub site: String,
+    #[serde(default)]
+    /// Time spent so far in the current day
+    pub used_seconds: u64,
+    #[serde(default)]
+    /// Local date the current day started on
+    pub day: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Rule blocking the apps since the allowance ran out today
+    pub rule_id: Option<String>,
+    #[serde(skip)]
+    /// DPI byte counter at the last check, to tell whether the apps were used since
+    pub last_bytes: Option<u64>,
+    #[serde(skip)]
+    /// When the devices were last checked, to charge the time since
+    pub last_checked: Option<DateTime<Utc>>,
+}
+
+impl Budget {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.minutes_per_day == 0 || self.minutes_per_day > 24 * 60 {
+            return Err("minutesPerDay must be between 1 and 1440".to_string());
+        }
+        self.timezone
+            .parse::<Tz>()
+            .map_err(|_| format!("Unknown timezone: {}", self.timezone))?;
+        schedule::parse_time(&self.reset_at)?;
+        Ok(())
+    }
+
+    pub fn remaining_seconds(&self) -> u64 {
+        (self.minutes_per_day as u64 * 60).saturating_sub(self.used_seconds)
+    }
+
+    /// Local date the day containing `now` started on, and when the next one starts
+    pub fn day_bounds(&self, now: DateTime<Utc>) -> Option<(NaiveDate, DateTime<Utc>)> {
+        let tz = self.timezone.parse::<Tz>().ok()?;
+        let reset_at = schedule::parse_time(&self.reset_at).ok()?;
+        let local = now.with_timezone(&tz);
+        let day = if local.time() >= reset_at {
+            local.date_naive()
+        } else {
+            local.date_naive().pred_opt()?
+        };
+        // A reset time skipped by a DST change falls back to the hour after
+        let next = day.succ_opt()?.and_time(reset_at);
+        let next = tz.from_local_datetime(&next)
+            .earliest()
+            .or_else(|| tz.from_local_datetime(&(next + Duration::hours(1))).earliest())?;
+        Some((day, next.with_timezone(&Utc)))
+    }
+
+    /// Time to charge for use seen at `now`: the time since the last check,
+    /// but never more than `max_seconds`, so a stalled or suspended host
+    /// doesn't charge for time it couldn't see
+    pub fn seconds_since_check(&self, now: DateTime<Utc>, max_seconds: u64) -> u64 {
+        match self.last_checked {
+            Some(last) => ((now - last).num_seconds().max(0) as u64).min(max_seconds),
+            None => max_seconds,
+        }
+    }
+
+    /// Start over if the reset time has passed since the day began. Returns
+    /// whether it did. The day's block is an until rule that ends by itself.
+    pub fn roll_over(&mut self, now: DateTime<Utc>) -> bool {
+        let Some((day, _)) = self.day_bounds(now) else {
+            return false;
+        };
+        let day = day.to_string();
+        if self.day == day {
+            return false;
+        }
+        self.day = day;
+        self.used_seconds = 0;
+        self.rule_id = None;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget() -> Budget {
+        Budget {
+            id: "1".to_string(),
+            profile: "emma".to_string(),
+            apps: vec!["youtube".to_string()],
+            minutes_per_day: 120,
+            reset_at: default_reset_at(),
+            timezone: "UTC".to_string(),
+            site: "default".to_string(),
+            used_seconds: 0,
+            day: String::new(),
+            rule_id: None,
+            last_bytes: None,
+            last_checked: None,
+        }
+    }
+
+    #[test]
+    fn charges_time_since_last_check_up_to_one_tick() {
+        let now = Utc::now();
+        let mut budget = budget();
+        assert_eq!(budget.seconds_since_check(now, 30), 30);
+
+        budget.last_checked = Some(now - Duration::seconds(12));
+        assert_eq!(budget.seconds_since_check(now, 30), 12);
+
+        // The host was asleep for ten minutes
+        budget.last_checked = Some(now - Duration::minutes(10));
+        assert_eq!(budget.seconds_since_check(now, 30), 30);
+
+        // The clock went backwards
+        budget.last_checked = Some(now + Duration::seconds(5));
+        assert_eq!(budget.seconds_since_check(now, 30), 0);
+    }
+}
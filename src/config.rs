@@ -0,0 +1,331 @@
+use serde::Deserialize;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+// Config file read when neither --config nor PUC_CONFIG names one
+const DEFAULT_CONFIG_FILE: &str = "parental_unifi.toml";
+
+const USAGE: &str = "Usage: parental-unifi-quick-set [OPTIONS]
+
+Options:
+  --config <FILE>      Config file (default: parental_unifi.toml if present)
+  --data-dir <DIR>     Directory for the rules database and saved login
+  --bind <ADDR>        Address to listen on (default: 0.0.0.0)
+  --port <PORT>        Port to listen on (default: 3000)
+  --site <NAME>        UniFi site used when a request names none
+  --unifi-url <URL>    Controller URL used when a login names none
+  --verify-tls         Require a valid certificate from the controller
+  --log-level <FILTER> Level or RUST_LOG-style filter (default: info)
+  --audit-retention-days <DAYS>
+                       Days of audit log to keep, 0 for all (default: 90)
+  --audit-max-entries <COUNT>
+                       Most audit log entries to keep, 0 for all (default: 10000)
+  -h, --help           Show this help
+
+Every option can also be set in the config file (data_dir, bind, port,
+default_site, unifi_url, verify_tls, log_level, audit_retention_days,
+audit_max_entries) or through the environment (PUC_DATA_DIR, PUC_BIND, PORT,
+UNIFI_SITE, UNIFI_URL, PUC_VERIFY_TLS, RUST_LOG, PUC_AUDIT_RETENTION_DAYS,
+PUC_AUDIT_MAX_ENTRIES). Flags override the environment, which overrides the file.";
+
+/// Runtime settings, resolved once at startup
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Directory holding the rules database and saved credentials
+    pub data_dir: PathBuf,
+    pub bind: IpAddr,
+    pub port: u16,
+    /// Site used when a request doesn't name one
+    pub default_site: String,
+    /// Controller to log in to when the login form leaves the URL empty
+    pub unifi_url: Option<String>,
+    /// Reject self-signed controller certificates
+    pub verify_tls: bool,
+    /// Log filter in `RUST_LOG` syntax, e.g. `info` or `info,hyper=warn`
+    pub log_level: String,
+    /// Days of audit log to keep; 0 keeps everything
+    pub audit_retention_days: u32,
+    /// Most audit log entries to keep; 0 means no limit
+    pub audit_max_entries: usize,
+    /// Config file the settings were read from, if any
+    pub file: Option<String>,
+}
+
+// The config file as written. Settings are flat: top-level keys only.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    data_dir: Option<PathBuf>,
+    bind: Option<IpAddr>,
+    port: Option<u16>,
+    default_site: Option<String>,
+    unifi_url: Option<String>,
+    verify_tls: Option<bool>,
+    log_level: Option<String>,
+    audit_retention_days: Option<u32>,
+    audit_max_entries: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            data_dir: PathBuf::from("."),
+            bind: IpAddr::from([0, 0, 0, 0]),
+            port: 3000,
+            default_site: crate::unifi::DEFAULT_SITE.to_string(),
+            unifi_url: None,
+            verify_tls: false,
+            log_level: "info".to_string(),
+            audit_retention_days: 90,
+            audit_max_entries: 10_000,
+            file: None,
+        }
+    }
+}
+
+impl Config {
+    /// Defaults, overridden by the config file, then the environment, then flags
+    pub fn load() -> Result<Self, String> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        let flags = parse_args(&args)?;
+
+        let mut config = Config::default();
+
+        let explicit_file = flag(&flags, "config")
+            .map(String::from)
+            .or_else(|| env("PUC_CONFIG"));
+        let file = explicit_file.clone().unwrap_or_else(|| DEFAULT_CONFIG_FILE.to_string());
+        if Path::new(&file).exists() {
+            let content = fs::read_to_string(&file)
+                .map_err(|e| format!("Failed to read config file {}: {}", file, e))?;
+            config.apply_file(&content).map_err(|e| format!("{}: {}", file, e))?;
+            config.file = Some(file);
+        } else if explicit_file.is_some() {
+            return Err(format!("Config file {} does not exist", file));
+        }
+
+        let env_overrides = [
+            ("data_dir", "PUC_DATA_DIR"),
+            ("bind", "PUC_BIND"),
+            ("port", "PUC_PORT"),
+            ("port", "PORT"),
+            ("default_site", "UNIFI_SITE"),
+            ("unifi_url", "UNIFI_URL"),
+            ("verify_tls", "PUC_VERIFY_TLS"),
+            ("log_level", "RUST_LOG"),
+            ("audit_retention_days", "PUC_AUDIT_RETENTION_DAYS"),
+            ("audit_max_entries", "PUC_AUDIT_MAX_ENTRIES"),
+        ];
+        for (key, var) in env_overrides {
+            if let Some(value) = env(var) {
+                config.set(key, &value).map_err(|e| format!("{}: {}", var, e))?;
+            }
+        }
+
+        for (key, value) in flags.iter().filter(|(key, _)| key != "config") {
+            config.set(key, value).map_err(|e| format!("--{}: {}", key.replace('_', "-"), e))?;
+        }
+
+        Ok(config)
+    }
+
+    pub fn listen_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+
+    /// Location of a state file inside the data directory
+    pub fn data_file(&self, name: &str) -> PathBuf {
+        self.data_dir.join(name)
+    }
+
+    fn apply_file(&mut self, content: &str) -> Result<(), String> {
+        let file: ConfigFile = toml::from_str(content).map_err(|e| e.to_string())?;
+        if let Some(data_dir) = file.data_dir {
+            self.data_dir = data_dir;
+        }
+        if let Some(bind) = file.bind {
+            self.bind = bind;
+        }
+        if let Some(port) = file.port {
+            self.port = port;
+        }
+        if let Some(verify_tls) = file.verify_tls {
+            self.verify_tls = verify_tls;
+        }
+        if let Some(days) = file.audit_retention_days {
+            self.audit_retention_days = days;
+        }
+        if let Some(entries) = file.audit_max_entries {
+            self.audit_max_entries = entries;
+        }
+        // Strings go through the same checks as flags and the environment
+        for (key, value) in [
+            ("default_site", file.default_site),
+            ("unifi_url", file.unifi_url),
+            ("log_level", file.log_level),
+        ] {
+            if let Some(value) = value {
+                self.set(key, &value)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "data_dir" => self.data_dir = PathBuf::from(value),
+            "bind" => {
+                self.bind = value.parse().map_err(|_| format!("Invalid bind address '{}'", value))?
+            }
+            "port" => self.port = value.parse().map_err(|_| format!("Invalid port '{}'", value))?,
+            "default_site" if !value.is_empty() => self.default_site = value.to_string(),
+            "default_site" => return Err("Site name cannot be empty".to_string()),
+            "unifi_url" => self.unifi_url = Some(value.to_string()).filter(|u| !u.is_empty()),
+            "verify_tls" => self.verify_tls = parse_bool(value)?,
+            "log_level" => self.log_level = parse_log_filter(value)?,
+            "audit_retention_days" => {
+                self.audit_retention_days = value.parse().map_err(|_| format!("Invalid number of days '{}'", value))?
+            }
+            "audit_max_entries" => {
+                self.audit_max_entries = value.parse().map_err(|_| format!("Invalid number of entries '{}'", value))?
+            }
+            _ => return Err(format!("Unknown setting '{}'", key)),
+        }
+        Ok(())
+    }
+}
+
+fn env(var: &str) -> Option<String> {
+    std::env::var(var).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+fn flag<'a>(flags: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    flags.iter().rev().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+}
+
+// Command line flags as (setting, value) pairs, in the order given
+fn parse_args(args: &[String]) -> Result<Vec<(String, String)>, String> {
+    let mut flags = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (arg.as_str(), None),
+        };
+        let key = match name {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            "--verify-tls" => {
+                flags.push(("verify_tls".to_string(), inline_value.unwrap_or_else(|| "true".to_string())));
+                continue;
+            }
+            "--config" => "config",
+            "--data-dir" => "data_dir",
+            "--bind" => "bind",
+            "--port" => "port",
+            "--site" => "default_site",
+            "--unifi-url" => "unifi_url",
+            "--log-level" => "log_level",
+            "--audit-retention-days" => "audit_retention_days",
+            "--audit-max-entries" => "audit_max_entries",
+            _ => return Err(format!("Unknown option '{}'\n\n{}", arg, USAGE)),
+        };
+        let value = match inline_value {
+            Some(value) => value,
+            None => args.next().cloned().ok_or_else(|| format!("{} needs a value", name))?,
+        };
+        flags.push((key.to_string(), value));
+    }
+    Ok(flags)
+}
+
+// Check a filter in `RUST_LOG` syntax: comma-separated directives, each a
+// level, a module path, or `module=level`. A bare word that isn't a level
+// names a module, so only the levels after `=` can be checked.
+fn parse_log_filter(value: &str) -> Result<String, String> {
+    const LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+    let filter = value.trim().to_string();
+    // A trailing /regex filters messages and is passed through as is
+    let directives = filter.split('/').next().unwrap_or_default();
+    for directive in directives.split(',').map(str::trim) {
+        let Some((_, level)) = directive.split_once('=') else {
+            continue;
+        };
+        let level = level.trim().to_lowercase();
+        if !LEVELS.contains(&level.as_str()) {
+            return Err(format!("Invalid log level '{}' in '{}', expected off, error, warn, info, debug or trace", level, value));
+        }
+    }
+    Ok(filter)
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Ok(true),
+        "false" | "0" | "no" | "off" => Ok(false),
+        _ => Err(format!("Invalid boolean '{}'", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_filter_accepts_rust_log_syntax() {
+        for filter in ["info", "DEBUG", "parental_unifi=debug", "info,hyper=warn", "hyper", "warn/timeout"] {
+            assert!(parse_log_filter(filter).is_ok(), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn log_filter_rejects_unknown_levels() {
+        assert!(parse_log_filter("info,hyper=loud").is_err());
+        assert!(parse_log_filter("parental_unifi=").is_err());
+    }
+
+    #[test]
+    fn config_file_sets_typed_values() {
+        let mut config = Config::default();
+        config
+            .apply_file(
+                r#"
+                data_dir = "/var/lib/parental-unifi"
+                bind = "127.0.0.1"
+                port = 8080             # comments are fine
+                unifi_url = "https://192.168.1.1"
+                verify_tls = true
+                log_level = "info,hyper=warn"
+                audit_max_entries = 0
+                "#,
+            )
+            .unwrap();
+        assert_eq!(config.data_dir, PathBuf::from("/var/lib/parental-unifi"));
+        assert_eq!(config.listen_addr(), "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(config.unifi_url.as_deref(), Some("https://192.168.1.1"));
+        assert!(config.verify_tls);
+        assert_eq!(config.log_level, "info,hyper=warn");
+        assert_eq!(config.audit_max_entries, 0);
+        assert_eq!(config.audit_retention_days, 90);
+    }
+
+    #[test]
+    fn config_file_rejects_what_it_does_not_understand() {
+        for content in [
+            "prot = 8080",                  // unknown setting
+            "[server]\nport = 8080",        // sections
+            "port = \"eighty\"",            // wrong type
+            "port = 70000",                 // out of range
+            "verify_tls = \"maybe\"",
+            "bind = \"localhost\"",
+            "default_site = \"\"",
+            "log_level = \"hyper=loud\"",
+            "port = 8080\nport = 8081",     // duplicate key
+        ] {
+            assert!(Config::default().apply_file(content).is_err(), "{}", content);
+        }
+    }
+}
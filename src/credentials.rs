@@ -0,0 +1,247 @@
+use crate::storage;
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Encrypted controller login, kept in the data directory next to the rules database
+const CREDENTIALS_FILE: &str = "unifi_credentials.json";
+// Secret used to derive the encryption key, given directly or as a file path
+const SECRET_KEY_ENV: &str = "PUC_SECRET_KEY";
+const SECRET_KEY_FILE_ENV: &str = "PUC_SECRET_KEY_FILE";
+// PBKDF2-SHA256 rounds for new files; each file records the rounds it used
+const KDF_ITERATIONS: u32 = 100_000;
+
+/// Controller login remembered across restarts
+#[derive(Serialize, Deserialize)]
+pub struct StoredCredentials {
+    pub url: String,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+    /// Set when connecting with an API key instead of a local admin
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+}
+
+// On-disk format: AES-256-GCM ciphertext of the JSON credentials, under a
+// key derived from the secret with PBKDF2. Files written before the salt was
+// added used a plain SHA-256 of the secret; they are still read, and
+// rewritten with a salt as soon as they are.
+#[derive(Serialize, Deserialize)]
+struct EncryptedFile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    salt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    iterations: Option<u32>,
+    nonce: String,
+    ciphertext: String,
+}
+
+pub struct CredentialStore {
+    secret: String,
+    path: PathBuf,
+}
+
+impl CredentialStore {
+    /// Build the store from the configured secret, if there is one.
+    /// Without a secret, credentials are never written to disk.
+    pub fn from_env(data_dir: &Path) -> Option<Self> {
+        let secret = match std::env::var(SECRET_KEY_ENV) {
+            Ok(secret) => secret,
+            Err(_) => {
+                let path = std::env::var(SECRET_KEY_FILE_ENV).ok()?;
+                match fs::read_to_string(&path) {
+                    Ok(secret) => secret,
+                    Err(e) => {
+                        warn!("⚠️ Failed to read secret key file {}: {}", path, e);
+                        return None;
+                    }
+                }
+            }
+        };
+
+        Self::new(secret.trim(), data_dir)
+    }
+
+    fn new(secret: &str, data_dir: &Path) -> Option<Self> {
+        if secret.is_empty() {
+            return None;
+        }
+        Some(Self {
+            secret: secret.to_string(),
+            path: data_dir.join(CREDENTIALS_FILE),
+        })
+    }
+
+    pub fn load(&self) -> Option<StoredCredentials> {
+        if !self.path.exists() {
+            return None;
+        }
+
+        let decrypted = fs::read_to_string(&self.path)
+            .map_err(|e| format!("Failed to read credentials: {}", e))
+            .and_then(|content| {
+                serde_json::from_str::<EncryptedFile>(&content)
+                    .map_err(|e| format!("Failed to parse credentials: {}", e))
+            })
+            .and_then(|file| self.decrypt(&file).map(|credentials| (credentials, file.salt.is_none())));
+
+        match decrypted {
+            Ok((credentials, unsalted)) => {
+                if unsalted {
+                    if let Err(e) = self.save(&credentials) {
+                        warn!("⚠️ Could not re-encrypt credentials with a salted key: {}", e);
+                    }
+                }
+                Some(credentials)
+            }
+            Err(e) => {
+                warn!("⚠️ {}", e);
+                None
+            }
+        }
+    }
+
+    pub fn save(&self, credentials: &StoredCredentials) -> Result<(), String> {
+        let plaintext = serde_json::to_vec(credentials)
+            .map_err(|e| format!("Failed to serialize credentials: {}", e))?;
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher(Some((&salt, KDF_ITERATIONS)))
+            .encrypt(&nonce, plaintext.as_ref())
+            .map_err(|_| "Failed to encrypt credentials".to_string())?;
+
+        let file = EncryptedFile {
+            salt: Some(BASE64.encode(salt)),
+            iterations: Some(KDF_ITERATIONS),
+            nonce: BASE64.encode(nonce),
+            ciphertext: BASE64.encode(ciphertext),
+        };
+        let content = serde_json::to_string_pretty(&file)
+            .map_err(|e| format!("Failed to serialize credentials: {}", e))?;
+        storage::write_atomic(&self.path, content.as_bytes())
+            .map_err(|e| format!("Failed to write credentials: {}", e))?;
+        info!("🔐 Saved encrypted controller credentials");
+        Ok(())
+    }
+
+    fn decrypt(&self, file: &EncryptedFile) -> Result<StoredCredentials, String> {
+        let nonce = BASE64.decode(&file.nonce)
+            .map_err(|_| "Invalid credentials nonce".to_string())?;
+        if nonce.len() != 12 {
+            return Err("Invalid credentials nonce".to_string());
+        }
+        let ciphertext = BASE64.decode(&file.ciphertext)
+            .map_err(|_| "Invalid credentials ciphertext".to_string())?;
+        let salt = file.salt.as_ref()
+            .map(|salt| BASE64.decode(salt).map_err(|_| "Invalid credentials salt".to_string()))
+            .transpose()?;
+        let kdf = match (&salt, file.iterations) {
+            (Some(salt), Some(iterations)) if iterations > 0 => Some((salt.as_slice(), iterations)),
+            (None, None) => None,
+            _ => return Err("Invalid credentials key parameters".to_string()),
+        };
+        let plaintext = self.cipher(kdf)
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+            .map_err(|_| "Failed to decrypt credentials (wrong secret key?)".to_string())?;
+        serde_json::from_slice(&plaintext)
+            .map_err(|e| format!("Failed to parse credentials: {}", e))
+    }
+
+    // Cipher for a file's salt and PBKDF2 rounds, or for the unsalted
+    // SHA-256 key of files written before there was a salt
+    fn cipher(&self, kdf: Option<(&[u8], u32)>) -> Aes256Gcm {
+        let mut key = [0u8; 32];
+        match kdf {
+            Some((salt, iterations)) => pbkdf2::pbkdf2_hmac::<Sha256>(self.secret.as_bytes(), salt, iterations, &mut key),
+            None => key.copy_from_slice(&Sha256::digest(self.secret.as_bytes())),
+        }
+        Aes256Gcm::new(&key.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials() -> StoredCredentials {
+        StoredCredentials {
+            url: "https://192.168.1.1".to_string(),
+            username: "admin".to_string(),
+            password: "hunter2".to_string(),
+            api_key: None,
+        }
+    }
+
+    fn stored_file(dir: &Path) -> EncryptedFile {
+        serde_json::from_str(&fs::read_to_string(dir.join(CREDENTIALS_FILE)).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn credentials_round_trip_under_a_salted_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = CredentialStore::new("correct horse", dir.path()).unwrap();
+        store.save(&credentials()).unwrap();
+
+        let file = stored_file(dir.path());
+        assert_eq!(BASE64.decode(file.salt.unwrap()).unwrap().len(), 16);
+        assert_eq!(file.iterations, Some(KDF_ITERATIONS));
+
+        let loaded = store.load().unwrap();
+        assert_eq!(loaded.url, "https://192.168.1.1");
+        assert_eq!(loaded.username, "admin");
+        assert_eq!(loaded.password, "hunter2");
+    }
+
+    #[test]
+    fn wrong_secret_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        CredentialStore::new("correct horse", dir.path()).unwrap().save(&credentials()).unwrap();
+        assert!(CredentialStore::new("battery staple", dir.path()).unwrap().load().is_none());
+    }
+
+    #[test]
+    fn tampered_ciphertext_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = CredentialStore::new("correct horse", dir.path()).unwrap();
+        store.save(&credentials()).unwrap();
+
+        let mut file = stored_file(dir.path());
+        let mut ciphertext = BASE64.decode(&file.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        file.ciphertext = BASE64.encode(ciphertext);
+        fs::write(dir.path().join(CREDENTIALS_FILE), serde_json::to_string(&file).unwrap()).unwrap();
+
+        assert!(store.load().is_none());
+    }
+
+    #[test]
+    fn unsalted_files_are_read_and_rewritten_with_a_salt() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = CredentialStore::new("correct horse", dir.path()).unwrap();
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let plaintext = serde_json::to_vec(&credentials()).unwrap();
+        let ciphertext = store.cipher(None).encrypt(&nonce, plaintext.as_ref()).unwrap();
+        let legacy = serde_json::json!({ "nonce": BASE64.encode(nonce), "ciphertext": BASE64.encode(ciphertext) });
+        fs::write(dir.path().join(CREDENTIALS_FILE), legacy.to_string()).unwrap();
+
+        assert_eq!(store.load().unwrap().password, "hunter2");
+        assert!(stored_file(dir.path()).salt.is_some());
+        assert_eq!(store.load().unwrap().password, "hunter2");
+    }
+
+    #[test]
+    fn empty_secret_stores_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(CredentialStore::new("", dir.path()).is_none());
+    }
+}
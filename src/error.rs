@@ -0,0 +1,92 @@
+use crate::unifi::UnifiError;
+use crate::ApiResponse;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+
+/// Failed API request, sent as an `ApiResponse` with a matching HTTP status
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, message)
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, message)
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::CONFLICT, message)
+    }
+
+    pub fn too_many_requests(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::TOO_MANY_REQUESTS, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, message)
+    }
+
+    /// The controller failed or refused a request made on the caller's behalf
+    pub fn bad_gateway(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_GATEWAY, message)
+    }
+}
+
+// Not being connected to a controller is a state the caller can fix by
+// logging in, so it's a conflict rather than a gateway failure. The app's own
+// sign-in is the only thing answered with 401.
+impl From<UnifiError> for ApiError {
+    fn from(error: UnifiError) -> Self {
+        match error {
+            UnifiError::NotAuthenticated => ApiError::conflict(error.to_string()),
+            _ => ApiError::bad_gateway(error.to_string()),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (
+            self.status,
+            Json(ApiResponse {
+                success: false,
+                error: Some(self.message),
+                message: None,
+            }),
+        ).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn not_being_connected_is_a_conflict_and_other_controller_errors_a_bad_gateway() {
+        assert_eq!(ApiError::from(UnifiError::NotAuthenticated).status, StatusCode::CONFLICT);
+        assert_eq!(ApiError::from(UnifiError::InvalidResponse("no data".to_string())).status, StatusCode::BAD_GATEWAY);
+        assert_eq!(ApiError::from(UnifiError::SessionExpired).status, StatusCode::BAD_GATEWAY);
+        assert_eq!(ApiError::from(UnifiError::Api("api.err.Invalid".to_string())).status, StatusCode::BAD_GATEWAY);
+    }
+}
@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Longest extra time a single grant can give
+pub const MAX_GRANT_MINUTES: u32 = 12 * 60;
+
+/// Extra time on a rule: its block is lifted until `until`, then comes back
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+#[schema(example = json!({
+    "rule_id": "1700000000000",
+    "profile": "emma",
+    "granted_by": "mom",
+    "granted_at": "2024-01-15T18:00:00Z",
+    "until": "2024-01-15T18:30:00Z"
+}))]
+pub struct Grant {
+    /// Rule whose block is lifted
+    pub rule_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Profile the time was granted to, when it covers all of a child's rules
+    pub profile: Option<String>,
+    /// Account that granted the time
+    pub granted_by: String,
+    /// When the time was granted (ISO 8601)
+    pub granted_at: String,
+    /// When the block comes back (ISO 8601)
+    pub until: String,
+}
+
+impl Grant {
+    pub fn until(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        chrono::DateTime::parse_from_rfc3339(&self.until)
+            .ok()
+            .map(|t| t.with_timezone(&chrono::Utc))
+    }
+
+    /// Whether the grant still lifts its rule at `now`. Unreadable end times
+    /// count as over, so a bad record can't leave a block off for good.
+    pub fn is_active_at(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        self.until().map(|until| until > now).unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grant(until: &str) -> Grant {
+        Grant {
+            rule_id: "1".to_string(),
+            profile: None,
+            granted_by: "mom".to_string(),
+            granted_at: "2024-01-15T18:00:00Z".to_string(),
+            until: until.to_string(),
+        }
+    }
+
+    #[test]
+    fn grant_runs_until_its_end_time() {
+        let grant = grant("2024-01-15T18:30:00Z");
+        let end = grant.until().unwrap();
+        assert!(grant.is_active_at(end - chrono::Duration::seconds(1)));
+        assert!(!grant.is_active_at(end));
+    }
+
+    #[test]
+    fn unreadable_end_time_counts_as_over() {
+        let grant = grant("half past six");
+        assert!(grant.until().is_none());
+        assert!(!grant.is_active_at(chrono::DateTime::<chrono::Utc>::MIN_UTC));
+    }
+}
@@ -1,19 +1,53 @@
 use axum::{
-    extract::{Json, State},
-    response::{Html, IntoResponse},
-    routing::{get, post},
+    extract::{ConnectInfo, Extension, Json, Path as UrlPath, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware,
+    response::{Html, IntoResponse, Response},
+    routing::{delete, get, post},
     Router,
 };
-use reqwest::{Client, header};
+use log::{debug, error, info, warn};
+use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use utoipa::{OpenApi, ToSchema};
+use utoipa::{IntoParams, OpenApi, ToSchema};
 use std::fs;
-use std::path::Path;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 
-// Rule persistence configuration
+mod access;
+mod apps;
+mod audit;
+mod auth;
+mod blocking;
+mod budgets;
+mod config;
+mod credentials;
+mod error;
+mod grants;
+mod profiles;
+mod schedule;
+mod scheduler;
+mod storage;
+mod unifi;
+
+use access::{AccessRequest, AccessStatus};
+use apps::{AppBundle, AppCatalog, CatalogApp};
+use audit::{AuditAction, AuditEntry, AuditLog};
+use auth::{AuthStore, CurrentUser, Permission, Role};
+use blocking::{BlockPlan, BlockStrategy, CreatedBlock, PausedStation};
+use budgets::Budget;
+use config::Config;
+use credentials::{CredentialStore, StoredCredentials};
+use error::ApiError;
+use grants::Grant;
+use profiles::Profile;
+use schedule::Schedule;
+use unifi::{ClientDevice, FirewallGroup, FirewallRule, Site, TrafficRule, UnifiClient, UnifiError};
+
+// Rule database file name, inside the configured data directory
 const RULES_DB_FILE: &str = "parental_rules.json";
 const RULE_NAME_PREFIX: &str = "[PUC]"; // Parental UniFi Control prefix for UniFi rules
 
@@ -21,45 +55,116 @@ const RULE_NAME_PREFIX: &str = "[PUC]"; // Parental UniFi Control prefix for Uni
 #[derive(Serialize, Deserialize, Clone)]
 struct RuleDatabase {
     rules: Vec<ActiveRule>,
+    #[serde(default)]
+    profiles: Vec<Profile>,
+    #[serde(default)]
+    grants: Vec<Grant>,
+    #[serde(default)]
+    budgets: Vec<Budget>,
+    #[serde(default)]
+    access_requests: Vec<AccessRequest>,
+    #[serde(default)]
+    paused_stations: Vec<PausedStation>,
     created_at: String,
     last_updated: String,
+    #[serde(skip)]
+    path: PathBuf,
 }
 
 impl RuleDatabase {
-    fn new() -> Self {
+    fn new(path: PathBuf) -> Self {
         Self {
             rules: Vec::new(),
+            profiles: Vec::new(),
+            grants: Vec::new(),
+            budgets: Vec::new(),
+            access_requests: Vec::new(),
+            paused_stations: Vec::new(),
             created_at: chrono::Utc::now().to_rfc3339(),
             last_updated: chrono::Utc::now().to_rfc3339(),
+            path,
         }
     }
 
-    fn load() -> Self {
-        if Path::new(RULES_DB_FILE).exists() {
-            match fs::read_to_string(RULES_DB_FILE) {
-                Ok(content) => {
-                    match serde_json::from_str::<RuleDatabase>(&content) {
-                        Ok(db) => {
-                            println!("📂 Loaded {} rules from persistent storage", db.rules.len());
-                            return db;
-                        }
-                        Err(e) => println!("⚠️ Failed to parse rules database: {}", e),
-                    }
+    /// Load the stored rules, falling back to the backup copy if the main
+    /// file is unreadable. Errors only when neither copy can be read, since
+    /// starting empty would orphan every rule still on the controller.
+    fn load(path: PathBuf) -> Result<Self, String> {
+        let backup = storage::backup_path(&path);
+
+        let error = match Self::read(&path) {
+            Ok(Some(db)) => return Ok(db.loaded(path)),
+            Ok(None) => None,
+            Err(e) => {
+                warn!("⚠️ {}", e);
+                Some(e)
+            }
+        };
+
+        match Self::read(&backup) {
+            Ok(Some(db)) => {
+                info!("🩹 Recovered rules database from {}", backup.display());
+                if error.is_some() {
+                    // Keep the unreadable file around instead of rotating it over the backup
+                    let corrupt = storage::corrupt_path(&path);
+                    fs::rename(&path, &corrupt)
+                        .map_err(|e| format!("Failed to move aside corrupt rules database: {}", e))?;
+                    warn!("⚠️ Moved unreadable rules database to {}", corrupt.display());
+                }
+                Ok(db.loaded(path))
+            }
+            Ok(None) => match error {
+                Some(e) => Err(format!("{} and no backup is available", e)),
+                None => {
+                    info!("📂 Creating new rules database");
+                    Ok(RuleDatabase::new(path))
+                }
+            },
+            Err(backup_error) => Err(match error {
+                Some(e) => format!("{}; backup is unusable too: {}", e, backup_error),
+                None => backup_error,
+            }),
+        }
+    }
+
+    // Parse one copy of the database; Ok(None) if the file doesn't exist
+    fn read(path: &Path) -> Result<Option<Self>, String> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        serde_json::from_str::<RuleDatabase>(&content)
+            .map(Some)
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+    }
+
+    fn loaded(mut self, path: PathBuf) -> Self {
+        self.path = path;
+        info!("📂 Loaded {} rules from persistent storage", self.rules.len());
+        // Deadlines are derived from the stored rule fields, so timed
+        // rules keep counting down across restarts.
+        let now = chrono::Utc::now();
+        for rule in self.rules.iter() {
+            if let Some(expires_at) = rule.expires_at() {
+                if expires_at <= now {
+                    info!("⏰ Rule {} expired while offline, will be removed", rule.id);
+                } else {
+                    debug!("⏳ Rule {} expires at {}", rule.id, expires_at.to_rfc3339());
                 }
-                Err(e) => println!("⚠️ Failed to read rules database: {}", e),
             }
         }
-        println!("📂 Creating new rules database");
-        RuleDatabase::new()
+        self
     }
 
     fn save(&mut self) -> Result<(), String> {
+        self.track_paused_stations();
         self.last_updated = chrono::Utc::now().to_rfc3339();
         match serde_json::to_string_pretty(self) {
             Ok(content) => {
-                match fs::write(RULES_DB_FILE, content) {
+                match storage::write_with_backup(&self.path, content.as_bytes()) {
                     Ok(_) => {
-                        println!("💾 Saved {} rules to persistent storage", self.rules.len());
+                        debug!("💾 Saved {} rules to persistent storage", self.rules.len());
                         Ok(())
                     }
                     Err(e) => Err(format!("Failed to write rules database: {}", e))
@@ -69,6 +174,18 @@ impl RuleDatabase {
         }
     }
 
+    // Remember every device a rule has paused through the station manager
+    fn track_paused_stations(&mut self) {
+        for rule in &self.rules {
+            for mac in &rule.unifi_paused_macs {
+                let station = PausedStation { site: rule.site.clone(), mac: mac.clone() };
+                if !self.paused_stations.contains(&station) {
+                    self.paused_stations.push(station);
+                }
+            }
+        }
+    }
+
     fn add_rule(&mut self, rule: ActiveRule) -> Result<(), String> {
         // Check for duplicate IDs
         if self.rules.iter().any(|r| r.id == rule.id) {
@@ -78,55 +195,146 @@ impl RuleDatabase {
         self.save()
     }
 
-    fn remove_rule(&mut self, rule_id: &str) -> Option<ActiveRule> {
-        if let Some(pos) = self.rules.iter().position(|r| r.id == rule_id) {
-            let rule = self.rules.remove(pos);
-            let _ = self.save();
-            Some(rule)
-        } else {
-            None
-        }
+    // Forget a rule and its grant. A failed save still leaves the rule gone
+    // from memory, and the next successful save writes that out.
+    fn remove_rule(&mut self, rule_id: &str) -> Result<Option<ActiveRule>, String> {
+        let Some(pos) = self.rules.iter().position(|r| r.id == rule_id) else {
+            return Ok(None);
+        };
+        let rule = self.rules.remove(pos);
+        self.grants.retain(|g| g.rule_id != rule_id);
+        self.save().map(|_| Some(rule))
     }
 
-    fn update_rule(&mut self, rule_id: &str, updated_rule: ActiveRule) -> Result<(), String> {
-        if let Some(pos) = self.rules.iter().position(|r| r.id == rule_id) {
-            self.rules[pos] = updated_rule;
-            self.save()
-        } else {
-            Err("Rule not found".to_string())
-        }
+    // Change a stored rule in place and save, returning the rule as stored.
+    // Callers change only the fields they own, so concurrent edits don't undo
+    // each other.
+    fn update_rule(&mut self, rule_id: &str, update: impl FnOnce(&mut ActiveRule)) -> Result<Option<ActiveRule>, String> {
+        let Some(rule) = self.rules.iter_mut().find(|r| r.id == rule_id) else {
+            return Ok(None);
+        };
+        update(rule);
+        let rule = rule.clone();
+        self.save().map(|_| Some(rule))
     }
 
-    fn clear_all(&mut self) -> Result<(), String> {
-        self.rules.clear();
+    // Forget several rules at once, along with their grants
+    fn remove_rules(&mut self, rule_ids: &[String]) -> Result<(), String> {
+        self.rules.retain(|r| !rule_ids.contains(&r.id));
+        self.grants.retain(|g| !rule_ids.contains(&g.rule_id));
         self.save()
     }
 
     fn get_rules(&self) -> &Vec<ActiveRule> {
         &self.rules
     }
+
+    fn get_profile(&self, profile_id: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|p| p.id == profile_id)
+    }
+
+    fn add_profile(&mut self, profile: Profile) -> Result<(), String> {
+        if self.get_profile(&profile.id).is_some() {
+            return Err(format!("Profile {} already exists", profile.id));
+        }
+        self.profiles.push(profile);
+        self.save()
+    }
+
+    fn remove_profile(&mut self, profile_id: &str) -> Result<Profile, String> {
+        if self.rules.iter().any(|r| r.profile.as_deref() == Some(profile_id)) {
+            return Err("Remove the profile's rules before deleting it".to_string());
+        }
+        let pos = self.profiles.iter().position(|p| p.id == profile_id)
+            .ok_or_else(|| "Profile not found".to_string())?;
+        let profile = self.profiles.remove(pos);
+        self.budgets.retain(|b| b.profile != profile_id);
+        self.save()?;
+        Ok(profile)
+    }
+
+    /// Whether a rule should be blocking right now, taking extra time into account
+    fn enforced(&self, rule: &ActiveRule, now: chrono::DateTime<chrono::Utc>) -> bool {
+        rule.should_enforce(now)
+            && !self.grants.iter().any(|g| g.rule_id == rule.id && g.is_active_at(now))
+    }
+
+    /// Drop grants whose time is up, returning them
+    fn take_expired_grants(&mut self, now: chrono::DateTime<chrono::Utc>) -> Vec<Grant> {
+        let (expired, active) = self.grants.drain(..).partition(|g| !g.is_active_at(now));
+        self.grants = active;
+        expired
+    }
+
+    fn expired_rules(&self, now: chrono::DateTime<chrono::Utc>) -> Vec<ActiveRule> {
+        self.rules
+            .iter()
+            .filter(|r| r.expires_at().map(|t| t <= now).unwrap_or(false))
+            .cloned()
+            .collect()
+    }
 }
 
 // OpenAPI Documentation
 #[derive(OpenApi)]
 #[openapi(
     paths(
+        auth_status,
+        auth_setup,
+        auth_login,
+        auth_logout,
+        create_account,
+        set_account_role,
+        create_api_token,
+        revoke_api_token,
         login_handler,
+        get_sites,
         get_devices,
+        get_apps,
+        refresh_apps,
+        get_profiles,
+        create_profile,
+        update_profile,
+        delete_profile,
         create_block_rule,
         unblock_rule,
         unblock_all_rules,
         get_rules,
         sync_rules,
-        cleanup_rules
+        cleanup_rules,
+        list_rules,
+        create_rule,
+        get_rule,
+        patch_rule,
+        enable_rule,
+        disable_rule,
+        delete_rule,
+        list_grants,
+        create_grant,
+        delete_grant,
+        list_budgets,
+        create_budget,
+        delete_budget,
+        kid_status,
+        create_kid_request,
+        list_access_requests,
+        approve_access_request,
+        deny_access_request,
+        get_audit_log
     ),
     components(
-        schemas(LoginRequest, BlockRule, UnblockRequest, ApiResponse, DevicesResponse, DeviceInfo, RulesResponse, ActiveRule)
+        schemas(AppsResponse, CatalogApp, AppBundle, ProfileRequest, ProfileIdRequest, ProfilesResponse, Profile, AccountRequest, AuthResponse, AuthStatusResponse, TokenRequest, RoleRequest, Role, LoginRequest, BlockRule, UnblockRequest, RuleUpdate, GrantRequest, Grant, BudgetRequest, Budget, KidRule, KidStatus, KidAccessRequest, AccessDecision, AccessRequest, AccessStatus, AuditPage, AuditEntry, AuditAction, ApiResponse, SitesResponse, SiteInfo, DevicesResponse, DeviceInfo, RulesResponse, ActiveRule, Schedule, BlockStrategy)
     ),
     tags(
+        (name = "accounts", description = "Sign-in to this app"),
         (name = "authentication", description = "UniFi controller authentication"),
+        (name = "sites", description = "UniFi site selection"),
         (name = "devices", description = "Network device management"),
-        (name = "rules", description = "Parental control rule management")
+        (name = "profiles", description = "Children and the devices they use"),
+        (name = "apps", description = "Blockable apps from the controller's DPI database"),
+        (name = "rules", description = "Parental control rule management"),
+        (name = "kids", description = "Requests from children to lift a block"),
+        (name = "audit", description = "History of rule changes")
     ),
     info(
         title = "Parental UniFi Quick Set API",
@@ -228,11 +436,36 @@ async fn docs_page() -> impl IntoResponse {
         <a href="https://editor.swagger.io/" target="_blank" class="button">🔧 Swagger Editor</a>
     </div>
 
+    <div class="section">
+        <h2>👤 Accounts</h2>
+        <p>Every <code>/api</code> endpoint below needs a signed-in session cookie or an <code>Authorization: Bearer</code> token.</p>
+        <div class="endpoint">
+            <span class="method">POST</span> /api/auth/setup
+            <p>Create the first account. Only allowed while no accounts exist.</p>
+        </div>
+        <div class="endpoint">
+            <span class="method">POST</span> /api/auth/login
+            <p>Sign in with a username and password. Sets the session cookie and returns the session token.</p>
+        </div>
+        <div class="endpoint">
+            <span class="method">POST</span> /api/auth/tokens
+            <p>Issue a named long-lived API token for scripts. Revoke it with <code>/api/auth/tokens/revoke</code>.</p>
+        </div>
+    </div>
+
     <div class="section">
         <h2>🔐 Authentication</h2>
         <div class="endpoint">
             <span class="method">POST</span> /api/login
-            <p>Connect to your UniFi controller with local admin credentials.</p>
+            <p>Connect to your UniFi controller with local admin credentials, or with an API key (<code>apiKey</code>) from UniFi Network.</p>
+        </div>
+    </div>
+
+    <div class="section">
+        <h2>🏢 Sites</h2>
+        <div class="endpoint">
+            <span class="method">GET</span> /api/sites
+            <p>List the UniFi sites discovered at login. Pass <code>?site=name</code> to device and rule endpoints to work on a site other than "default".</p>
         </div>
     </div>
 
@@ -244,11 +477,32 @@ async fn docs_page() -> impl IntoResponse {
         </div>
     </div>
 
+    <div class="section">
+        <h2>📦 Apps</h2>
+        <div class="endpoint">
+            <span class="method">GET</span> /api/apps?q=you
+            <p>Search the apps that can be blocked. Once connected, the list comes from the controller's DPI database.</p>
+        </div>
+    </div>
+
+    <div class="section">
+        <h2>👧 Profiles</h2>
+        <div class="endpoint">
+            <span class="method">GET</span> /api/profiles
+            <p>List children and the devices grouped under each.</p>
+        </div>
+        <div class="endpoint">
+            <span class="method">POST</span> /api/profiles
+            <p>Create a profile with a name, avatar and device MACs. Update it with <code>/api/profiles/update</code>, delete it with <code>/api/profiles/delete</code>.</p>
+        </div>
+        <p>Create a rule with <code>"profile": "emma"</code> to block all of Emma's devices; devices added to the profile later are covered automatically.</p>
+    </div>
+
     <div class="section">
         <h2>🚫 Rule Management</h2>
         <div class="endpoint">
             <span class="method">POST</span> /api/block
-            <p>Create a new blocking rule for apps (Fortnite, YouTube, etc.) with flexible scheduling.</p>
+            <p>Create a new blocking rule for apps (Fortnite, YouTube, etc.) or domains (roblox.com) with flexible scheduling.</p>
         </div>
         <div class="endpoint">
             <span class="method">GET</span> /api/rules
@@ -274,18 +528,21 @@ async fn docs_page() -> impl IntoResponse {
 
     <div class="section">
         <h2>🔧 Quick Start</h2>
-        <pre><code># 1. Connect to UniFi
-curl -X POST http://localhost:3000/api/login \\
+        <pre><code># 1. Sign in and connect to UniFi
+curl -c cookies.txt -X POST http://localhost:3000/api/auth/login \\
+  -H "Content-Type: application/json" \\
+  -d '{"username":"parent","password":"YOUR_APP_PASSWORD"}'
+curl -b cookies.txt -X POST http://localhost:3000/api/login \\
   -H "Content-Type: application/json" \\
   -d '{"url":"https://192.168.1.1:8443","username":"admin","password":"YOUR_PASSWORD"}'
 
 # 2. Block gaming apps
-curl -X POST http://localhost:3000/api/block \\
+curl -b cookies.txt -X POST http://localhost:3000/api/block \\
   -H "Content-Type: application/json" \\
   -d '{"id":"1234","apps":["fortnite","roblox"],"type":"permanent","devices":["all"],"status":"active","created":"2024-01-01T12:00:00Z"}'
 
 # 3. List active rules
-curl http://localhost:3000/api/rules</code></pre>
+curl -b cookies.txt http://localhost:3000/api/rules</code></pre>
     </div>
 
     <div class="section">
@@ -300,6 +557,69 @@ curl http://localhost:3000/api/rules</code></pre>
 }
 
 // Request/Response structures with OpenAPI schemas
+#[derive(Deserialize, ToSchema)]
+#[schema(example = json!({
+    "username": "parent",
+    "password": "YOUR_APP_PASSWORD"
+}))]
+struct AccountRequest {
+    /// Account name for this app (not the UniFi login)
+    username: String,
+    /// At least 8 characters
+    password: String,
+    #[serde(default)]
+    /// Role for new accounts (defaults to read_only; the first account is always parent)
+    role: Option<Role>,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[schema(example = json!({
+    "username": "grandma",
+    "role": "co_parent"
+}))]
+struct RoleRequest {
+    /// Account to change
+    username: String,
+    /// New role
+    role: Role,
+}
+
+#[derive(Serialize, ToSchema)]
+struct AuthResponse {
+    /// Whether the operation was successful
+    success: bool,
+    /// Signed-in account
+    username: String,
+    /// Role of the signed-in account
+    role: Role,
+    /// Session or API token, usable as a Bearer token
+    token: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct AuthStatusResponse {
+    /// Whether the request carries a valid session or token
+    authenticated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Signed-in account
+    username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Role of the signed-in account
+    role: Option<Role>,
+    #[serde(rename = "setupRequired")]
+    /// True until the first account has been created
+    setup_required: bool,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[schema(example = json!({
+    "name": "home-assistant"
+}))]
+struct TokenRequest {
+    /// Label identifying the API token
+    name: String,
+}
+
 #[derive(Deserialize, ToSchema)]
 #[schema(example = json!({
     "url": "https://192.168.1.1:8443",
@@ -307,15 +627,21 @@ curl http://localhost:3000/api/rules</code></pre>
     "password": "YOUR_SECURE_PASSWORD"
 }))]
 struct LoginRequest {
-    /// UniFi controller URL (e.g., https://192.168.1.1:8443)
+    #[serde(default)]
+    /// UniFi controller URL (e.g., https://192.168.1.1:8443); defaults to the configured controller
     url: String,
+    #[serde(default)]
     /// Local admin username (recommended over cloud accounts)
     username: String,
+    #[serde(default)]
     /// Local admin password
     password: String,
+    #[serde(rename = "apiKey", default)]
+    /// UniFi Network API key, used instead of username and password
+    api_key: Option<String>,
 }
 
-#[derive(Deserialize, ToSchema)]
+#[derive(Serialize, Deserialize, ToSchema)]
 #[schema(example = json!({
     "id": "1642781234567",
     "apps": ["fortnite", "roblox"],
@@ -325,21 +651,36 @@ struct LoginRequest {
     "created": "2024-01-01T12:00:00Z",
     "duration": 2,
     "endTime": "2024-01-01T14:00:00Z",
-    "scheduleType": "bedtime"
+    "scheduleType": "bedtime",
+    "timezone": "America/New_York",
+    "strategy": "traffic_rules"
 }))]
 struct BlockRule {
-    /// Unique identifier for the rule
+    #[serde(default)]
+    /// Unique identifier for the rule (generated if omitted)
     id: String,
+    #[serde(default)]
     /// List of app names to block (fortnite, roblox, youtube, etc.)
     apps: Vec<String>,
+    #[serde(default)]
+    /// Domains to block, including their subdomains (roblox.com, rbxcdn.com)
+    domains: Vec<String>,
+    #[serde(default)]
+    /// Take the devices off the internet entirely instead of blocking apps
+    pause: bool,
     #[serde(rename = "type")]
     /// Type of blocking: permanent, duration, until, schedule
     rule_type: String,
     /// Target devices (MAC addresses or "all")
     devices: Vec<String>,
-    /// Rule status: active or disabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// UniFi site to create the rule on (defaults to "default")
+    site: Option<String>,
+    #[serde(default)]
+    /// Rule status: active (default) or disabled
     status: String,
-    /// ISO timestamp when rule was created
+    #[serde(default)]
+    /// ISO timestamp when rule was created (defaults to now)
     created: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     /// Duration in hours (for duration type)
@@ -350,6 +691,18 @@ struct BlockRule {
     #[serde(rename = "scheduleType", skip_serializing_if = "Option::is_none")]
     /// Schedule type for recurring rules (bedtime, homework, etc.)
     schedule_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Custom recurring window (for schedule type); overrides scheduleType presets
+    schedule: Option<Schedule>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// IANA timezone for scheduleType presets (defaults to the server's TZ)
+    timezone: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// How to block on the controller (defaults to traffic_rules)
+    strategy: Option<BlockStrategy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Profile to target instead of devices; follows the profile's device list
+    profile: Option<String>,
 }
 
 #[derive(Deserialize, ToSchema)]
@@ -362,7 +715,162 @@ struct UnblockRequest {
     rule_id: String,
 }
 
+/// Changes to an existing rule; omitted fields are left alone
+#[derive(Deserialize, ToSchema)]
+#[schema(example = json!({
+    "apps": ["fortnite", "roblox", "minecraft"],
+    "duration": 3
+}))]
+struct RuleUpdate {
+    /// New list of apps to block, replacing the current one
+    apps: Option<Vec<String>>,
+    /// New device MACs, or "all" (not for profile rules, which follow their profile)
+    devices: Option<Vec<String>>,
+    /// New duration in hours, counted from the rule's creation (duration rules)
+    duration: Option<u32>,
+    #[serde(rename = "endTime")]
+    /// New end time as an ISO timestamp (until rules)
+    end_time: Option<String>,
+}
+
+/// Extra time for one rule or for every active rule of a profile
+#[derive(Deserialize, ToSchema)]
+#[schema(example = json!({
+    "profile": "emma",
+    "minutes": 30
+}))]
+struct GrantRequest {
+    #[serde(rename = "ruleId")]
+    /// Rule to lift
+    rule_id: Option<String>,
+    /// Profile whose active rules are all lifted, instead of `ruleId`
+    profile: Option<String>,
+    /// How long to lift the block for; asking again adds to time already granted
+    minutes: u32,
+}
+
+/// Daily allowance for a profile
+#[derive(Deserialize, ToSchema)]
+#[schema(example = json!({
+    "profile": "emma",
+    "apps": ["youtube"],
+    "minutesPerDay": 120,
+    "resetAt": "00:00",
+    "timezone": "America/New_York"
+}))]
+struct BudgetRequest {
+    /// Profile whose devices share the allowance
+    profile: String,
+    #[serde(default)]
+    /// Apps (or bundles) the time is counted on; empty counts all time online
+    apps: Vec<String>,
+    #[serde(rename = "minutesPerDay")]
+    /// Allowance per day in minutes
+    minutes_per_day: u32,
+    #[serde(rename = "resetAt")]
+    /// Local time the allowance starts over (HH:MM, defaults to midnight)
+    reset_at: Option<String>,
+    /// IANA timezone for resetAt (defaults to the server's TZ)
+    timezone: Option<String>,
+    /// UniFi site the devices are on (defaults to "default")
+    site: Option<String>,
+}
+
+/// A rule as shown to the child it applies to
+#[derive(Serialize, ToSchema)]
+struct KidRule {
+    id: String,
+    apps: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    domains: Vec<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pause: bool,
+    /// Whether the rule is blocking right now
+    blocking: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// When a timed rule ends (ISO 8601)
+    ends: Option<String>,
+}
+
+/// What a child's device can see about itself
+#[derive(Serialize, ToSchema)]
+struct KidStatus {
+    mac: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_name: Option<String>,
+    /// Rules aimed at this device
+    rules: Vec<KidRule>,
+    /// The device's recent requests, newest first
+    requests: Vec<AccessRequest>,
+}
+
+/// A child asking for a rule to be lifted
+#[derive(Deserialize, ToSchema)]
+#[schema(example = json!({
+    "ruleId": "1642781234567",
+    "reason": "Need a video for my science project",
+    "minutes": 30
+}))]
+struct KidAccessRequest {
+    #[serde(rename = "ruleId")]
+    /// Rule to lift
+    rule_id: String,
+    /// Why the child needs it
+    reason: String,
+    /// Minutes asked for (defaults to 30)
+    minutes: Option<u32>,
+}
+
+/// A parent's answer to an access request
+#[derive(Deserialize, ToSchema)]
+#[schema(example = json!({
+    "minutes": 20,
+    "note": "20 minutes, then homework"
+}))]
+struct AccessDecision {
+    /// Minutes to grant, instead of those asked for (approvals only)
+    minutes: Option<u32>,
+    /// Note shown to the child
+    note: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+struct AccessRequestQuery {
+    /// Only requests with this status (pending, approved, denied)
+    status: Option<AccessStatus>,
+}
+
+// Longest page of audit log entries
+const MAX_AUDIT_PAGE: usize = 500;
+
+#[derive(Deserialize, IntoParams)]
+struct AuditQuery {
+    /// Page to return, starting at 1
+    page: Option<usize>,
+    #[serde(rename = "perPage")]
+    /// Entries per page (default 50, at most 500)
+    per_page: Option<usize>,
+    #[serde(rename = "ruleId")]
+    /// Only changes to this rule
+    rule_id: Option<String>,
+    /// Only changes made by this account
+    actor: Option<String>,
+    /// Only this kind of change
+    action: Option<AuditAction>,
+}
+
 #[derive(Serialize, ToSchema)]
+struct AuditPage {
+    /// Matching entries on this page, newest first
+    entries: Vec<AuditEntry>,
+    page: usize,
+    #[serde(rename = "perPage")]
+    per_page: usize,
+    /// Matching entries across all pages
+    total: usize,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
 struct ApiResponse {
     /// Whether the operation was successful
     success: bool,
@@ -374,6 +882,32 @@ struct ApiResponse {
     message: Option<String>,
 }
 
+#[derive(Deserialize, IntoParams)]
+struct SiteQuery {
+    /// UniFi site name (defaults to "default")
+    site: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct SitesResponse {
+    /// Whether the operation was successful
+    success: bool,
+    /// Sites available on the controller
+    sites: Vec<SiteInfo>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[schema(example = json!({
+    "name": "default",
+    "description": "Home"
+}))]
+struct SiteInfo {
+    /// Short site name used in API calls
+    name: String,
+    /// Site name as shown in the UniFi UI
+    description: String,
+}
+
 #[derive(Serialize, ToSchema)]
 struct DevicesResponse {
     /// Whether the operation was successful
@@ -396,6 +930,65 @@ struct DeviceInfo {
     #[serde(rename = "type")]
     /// Device manufacturer or type
     device_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// ID of the profile the device belongs to
+    profile: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[schema(example = json!({
+    "name": "Emma",
+    "avatar": "🦄",
+    "devices": ["aa:bb:cc:dd:ee:ff"]
+}))]
+struct ProfileRequest {
+    /// Profile to update; omitted when creating
+    id: Option<String>,
+    /// Display name
+    name: String,
+    #[serde(default)]
+    /// Emoji or image URL
+    avatar: Option<String>,
+    #[serde(default)]
+    /// MAC addresses of the child's devices
+    devices: Vec<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[schema(example = json!({
+    "id": "emma"
+}))]
+struct ProfileIdRequest {
+    /// Profile ID
+    id: String,
+}
+
+#[derive(Deserialize, IntoParams)]
+struct AppsQuery {
+    /// Part of the app name to look for
+    q: Option<String>,
+    /// Only apps in this DPI category
+    category: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct AppsResponse {
+    /// Whether the operation was successful
+    success: bool,
+    /// Where the catalog came from: builtin or controller
+    source: String,
+    /// Matching apps
+    apps: Vec<CatalogApp>,
+    /// Bundles from apps.toml, usable in block rules like app names
+    bundles: Vec<AppBundle>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ProfilesResponse {
+    /// Whether the operation was successful
+    success: bool,
+    /// Child profiles
+    profiles: Vec<Profile>,
 }
 
 #[derive(Serialize, ToSchema)]
@@ -412,6 +1005,7 @@ struct RulesResponse {
     "apps": ["fortnite", "roblox"],
     "rule_type": "permanent",
     "devices": ["all"],
+    "site": "default",
     "status": "active",
     "created": "2024-01-01T12:00:00Z",
     "unifi_rule_id": "61d1234567890abcdef12345"
@@ -421,11 +1015,20 @@ struct ActiveRule {
     id: String,
     /// List of blocked app names
     apps: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// Blocked domains
+    domains: Vec<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    /// Whether the devices are cut off from the internet entirely
+    pause: bool,
     /// Type of blocking rule
     rule_type: String,
     /// Target devices
     devices: Vec<String>,
-    /// Current rule status
+    #[serde(default = "default_site")]
+    /// UniFi site the rule lives on
+    site: String,
+    /// Current rule status: active, or disabled to keep the rule without enforcing it
     status: String,
     /// When the rule was created
     created: String,
@@ -439,191 +1042,466 @@ struct ActiveRule {
     /// Schedule type for recurring rules
     schedule_type: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    /// UniFi firewall rule ID (internal)
+    /// Recurring window during which the rule is enforced
+    schedule: Option<Schedule>,
+    #[serde(default)]
+    /// How the block is expressed on the controller
+    strategy: BlockStrategy,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Profile whose devices the rule follows
+    profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// UniFi traffic or firewall rule ID (internal)
     unifi_rule_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// UniFi firewall group holding the targeted devices (internal)
+    unifi_group_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// UniFi traffic rule blocking the domains (internal)
+    unifi_domain_rule_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// Devices blocked through the station manager for a pause (internal)
+    unifi_paused_macs: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Enabled flag last pushed to the UniFi rule (internal)
+    unifi_enabled: Option<bool>,
+}
+
+impl ActiveRule {
+    /// When the rule should be lifted, if it is a timed rule.
+    ///
+    /// "duration" rules run for `duration` hours from `created`, "until" rules
+    /// end at `end_time`. Permanent and scheduled rules never expire.
+    fn expires_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        match self.rule_type.as_str() {
+            "duration" => {
+                let created = chrono::DateTime::parse_from_rfc3339(&self.created).ok()?;
+                let hours = self.duration?;
+                Some(created.with_timezone(&chrono::Utc) + chrono::Duration::hours(hours as i64))
+            }
+            "until" => {
+                let end_time = self.end_time.as_ref()?;
+                chrono::DateTime::parse_from_rfc3339(end_time)
+                    .ok()
+                    .map(|t| t.with_timezone(&chrono::Utc))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the UniFi rule should currently be blocking traffic. A timed
+    /// rule stops at its expiry, even before the scheduler removes it.
+    fn should_enforce(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        self.status == "active"
+            && self.expires_at().map(|t| now < t).unwrap_or(true)
+            && self.schedule.as_ref().map(|s| s.is_active_at(now)).unwrap_or(true)
+    }
+
+    /// Whether anything was created on the controller for this rule
+    fn on_controller(&self) -> bool {
+        self.unifi_rule_id.is_some()
+            || self.unifi_group_id.is_some()
+            || self.unifi_domain_rule_id.is_some()
+            || !self.unifi_paused_macs.is_empty()
+    }
+
+    /// MACs the rule is aimed at, empty for every device
+    fn device_macs(&self) -> Vec<String> {
+        if targets_all_devices(&self.devices) {
+            vec![]
+        } else {
+            self.devices.clone()
+        }
+    }
+
+    /// Lifting a permanent rule takes more than lifting one that ends anyway
+    fn removal_permission(&self) -> Permission {
+        if self.rule_type == "permanent" {
+            Permission::RemovePermanentRule
+        } else {
+            Permission::RemoveRule
+        }
+    }
+
+    /// What the rule created on the controller, for the blocking strategy
+    fn controller_objects(&self) -> CreatedBlock {
+        CreatedBlock {
+            unifi_rule_id: self.unifi_rule_id.clone(),
+            unifi_group_id: self.unifi_group_id.clone(),
+            unifi_domain_rule_id: self.unifi_domain_rule_id.clone(),
+            unifi_paused_macs: self.unifi_paused_macs.clone(),
+        }
+    }
+}
+
+fn default_site() -> String {
+    unifi::DEFAULT_SITE.to_string()
+}
+
+// Last ID handed out by `new_id`
+static LAST_ID: AtomicI64 = AtomicI64::new(0);
+
+// ID for a new rule, budget or access request: the current time in
+// milliseconds, moved past the last ID handed out so two created in the same
+// millisecond don't clash
+fn new_id() -> String {
+    let now = chrono::Utc::now().timestamp_millis();
+    let previous = LAST_ID.fetch_max(now, Ordering::SeqCst);
+    let id = if previous >= now { LAST_ID.fetch_add(1, Ordering::SeqCst) + 1 } else { now };
+    id.to_string()
+}
+
+// An empty device list or an "all" entry means the whole network
+fn targets_all_devices(devices: &[String]) -> bool {
+    devices.is_empty() || devices.iter().any(|d| d.eq_ignore_ascii_case("all"))
 }
 
 // Enhanced rule management
 impl AppState {
-    fn new() -> Self {
-        let mut app_id_map = HashMap::new();
-        
-        // Extended app mapping with more popular apps
-        app_id_map.insert("fortnite".to_string(), "655369".to_string());
-        app_id_map.insert("roblox".to_string(), "851993".to_string());
-        app_id_map.insert("youtube".to_string(), "851969".to_string());
-        app_id_map.insert("tiktok".to_string(), "855327".to_string());
-        app_id_map.insert("instagram".to_string(), "655311".to_string());
-        app_id_map.insert("snapchat".to_string(), "655301".to_string());
-        app_id_map.insert("netflix".to_string(), "655324".to_string());
-        app_id_map.insert("twitch".to_string(), "655328".to_string());
-        app_id_map.insert("discord".to_string(), "655365".to_string());
-        app_id_map.insert("minecraft".to_string(), "655370".to_string());
-
+    fn new(config: Config) -> Result<Self, String> {
         // Load persistent rules
-        let rules_db = RuleDatabase::load();
+        let rules_db = RuleDatabase::load(config.data_file(RULES_DB_FILE))?;
+        let apps = AppCatalog::load(config.data_file(apps::CATALOG_FILE), config.data_file(apps::OVERLAY_FILE))?;
 
-        Self {
+        let auth = AuthStore::load(config.data_file(auth::USERS_FILE))?;
+        if auth.needs_setup() {
+            info!("👤 No accounts yet, open the web UI to create the first one");
+        }
+
+        let audit = AuditLog::open(config.data_file(audit::AUDIT_LOG_FILE), audit::Retention {
+            days: config.audit_retention_days,
+            max_entries: config.audit_max_entries,
+        })?;
+
+        let credentials = CredentialStore::from_env(&config.data_dir).map(Arc::new);
+        if credentials.is_none() {
+            info!("🔑 No secret key configured, controller login will not survive restarts");
+        }
+
+        Ok(Self {
             client: Client::builder()
-                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_certs(!config.verify_tls)
                 .build()
                 .unwrap(),
-            unifi_url: Arc::new(Mutex::new(None)),
-            session_cookies: Arc::new(Mutex::new(None)),
-            app_id_map,
+            unifi: Arc::new(Mutex::new(None)),
+            sites: Arc::new(Mutex::new(Vec::new())),
+            credentials,
+            apps: Arc::new(Mutex::new(apps)),
             rules_db: Arc::new(Mutex::new(rules_db)),
-        }
+            block_strategy: BlockStrategy::from_env(),
+            config: Arc::new(config),
+            auth: Arc::new(Mutex::new(auth)),
+            audit: Arc::new(Mutex::new(audit)),
+        })
     }
 
-    // Sync rules with UniFi controller
-    async fn sync_rules_with_unifi(&self) -> Result<(), String> {
-        let unifi_url = self.unifi_url.lock().await.clone();
-        let cookies = self.session_cookies.lock().await.clone();
-
-        let (url, cookie_header) = match (unifi_url, cookies) {
-            (Some(url), Some(cookies)) => (url, cookies),
-            _ => return Err("Not authenticated with UniFi".to_string()),
+    // Adopt a freshly logged-in controller session and discover its sites.
+    // Returns the number of sites available.
+    async fn connect(&self, unifi: UnifiClient) -> usize {
+        let sites = match unifi.list_sites().await {
+            Ok(sites) => sites,
+            Err(e) => {
+                warn!("⚠️ Site discovery failed, using \"{}\": {}", unifi::DEFAULT_SITE, e);
+                vec![]
+            }
         };
+        info!("🏢 Found {} UniFi sites", sites.len());
+        let site_count = sites.len().max(1);
+        *self.sites.lock().await = sites;
 
-        // Get all firewall rules from UniFi
-        let firewall_url = if url.contains("/proxy/network") {
-            format!("{}/api/s/default/rest/firewallrule", url)
-        } else {
-            format!("{}/proxy/network/api/s/default/rest/firewallrule", url)
-        };
+        // The DPI database is the same on every site, so read it once
+        let site = self.default_site().await;
+        match self.apps.lock().await.refresh(&unifi, &site).await {
+            Ok(count) => info!("📦 Loaded {} apps from the controller's DPI database", count),
+            Err(e) => warn!("⚠️ Could not load the DPI app catalog, keeping the current one: {}", e),
+        }
 
-        println!("🔄 Syncing rules with UniFi controller...");
-        
-        match self.client.get(&firewall_url)
-            .header(header::COOKIE, cookie_header)
-            .send()
-            .await 
-        {
-            Ok(response) => {
-                if let Ok(json) = response.json::<serde_json::Value>().await {
-                    let empty_vec = vec![];
-                    let unifi_rules = json["data"].as_array().unwrap_or(&empty_vec);
-                    
-                    // Find our rules by name prefix
-                    let our_unifi_rules: Vec<&serde_json::Value> = unifi_rules
-                        .iter()
-                        .filter(|rule| {
-                            rule["name"].as_str()
-                                .map(|name| name.starts_with(RULE_NAME_PREFIX))
-                                .unwrap_or(false)
-                        })
-                        .collect();
-
-                    println!("🔍 Found {} UniFi rules created by our tool", our_unifi_rules.len());
-
-                    // Update our database with UniFi rule IDs
-                    let mut rules_db = self.rules_db.lock().await;
-                    for our_rule in rules_db.rules.iter_mut() {
-                        if our_rule.unifi_rule_id.is_none() {
-                            // Try to match by name
-                            let expected_name = format!("{} {}", RULE_NAME_PREFIX, our_rule.apps.join(", "));
-                            if let Some(unifi_rule) = our_unifi_rules.iter().find(|r| {
-                                r["name"].as_str() == Some(&expected_name)
-                            }) {
-                                our_rule.unifi_rule_id = unifi_rule["_id"].as_str().map(|s| s.to_string());
-                                println!("🔗 Linked rule {} to UniFi rule {}", our_rule.id, 
-                                    our_rule.unifi_rule_id.as_ref().unwrap());
-                            }
-                        }
-                    }
+        *self.unifi.lock().await = Some(unifi);
+        site_count
+    }
 
-                    let _ = rules_db.save();
-                    Ok(())
-                } else {
-                    Err("Failed to parse UniFi firewall rules".to_string())
-                }
+    // Log back in with credentials saved by a previous run
+    async fn restore_session(&self) -> bool {
+        let stored = match self.credentials.as_ref().and_then(|store| store.load()) {
+            Some(stored) => stored,
+            None => return false,
+        };
+
+        info!("🔐 Restoring controller session for {}", stored.url);
+        match open_session(self.client.clone(), &stored).await {
+            Ok(unifi) => {
+                self.connect(unifi).await;
+                true
+            }
+            Err(e) => {
+                warn!("⚠️ Could not restore controller session: {}", e);
+                false
             }
-            Err(e) => Err(format!("Failed to fetch UniFi rules: {}", e))
         }
     }
 
-    // Clean orphaned UniFi rules (rules in UniFi but not in our database)
-    async fn cleanup_orphaned_rules(&self) -> Result<u32, String> {
-        let unifi_url = self.unifi_url.lock().await.clone();
-        let cookies = self.session_cookies.lock().await.clone();
+    // Current controller session, if the user has logged in
+    async fn unifi(&self) -> Result<UnifiClient, UnifiError> {
+        self.unifi.lock().await.clone().ok_or(UnifiError::NotAuthenticated)
+    }
 
-        let (url, cookie_header) = match (unifi_url, cookies) {
-            (Some(url), Some(cookies)) => (url, cookies),
-            _ => return Err("Not authenticated with UniFi".to_string()),
-        };
+    // Site used when none is given: the configured default, or the
+    // controller's only site if the default isn't among the discovered ones
+    async fn default_site(&self) -> String {
+        match self.sites.lock().await.as_slice() {
+            [only] if only.name != self.config.default_site => only.name.clone(),
+            _ => self.config.default_site.clone(),
+        }
+    }
 
-        let firewall_url = if url.contains("/proxy/network") {
-            format!("{}/api/s/default/rest/firewallrule", url)
-        } else {
-            format!("{}/proxy/network/api/s/default/rest/firewallrule", url)
+    // Check a requested site against the ones discovered at login
+    async fn resolve_site(&self, requested: Option<&str>) -> Result<String, String> {
+        let site = match requested {
+            Some(site) if !site.trim().is_empty() => site.trim(),
+            _ => return Ok(self.default_site().await),
         };
+        let sites = self.sites.lock().await;
+        if !sites.is_empty() && !sites.iter().any(|s| s.name == site) {
+            return Err(format!("Unknown UniFi site: {}", site));
+        }
+        Ok(site.to_string())
+    }
 
-        match self.client.get(&firewall_url)
-            .header(header::COOKIE, &cookie_header)
-            .send()
-            .await 
-        {
-            Ok(response) => {
-                let status = response.status();
-                if let Ok(json) = response.json::<serde_json::Value>().await {
-                    let empty_vec = vec![];
-                    let unifi_rules = json["data"].as_array().unwrap_or(&empty_vec);
-                    
-                    // Find orphaned rules (our prefix but not in database)
-                    let rules_db = self.rules_db.lock().await;
-                    let our_rule_ids: Vec<&String> = rules_db.rules
-                        .iter()
-                        .filter_map(|r| r.unifi_rule_id.as_ref())
-                        .collect();
-
-                    let orphaned_rules: Vec<&serde_json::Value> = unifi_rules
-                        .iter()
-                        .filter(|rule| {
-                            let name = rule["name"].as_str().unwrap_or("");
-                            let rule_id = rule["_id"].as_str().unwrap_or("");
-                            name.starts_with(RULE_NAME_PREFIX) && !our_rule_ids.contains(&&rule_id.to_string())
-                        })
-                        .collect();
-
-                    println!("🧹 Found {} orphaned rules to clean up", orphaned_rules.len());
-
-                    let mut cleaned_count = 0;
-                    for orphaned_rule in orphaned_rules {
-                        if let Some(rule_id) = orphaned_rule["_id"].as_str() {
-                            let delete_url = if url.contains("/proxy/network") {
-                                format!("{}/api/s/default/rest/firewallrule/{}", url, rule_id)
-                            } else {
-                                format!("{}/proxy/network/api/s/default/rest/firewallrule/{}", url, rule_id)
-                            };
-
-                            match self.client.delete(&delete_url)
-                                .header(header::COOKIE, &cookie_header)
-                                .send()
-                                .await
-                            {
-                                Ok(resp) if resp.status().is_success() => {
-                                    cleaned_count += 1;
-                                    println!("🗑️ Cleaned orphaned rule: {}", 
-                                        orphaned_rule["name"].as_str().unwrap_or("unknown"));
-                                }
-                                Ok(_) => {
-                                    println!("⚠️ Failed to delete orphaned rule: HTTP {}", 
-                                        status);
-                                }
-                                Err(e) => {
-                                    println!("⚠️ Error deleting orphaned rule: {}", e);
-                                }
-                            }
-                        }
+    // Record a rule change in the audit log
+    async fn audit(&self, entry: AuditEntry) {
+        self.audit.lock().await.record(entry);
+    }
+
+    // Sites to sweep when no specific site was requested
+    async fn known_sites(&self) -> Vec<String> {
+        let mut sites: Vec<String> = self.sites.lock().await.iter().map(|s| s.name.clone()).collect();
+        for rule in self.rules_db.lock().await.rules.iter() {
+            if !sites.contains(&rule.site) {
+                sites.push(rule.site.clone());
+            }
+        }
+        if sites.is_empty() {
+            sites.push(self.config.default_site.clone());
+        }
+        sites
+    }
+
+    // Sync rules with UniFi controller
+    async fn sync_rules_with_unifi(&self, site: &str) -> Result<(), ApiError> {
+        let unifi = self.unifi().await?;
+
+        debug!("🔄 Syncing rules with UniFi controller (site {})...", site);
+        let unifi_rules = unifi.list_firewall_rules(site).await?;
+        let traffic_rules = blocking::list_our_traffic_rules(&unifi, site).await?;
+
+        // Find our rules by name prefix
+        let our_unifi_rules: Vec<&FirewallRule> = unifi_rules
+            .iter()
+            .filter(|rule| rule.name.starts_with(RULE_NAME_PREFIX))
+            .collect();
+
+        debug!("🔍 Found {} UniFi rules created by our tool",
+            our_unifi_rules.len() + traffic_rules.len());
+
+        let traffic_ids: Vec<&str> = traffic_rules.iter().filter_map(|r| r.id.as_deref()).collect();
+        let firewall_ids: Vec<&str> = our_unifi_rules.iter().filter_map(|r| r.id.as_deref()).collect();
+
+        // Rules keep the controller rule whose ID they stored. Only rules
+        // without one are linked by name, and never to a controller rule
+        // another rule already has, since several rules can block the same apps.
+        let mut rules_db = self.rules_db.lock().await;
+        let mut claimed: Vec<String> = Vec::new();
+        for our_rule in rules_db.rules.iter_mut().filter(|r| r.site == site) {
+            let controller_ids = match our_rule.strategy {
+                BlockStrategy::TrafficRules => &traffic_ids,
+                BlockStrategy::FirewallRules => &firewall_ids,
+            };
+            for (stored, ids) in [(&mut our_rule.unifi_rule_id, controller_ids), (&mut our_rule.unifi_domain_rule_id, &traffic_ids)] {
+                match stored.take() {
+                    Some(id) if claimed.contains(&id) => {
+                        warn!("⚠️ UniFi rule {} of rule {} already belongs to another rule", id, our_rule.id);
                     }
+                    Some(id) if ids.contains(&id.as_str()) => {
+                        claimed.push(id.clone());
+                        *stored = Some(id);
+                    }
+                    Some(id) => warn!("⚠️ UniFi rule {} of rule {} is gone from the controller", id, our_rule.id),
+                    None => {}
+                }
+            }
+        }
 
-                    Ok(cleaned_count)
+        for our_rule in rules_db.rules.iter_mut().filter(|r| r.site == site) {
+            if our_rule.unifi_rule_id.is_none() && (!our_rule.apps.is_empty() || our_rule.pause) {
+                let expected_name = if our_rule.pause {
+                    blocking::pause_rule_name(&our_rule.id)
                 } else {
-                    Err("Failed to parse UniFi firewall rules".to_string())
+                    blocking::rule_name(&our_rule.apps)
+                };
+                our_rule.unifi_rule_id = match our_rule.strategy {
+                    BlockStrategy::TrafficRules => traffic_rules.iter()
+                        .filter(|r| r.description == expected_name)
+                        .find_map(|r| r.id.clone().filter(|id| !claimed.contains(id))),
+                    BlockStrategy::FirewallRules => our_unifi_rules.iter()
+                        .filter(|r| r.name == expected_name)
+                        .find_map(|r| r.id.clone().filter(|id| !claimed.contains(id))),
+                };
+                if let Some(ref unifi_rule_id) = our_rule.unifi_rule_id {
+                    debug!("🔗 Linked rule {} to UniFi rule {}", our_rule.id, unifi_rule_id);
+                    claimed.push(unifi_rule_id.clone());
+                }
+            }
+            if our_rule.unifi_domain_rule_id.is_none() && !our_rule.domains.is_empty() {
+                let expected_name = blocking::rule_name(&our_rule.domains);
+                our_rule.unifi_domain_rule_id = traffic_rules.iter()
+                    .filter(|r| r.description == expected_name)
+                    .find_map(|r| r.id.clone().filter(|id| !claimed.contains(id)));
+                if let Some(ref unifi_rule_id) = our_rule.unifi_domain_rule_id {
+                    debug!("🔗 Linked rule {} to UniFi domain rule {}", our_rule.id, unifi_rule_id);
+                    claimed.push(unifi_rule_id.clone());
+                }
+            }
+        }
+
+        rules_db.save().map_err(ApiError::internal)
+    }
+
+    // Remove everything a rule created on the controller
+    async fn remove_from_unifi(&self, rule: &ActiveRule) -> Result<(), UnifiError> {
+        let unifi = self.unifi().await?;
+        rule.strategy.remove(&unifi, &rule.site, &rule.controller_objects()).await?;
+        if !rule.unifi_paused_macs.is_empty() {
+            self.rules_db.lock().await.paused_stations
+                .retain(|p| p.site != rule.site || !rule.unifi_paused_macs.contains(&p.mac));
+        }
+        Ok(())
+    }
+
+    // Turn everything a rule created on the controller on or off
+    async fn set_unifi_rule_enabled(&self, rule: &ActiveRule, enabled: bool) -> Result<(), UnifiError> {
+        let unifi = self.unifi().await?;
+        rule.strategy.set_enabled(&unifi, &rule.site, &rule.controller_objects(), enabled).await
+    }
+
+    // Clean orphaned UniFi rules (rules in UniFi but not in our database)
+    async fn cleanup_orphaned_rules(&self, site: &str) -> Result<u32, UnifiError> {
+        let unifi = self.unifi().await?;
+        let unifi_rules = unifi.list_firewall_rules(site).await?;
+
+        // Find orphaned rules (our prefix but not in database)
+        let (our_rule_ids, our_group_ids): (Vec<String>, Vec<String>) = {
+            let rules_db = self.rules_db.lock().await;
+            (
+                rules_db.rules.iter()
+                    .flat_map(|r| [r.unifi_rule_id.clone(), r.unifi_domain_rule_id.clone()])
+                    .flatten()
+                    .collect(),
+                rules_db.rules.iter().filter_map(|r| r.unifi_group_id.clone()).collect(),
+            )
+        };
+
+        let orphaned_rules: Vec<&FirewallRule> = unifi_rules
+            .iter()
+            .filter(|rule| {
+                let rule_id = rule.id.as_deref().unwrap_or("");
+                rule.name.starts_with(RULE_NAME_PREFIX) && !our_rule_ids.iter().any(|id| id == rule_id)
+            })
+            .collect();
+
+        let orphaned_traffic_rules: Vec<TrafficRule> = blocking::list_our_traffic_rules(&unifi, site).await?
+            .into_iter()
+            .filter(|rule| {
+                let rule_id = rule.id.as_deref().unwrap_or("");
+                !our_rule_ids.iter().any(|id| id == rule_id)
+            })
+            .collect();
+
+        info!("🧹 Found {} orphaned rules to clean up on site {}",
+            orphaned_rules.len() + orphaned_traffic_rules.len(), site);
+
+        let mut cleaned_count = 0;
+        for orphaned_rule in orphaned_traffic_rules {
+            if let Some(ref rule_id) = orphaned_rule.id {
+                match unifi.delete_traffic_rule(site, rule_id).await {
+                    Ok(_) => {
+                        cleaned_count += 1;
+                        info!("🗑️ Cleaned orphaned traffic rule: {}", orphaned_rule.description);
+                    }
+                    Err(e) => warn!("⚠️ Failed to delete orphaned traffic rule: {}", e),
+                }
+            }
+        }
+        for orphaned_rule in orphaned_rules {
+            if let Some(ref rule_id) = orphaned_rule.id {
+                match unifi.delete_firewall_rule(site, rule_id).await {
+                    Ok(_) => {
+                        cleaned_count += 1;
+                        info!("🗑️ Cleaned orphaned rule: {}", orphaned_rule.name);
+                    }
+                    Err(e) => warn!("⚠️ Failed to delete orphaned rule: {}", e),
+                }
+            }
+        }
+
+        // Devices still blocked by the station manager for a pause whose rule
+        // is gone, e.g. after unblocking everything failed halfway
+        let orphaned_pauses: Vec<String> = {
+            let rules_db = self.rules_db.lock().await;
+            rules_db.paused_stations.iter()
+                .filter(|p| p.site == site)
+                .filter(|p| !rules_db.rules.iter().any(|r| r.site == site && r.unifi_paused_macs.contains(&p.mac)))
+                .map(|p| p.mac.clone())
+                .collect()
+        };
+        let mut unpaused = Vec::new();
+        for mac in orphaned_pauses {
+            match unifi.unblock_station(site, &mac).await {
+                Ok(_) => {
+                    cleaned_count += 1;
+                    info!("🗑️ Unpaused orphaned device: {}", mac);
+                    unpaused.push(mac);
+                }
+                Err(e) => warn!("⚠️ Failed to unpause orphaned device {}: {}", mac, e),
+            }
+        }
+        if !unpaused.is_empty() {
+            let mut rules_db = self.rules_db.lock().await;
+            rules_db.paused_stations.retain(|p| p.site != site || !unpaused.contains(&p.mac));
+            if let Err(e) = rules_db.save() {
+                warn!("⚠️ Failed to record unpaused devices: {}", e);
+            }
+        }
+
+        // Device groups can only go once the rules using them are gone
+        let orphaned_groups: Vec<FirewallGroup> = unifi.list_firewall_groups(site).await?
+            .into_iter()
+            .filter(|group| {
+                let group_id = group.id.as_deref().unwrap_or("");
+                group.name.starts_with(RULE_NAME_PREFIX) && !our_group_ids.iter().any(|id| id == group_id)
+            })
+            .collect();
+
+        for orphaned_group in orphaned_groups {
+            if let Some(ref group_id) = orphaned_group.id {
+                match unifi.delete_firewall_group(site, group_id).await {
+                    Ok(_) => {
+                        cleaned_count += 1;
+                        info!("🗑️ Cleaned orphaned device group: {}", orphaned_group.name);
+                    }
+                    Err(e) => warn!("⚠️ Failed to delete orphaned device group: {}", e),
                 }
             }
-            Err(e) => Err(format!("Failed to fetch UniFi rules: {}", e))
         }
+
+        Ok(cleaned_count)
+    }
+}
+
+// Open a controller session with whichever credentials were supplied
+async fn open_session(http: Client, credentials: &StoredCredentials) -> Result<UnifiClient, UnifiError> {
+    match credentials.api_key {
+        Some(ref api_key) => UnifiClient::with_api_key(http, &credentials.url, api_key).await,
+        None => UnifiClient::login(http, &credentials.url, &credentials.username, &credentials.password).await,
     }
 }
 
@@ -631,625 +1509,3084 @@ impl AppState {
 #[derive(Clone)]
 struct AppState {
     client: Client,
-    unifi_url: Arc<Mutex<Option<String>>>,
-    session_cookies: Arc<Mutex<Option<String>>>,
-    app_id_map: HashMap<String, String>,
+    unifi: Arc<Mutex<Option<UnifiClient>>>,
+    sites: Arc<Mutex<Vec<Site>>>,
+    credentials: Option<Arc<CredentialStore>>,
+    apps: Arc<Mutex<AppCatalog>>,
     rules_db: Arc<Mutex<RuleDatabase>>,
+    block_strategy: BlockStrategy,
+    config: Arc<Config>,
+    auth: Arc<Mutex<AuthStore>>,
+    audit: Arc<Mutex<AuditLog>>,
 }
 
 async fn index() -> impl IntoResponse {
     Html(include_str!("../index.html"))
 }
 
-/// Authenticate with UniFi controller
+/// Sign-in status
 ///
-/// Connects to your UniFi controller using local admin credentials.
-/// Cloud accounts are not recommended due to MFA requirements.
+/// Reports whether the caller is signed in to this app and whether the
+/// first account still has to be created.
 #[utoipa::path(
-    post,
-    path = "/api/login",
-    tag = "authentication",
-    request_body = LoginRequest,
+    get,
+    path = "/api/auth/status",
+    tag = "accounts",
     responses(
-        (status = 200, description = "Login successful", body = ApiResponse),
-        (status = 401, description = "Authentication failed", body = ApiResponse),
-        (status = 400, description = "Invalid request format", body = ApiResponse)
+        (status = 200, description = "Current sign-in status", body = AuthStatusResponse)
     )
 )]
-async fn login_handler(
-    State(state): State<AppState>,
-    Json(request): Json<LoginRequest>,
-) -> impl IntoResponse {
-    println!("🔐 Login attempt to: {}", request.url);
-    
-    // Validate URL format
-    if !request.url.starts_with("https://") && !request.url.starts_with("http://") {
-        return Json(ApiResponse {
-            success: false,
-            error: Some("Invalid URL format. Must start with https:// or http://".to_string()),
-            message: None,
-        });
-    }
-
-    // For UniFi OS (UDM devices), use the correct auth endpoint
-    let login_url = if request.url.contains("/proxy/network") {
-        // User provided proxy/network URL - use traditional controller login
-        format!("{}/api/login", request.url)
-    } else {
-        // Regular UniFi OS URL - use the UniFi OS auth endpoint that works
-        format!("{}/api/auth/login", request.url)
-    };
-
-    let login_data = serde_json::json!({
-        "username": request.username,
-        "password": request.password
-    });
-
-    match state.client.post(&login_url)
-        .json(&login_data)
-        .send()
-        .await 
-    {
-        Ok(response) => {
-            if response.status().is_success() {
-                // Extract cookies for session management
-                let cookies = response
-                    .headers()
-                    .get_all(header::SET_COOKIE)
-                    .iter()
-                    .filter_map(|hv| hv.to_str().ok())
-                    .collect::<Vec<_>>()
-                    .join("; ");
-
-                // Update state
-                *state.unifi_url.lock().await = Some(request.url);
-                *state.session_cookies.lock().await = Some(cookies);
-
-                println!("✅ Login successful via {}", login_url);
-                Json(ApiResponse {
-                    success: true,
-                    error: None,
-                    message: Some("Connected successfully to UniFi OS".to_string()),
-                })
-            } else {
-                println!("❌ Login failed: HTTP {}", response.status());
-                Json(ApiResponse {
-                    success: false,
-                    error: Some(format!("Authentication failed: {}. Try using the UniFi OS auth endpoint.", response.status())),
-                    message: None,
-                })
-            }
-        }
-        Err(e) => {
-            println!("❌ Connection error: {}", e);
-            Json(ApiResponse {
-                success: false,
-                error: Some(format!("Connection failed: {}. Verify the UniFi controller is accessible.", e)),
-                message: None,
-            })
-        }
-    }
+async fn auth_status(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let auth = state.auth.lock().await;
+    let user = auth::request_token(&headers).and_then(|token| auth.authenticate(&token));
+    Json(AuthStatusResponse {
+        authenticated: user.is_some(),
+        username: user.as_ref().map(|u| u.username.clone()),
+        role: user.map(|u| u.role),
+        setup_required: auth.needs_setup(),
+    })
 }
 
-/// Get network devices
+/// Create the first account
 ///
-/// Discovers all devices connected to the UniFi network.
-/// Requires authentication first.
+/// Only allowed while no accounts exist. Signs the new account in.
 #[utoipa::path(
-    get,
-    path = "/api/devices",
-    tag = "devices",
+    post,
+    path = "/api/auth/setup",
+    tag = "accounts",
+    request_body = AccountRequest,
     responses(
-        (status = 200, description = "Devices retrieved successfully", body = DevicesResponse),
-        (status = 401, description = "Not authenticated", body = DevicesResponse)
+        (status = 200, description = "Account created and signed in", body = AuthResponse),
+        (status = 400, description = "Invalid username or password", body = ApiResponse),
+        (status = 409, description = "An account already exists", body = ApiResponse)
     )
 )]
-async fn get_devices(State(state): State<AppState>) -> impl IntoResponse {
-    let unifi_url = state.unifi_url.lock().await.clone();
-    let cookies = state.session_cookies.lock().await.clone();
-
-    let (url, cookie_header) = match (unifi_url, cookies) {
-        (Some(url), Some(cookies)) => (url, cookies),
-        _ => {
-            return Json(DevicesResponse {
-                success: false,
-                devices: vec![],
-            });
-        }
-    };
-
-    // For UniFi OS, we need to use the proxy endpoint to access network data
-    let devices_url = if url.contains("/proxy/network") {
-        // Traditional controller path
-        format!("{}/api/s/default/stat/sta", url)
-    } else {
-        // UniFi OS path - use proxy to access network controller
-        format!("{}/proxy/network/api/s/default/stat/sta", url)
-    };
-    
-    println!("🔍 Discovering devices at: {}", devices_url);
-    
-    match state.client.get(&devices_url)
-        .header(header::COOKIE, cookie_header)
-        .send()
-        .await 
-    {
-        Ok(response) => {
-            println!("📱 Device discovery response: HTTP {}", response.status());
-            if let Ok(json) = response.json::<serde_json::Value>().await {
-                println!("📊 Device data received: {}", json.to_string().chars().take(200).collect::<String>());
-                
-                let devices: Vec<DeviceInfo> = json["data"]
-                    .as_array()
-                    .unwrap_or(&vec![])
-                    .iter()
-                    .map(|device| DeviceInfo {
-                        mac: device["mac"].as_str().unwrap_or("").to_string(),
-                        name: device["hostname"].as_str()
-                            .or(device["name"].as_str())
-                            .or(device["display_name"].as_str())
-                            .map(|s| s.to_string()),
-                        device_type: device["oui"].as_str()
-                            .or(device["manufacturer"].as_str())
-                            .map(|s| s.to_string()),
-                    })
-                    .filter(|d| !d.mac.is_empty()) // Only include devices with MAC addresses
-                    .collect();
+async fn auth_setup(
+    State(state): State<AppState>,
+    Json(request): Json<AccountRequest>,
+) -> Result<Response, ApiError> {
+    if !state.auth.lock().await.needs_setup() {
+        return Err(ApiError::conflict("Setup is already complete, sign in instead"));
+    }
+    let password_hash = hash_new_password(request.password).await?;
 
-                println!("✅ Found {} devices", devices.len());
-                Json(DevicesResponse {
-                    success: true,
-                    devices,
-                })
-            } else {
-                println!("❌ Failed to parse device response");
-                Json(DevicesResponse {
-                    success: false,
-                    devices: vec![],
-                })
-            }
-        }
-        Err(e) => {
-            println!("❌ Device discovery error: {}", e);
-            Json(DevicesResponse {
-                success: false,
-                devices: vec![],
-            })
-        }
+    // Checked again in case another setup finished while hashing
+    let mut auth = state.auth.lock().await;
+    if !auth.needs_setup() {
+        return Err(ApiError::conflict("Setup is already complete, sign in instead"));
     }
+    auth.create_account(&request.username, password_hash, Role::Parent)
+        .map_err(ApiError::bad_request)?;
+
+    let username = request.username.trim().to_string();
+    let token = auth.start_session(&username);
+    Ok(signed_in(username, Role::Parent, token))
 }
 
-/// Create a blocking rule
+/// Sign in to this app
 ///
-/// Creates a new rule to block specified apps with flexible scheduling.
-/// Supports permanent blocks, duration-based blocks, time-based blocks, and recurring schedules.
+/// Checks a local account's password, sets the session cookie and returns
+/// the session token for use as a Bearer token.
 #[utoipa::path(
     post,
-    path = "/api/block",
-    tag = "rules",
-    request_body = BlockRule,
+    path = "/api/auth/login",
+    tag = "accounts",
+    request_body = AccountRequest,
     responses(
-        (status = 200, description = "Rule created successfully", body = ApiResponse),
-        (status = 400, description = "Invalid rule configuration", body = ApiResponse),
-        (status = 401, description = "Not authenticated", body = ApiResponse)
+        (status = 200, description = "Signed in", body = AuthResponse),
+        (status = 401, description = "Invalid username or password", body = ApiResponse),
+        (status = 429, description = "Too many failed sign-ins from this address, wait and retry", body = ApiResponse)
     )
 )]
-async fn create_block_rule(
+async fn auth_login(
     State(state): State<AppState>,
-    Json(rule): Json<BlockRule>,
-) -> impl IntoResponse {
-    println!("🚫 Creating block rule for apps: {:?}", rule.apps);
-
-    let unifi_url = state.unifi_url.lock().await.clone();
-    let cookies = state.session_cookies.lock().await.clone();
-
-    let (url, cookie_header) = match (unifi_url, cookies) {
-        (Some(url), Some(cookies)) => (url, cookies),
-        _ => {
-            return Json(ApiResponse {
-                success: false,
-                error: Some("Not logged in to UniFi".to_string()),
-                message: None,
-            });
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(request): Json<AccountRequest>,
+) -> Result<Response, ApiError> {
+    let ip = addr.ip().to_canonical();
+    let stored = {
+        let mut auth = state.auth.lock().await;
+        if let Err(delay) = auth.start_login(ip) {
+            return Err(ApiError::too_many_requests(format!(
+                "Too many failed sign-ins, try again in {} seconds",
+                delay.num_seconds().max(1)
+            )));
         }
+        auth.password_hash(&request.username)
     };
 
-    // Convert app names to UniFi app IDs
-    let app_ids: Vec<String> = rule.apps
-        .iter()
-        .filter_map(|app| state.app_id_map.get(app).cloned())
-        .collect();
+    // The hash is slow on purpose; keep it off the runtime and out of the
+    // lock every authenticated request takes
+    let password = request.password;
+    let valid = tokio::task::spawn_blocking(move || auth::verify_password(&password, stored.as_deref()))
+        .await
+        .map_err(|e| ApiError::internal(format!("Password check failed: {}", e)))?;
 
-    if app_ids.is_empty() {
-        return Json(ApiResponse {
-            success: false,
-            error: Some("No valid apps selected for blocking".to_string()),
-            message: None,
-        });
+    let mut auth = state.auth.lock().await;
+    auth.finish_login(ip, valid);
+    if !valid {
+        error!("❌ Failed sign-in for {} from {}", request.username, ip);
+        return Err(ApiError::unauthorized("Invalid username or password"));
     }
 
-    // For UniFi OS, use the proxy endpoint to access network firewall rules
-    let firewall_url = if url.contains("/proxy/network") {
-        // Traditional controller path
-        format!("{}/api/s/default/rest/firewallrule", url)
-    } else {
-        // UniFi OS path - use proxy to access network controller
-        format!("{}/proxy/network/api/s/default/rest/firewallrule", url)
-    };
-    
-    println!("🔥 Creating firewall rule at: {}", firewall_url);
-
-    let firewall_rule = serde_json::json!({
-        "name": format!("{} {}", RULE_NAME_PREFIX, rule.apps.join(", ")),
-        "ruleset": "WAN_IN",
-        "rule_index": 2000,
-        "action": "drop",
-        "protocol_match_excepted": false,
-        "logging": false,
-        "state_established": false,
-        "state_invalid": false,
-        "state_new": false,
-        "state_related": false,
-        "ipsec": "",
-        "src_firewallgroup_ids": [],
-        "src_mac_address": "",
-        "src_address": "",
-        "src_port": "",
-        "dst_firewallgroup_ids": [],
-        "dst_address": "",
-        "dst_port": "",
-        "icmp_typename": "",
-        "app_category_ids": app_ids,
-        "enabled": rule.status == "active"
-    });
-
-    match state.client.post(&firewall_url)
-        .header(header::COOKIE, cookie_header)
-        .json(&firewall_rule)
-        .send()
-        .await 
-    {
-        Ok(response) => {
-            println!("🔥 Firewall rule response: HTTP {}", response.status());
-            if response.status().is_success() {
-                // Parse response to get the created rule ID
-                let unifi_rule_id = if let Ok(json) = response.json::<serde_json::Value>().await {
-                    println!("📊 Firewall response data: {}", json.to_string().chars().take(200).collect::<String>());
-                    json["data"][0]["_id"].as_str().map(|s| s.to_string())
-                } else {
-                    None
-                };
+    let token = auth.start_session(&request.username);
+    let role = auth.authenticate(&token).map(|u| u.role).unwrap_or_default();
+    info!("👤 {} ({}) signed in", request.username, role);
+    Ok(signed_in(request.username, role, token))
+}
 
-                // Store rule in our state
-                let active_rule = ActiveRule {
-                    id: rule.id.clone(),
-                    apps: rule.apps.clone(),
-                    rule_type: rule.rule_type.clone(),
-                    devices: rule.devices.clone(),
-                    status: rule.status.clone(),
-                    created: rule.created.clone(),
-                    duration: rule.duration,
-                    end_time: rule.end_time.clone(),
-                    schedule_type: rule.schedule_type.clone(),
-                    unifi_rule_id,
-                };
+// Check and hash a new account's password on a blocking thread
+async fn hash_new_password(password: String) -> Result<String, ApiError> {
+    auth::validate_password(&password).map_err(ApiError::bad_request)?;
+    tokio::task::spawn_blocking(move || auth::hash_password(&password))
+        .await
+        .map_err(|e| ApiError::internal(format!("Password hashing failed: {}", e)))
+}
 
-                state.rules_db.lock().await.add_rule(active_rule).unwrap();
+fn signed_in(username: String, role: Role, token: String) -> Response {
+    (
+        [(header::SET_COOKIE, auth::session_cookie(&token))],
+        Json(AuthResponse {
+            success: true,
+            username,
+            role,
+            token,
+        }),
+    ).into_response()
+}
 
-                println!("✅ Block rule created successfully");
-                Json(ApiResponse {
-                    success: true,
-                    error: None,
-                    message: Some("Block rule created successfully".to_string()),
-                })
-            } else {
-                // Try to get the error message from response
-                let status = response.status();
-                let error_msg = if let Ok(text) = response.text().await {
-                    format!("Failed to create firewall rule: HTTP {} - {}", status, text.chars().take(200).collect::<String>())
-                } else {
-                    format!("Failed to create firewall rule: HTTP {}", status)
-                };
-                println!("❌ {}", error_msg);
-                Json(ApiResponse {
-                    success: false,
-                    error: Some(error_msg),
-                    message: None,
-                })
-            }
-        }
-        Err(e) => {
-            println!("❌ Error creating firewall rule: {}", e);
-            Json(ApiResponse {
-                success: false,
-                error: Some(format!("Error creating rule: {}", e)),
-                message: None,
-            })
-        }
+/// Sign out
+///
+/// Ends the current session and clears the session cookie.
+#[utoipa::path(
+    post,
+    path = "/api/auth/logout",
+    tag = "accounts",
+    responses(
+        (status = 200, description = "Signed out", body = ApiResponse)
+    )
+)]
+async fn auth_logout(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(token) = auth::request_token(&headers) {
+        state.auth.lock().await.end_session(&token);
     }
+    (
+        [(header::SET_COOKIE, auth::expired_session_cookie())],
+        Json(ApiResponse {
+            success: true,
+            error: None,
+            message: Some("Signed out".to_string()),
+        }),
+    ).into_response()
 }
 
-/// Remove a blocking rule
+/// Add an account
 ///
-/// Removes a specific blocking rule by ID. This will unblock the apps
-/// for the specified devices and remove the rule from the UniFi controller.
+/// Creates another local account that can sign in to this app.
+/// Requires the parent role.
 #[utoipa::path(
     post,
-    path = "/api/unblock",
-    tag = "rules",
-    request_body = UnblockRequest,
+    path = "/api/auth/users",
+    tag = "accounts",
+    request_body = AccountRequest,
     responses(
-        (status = 200, description = "Rule removed successfully", body = ApiResponse),
-        (status = 404, description = "Rule not found", body = ApiResponse),
-        (status = 401, description = "Not authenticated", body = ApiResponse)
+        (status = 200, description = "Account created", body = ApiResponse),
+        (status = 400, description = "Invalid username or password, or the name is taken", body = ApiResponse),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Requires the parent role", body = ApiResponse)
     )
 )]
-async fn unblock_rule(
+async fn create_account(
     State(state): State<AppState>,
-    Json(request): Json<UnblockRequest>,
-) -> impl IntoResponse {
-    println!("🔓 Unblocking rule: {}", request.rule_id);
-
-    let unifi_url = state.unifi_url.lock().await.clone();
-    let cookies = state.session_cookies.lock().await.clone();
+    Extension(user): Extension<CurrentUser>,
+    Json(request): Json<AccountRequest>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    user.require(Permission::ManageAccounts)?;
 
-    let (url, cookie_header) = match (unifi_url, cookies) {
-        (Some(url), Some(cookies)) => (url, cookies),
-        _ => {
-            return Json(ApiResponse {
-                success: false,
-                error: Some("Not logged in to UniFi".to_string()),
-                message: None,
-            });
-        }
-    };
-
-    // Find and remove the rule from our persistent storage
-    let mut rules_db = state.rules_db.lock().await;
-    if let Some(rule) = rules_db.remove_rule(&request.rule_id) {
-        // If we have a UniFi rule ID, delete it from the controller
-        if let Some(ref unifi_rule_id) = rule.unifi_rule_id {
-            let delete_url = if url.contains("/proxy/network") {
-                format!("{}/api/s/default/rest/firewallrule/{}", url, unifi_rule_id)
-            } else {
-                format!("{}/proxy/network/api/s/default/rest/firewallrule/{}", url, unifi_rule_id)
-            };
-            
-            match state.client.delete(&delete_url)
-                .header(header::COOKIE, cookie_header)
-                .send()
-                .await 
-            {
-                Ok(response) => {
-                    if response.status().is_success() {
-                        println!("✅ Rule unblocked successfully");
-                        Json(ApiResponse {
-                            success: true,
-                            error: None,
-                            message: Some("Rule unblocked successfully".to_string()),
-                        })
-                    } else {
-                        println!("❌ Failed to delete UniFi rule: HTTP {}", response.status());
-                        // Re-add the rule since deletion failed
-                        let _ = rules_db.add_rule(rule);
-                        Json(ApiResponse {
-                            success: false,
-                            error: Some("Failed to delete rule from UniFi".to_string()),
-                            message: None,
-                        })
-                    }
-                }
-                Err(e) => {
-                    println!("❌ Error deleting UniFi rule: {}", e);
-                    // Re-add the rule since deletion failed
-                    let _ = rules_db.add_rule(rule);
-                    Json(ApiResponse {
-                        success: false,
-                        error: Some(format!("Error deleting rule: {}", e)),
-                        message: None,
-                    })
-                }
-            }
-        } else {
-            // No UniFi rule ID, just removed from local state
-            Json(ApiResponse {
-                success: true,
-                error: None,
-                message: Some("Rule removed from local state".to_string()),
-            })
-        }
-    } else {
-        Json(ApiResponse {
-            success: false,
-            error: Some("Rule not found".to_string()),
-            message: None,
-        })
+    let role = request.role.unwrap_or(Role::ReadOnly);
+    let password_hash = hash_new_password(request.password).await?;
+    match state.auth.lock().await.create_account(&request.username, password_hash, role) {
+        Ok(_) => Ok(Json(ApiResponse {
+            success: true,
+            error: None,
+            message: Some(format!("Account {} created", request.username.trim())),
+        })),
+        Err(e) => Err(ApiError::bad_request(e)),
     }
 }
 
-/// Remove all blocking rules
+/// Change an account's role
 ///
-/// Removes all active blocking rules at once. This is useful for
-/// emergency situations where you need to quickly unblock everything.
+/// Requires the parent role. The last parent account can't be demoted.
 #[utoipa::path(
     post,
-    path = "/api/unblock-all",
-    tag = "rules",
+    path = "/api/auth/users/role",
+    tag = "accounts",
+    request_body = RoleRequest,
     responses(
-        (status = 200, description = "All rules removed successfully", body = ApiResponse),
-        (status = 401, description = "Not authenticated", body = ApiResponse)
+        (status = 200, description = "Role changed", body = ApiResponse),
+        (status = 400, description = "Unknown account, or the last parent would be demoted", body = ApiResponse),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Requires the parent role", body = ApiResponse)
     )
 )]
-async fn unblock_all_rules(State(state): State<AppState>) -> impl IntoResponse {
-    println!("🔓 Unblocking all rules");
-
-    let unifi_url = state.unifi_url.lock().await.clone();
-    let cookies = state.session_cookies.lock().await.clone();
-
-    let (url, cookie_header) = match (unifi_url, cookies) {
-        (Some(url), Some(cookies)) => (url, cookies),
-        _ => {
-            return Json(ApiResponse {
-                success: false,
-                error: Some("Not logged in to UniFi".to_string()),
-                message: None,
-            });
-        }
-    };
-
-    let mut rules_db = state.rules_db.lock().await;
-    let mut failed_deletions = Vec::new();
-
-    // Delete all UniFi rules
-    for rule in rules_db.get_rules().iter() {
-        if let Some(unifi_rule_id) = &rule.unifi_rule_id {
-            let delete_url = if url.contains("/proxy/network") {
-                format!("{}/api/s/default/rest/firewallrule/{}", url, unifi_rule_id)
-            } else {
-                format!("{}/proxy/network/api/s/default/rest/firewallrule/{}", url, unifi_rule_id)
-            };
-            
-            if let Err(e) = state.client.delete(&delete_url)
-                .header(header::COOKIE, &cookie_header)
-                .send()
-                .await 
-            {
-                failed_deletions.push(format!("Rule {}: {}", rule.id, e));
-            }
-        }
-    }
-
-    // Clear all rules from persistent storage
-    let _ = rules_db.clear_all();
+async fn set_account_role(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    Json(request): Json<RoleRequest>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    user.require(Permission::ManageAccounts)?;
 
-    if failed_deletions.is_empty() {
-        Json(ApiResponse {
+    match state.auth.lock().await.set_role(&request.username, request.role) {
+        Ok(_) => Ok(Json(ApiResponse {
             success: true,
             error: None,
-            message: Some("All rules unblocked successfully".to_string()),
-        })
-    } else {
-        Json(ApiResponse {
-            success: false,
-            error: Some(format!("Some rules failed to delete: {}", failed_deletions.join(", "))),
-            message: None,
-        })
+            message: Some(format!("{} is now {}", request.username, request.role)),
+        })),
+        Err(e) => Err(ApiError::bad_request(e)),
     }
 }
 
-/// Get all active rules
+/// Issue an API token
 ///
-/// Returns a list of all currently active blocking rules.
-/// This includes rule details, schedules, and target devices.
+/// Creates a named long-lived token for the signed-in account. The token is
+/// only shown once; send it as `Authorization: Bearer <token>`.
 #[utoipa::path(
-    get,
-    path = "/api/rules",
-    tag = "rules",
+    post,
+    path = "/api/auth/tokens",
+    tag = "accounts",
+    request_body = TokenRequest,
     responses(
-        (status = 200, description = "Rules retrieved successfully", body = RulesResponse)
+        (status = 200, description = "Token created", body = AuthResponse),
+        (status = 401, description = "Not signed in", body = ApiResponse)
     )
 )]
-async fn get_rules(State(state): State<AppState>) -> impl IntoResponse {
-    let rules_db = state.rules_db.lock().await;
-    let rules = rules_db.get_rules().clone();
-    
-    Json(RulesResponse {
+async fn create_api_token(
+    State(state): State<AppState>,
+    Extension(CurrentUser { username, role }): Extension<CurrentUser>,
+    Json(request): Json<TokenRequest>,
+) -> Result<Json<AuthResponse>, ApiError> {
+    let token = state.auth.lock().await
+        .create_api_token(&username, &request.name)
+        .map_err(ApiError::bad_request)?;
+    Ok(Json(AuthResponse {
         success: true,
-        rules,
-    })
+        username,
+        role,
+        token,
+    }))
 }
 
-/// Sync rules with UniFi controller
+/// Revoke an API token
+///
+/// Deletes one of the signed-in account's API tokens by name.
+#[utoipa::path(
+    post,
+    path = "/api/auth/tokens/revoke",
+    tag = "accounts",
+    request_body = TokenRequest,
+    responses(
+        (status = 200, description = "Token revoked", body = ApiResponse),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 404, description = "Token not found", body = ApiResponse)
+    )
+)]
+async fn revoke_api_token(
+    State(state): State<AppState>,
+    Extension(CurrentUser { username, .. }): Extension<CurrentUser>,
+    Json(request): Json<TokenRequest>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    match state.auth.lock().await.revoke_api_token(&username, &request.name) {
+        Ok(_) => Ok(Json(ApiResponse {
+            success: true,
+            error: None,
+            message: Some(format!("Token {} revoked", request.name)),
+        })),
+        Err(e) => Err(ApiError::not_found(e)),
+    }
+}
+
+/// Authenticate with UniFi controller
+///
+/// Connects to your UniFi controller using local admin credentials or an API key.
+/// Cloud accounts are not recommended due to MFA requirements.
+#[utoipa::path(
+    post,
+    path = "/api/login",
+    tag = "authentication",
+    request_body = LoginRequest,
+    responses(
+        (status = 200, description = "Login successful", body = ApiResponse),
+        (status = 400, description = "Invalid request format", body = ApiResponse),
+        (status = 401, description = "Not signed in, or the controller rejected the credentials", body = ApiResponse),
+        (status = 403, description = "Requires the parent role", body = ApiResponse),
+        (status = 502, description = "The controller could not be reached", body = ApiResponse)
+    )
+)]
+async fn login_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    Json(request): Json<LoginRequest>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    user.require(Permission::ConnectController)?;
+
+    let url = match request.url.trim() {
+        "" => state.config.unifi_url.clone().unwrap_or_default(),
+        url => url.to_string(),
+    };
+    debug!("🔐 Login attempt to: {}", url);
+    
+    // Validate URL format
+    if !url.starts_with("https://") && !url.starts_with("http://") {
+        return Err(ApiError::bad_request("Invalid URL format. Must start with https:// or http://"));
+    }
+
+    let api_key = request.api_key.filter(|k| !k.trim().is_empty());
+    if api_key.is_none() && (request.username.is_empty() || request.password.is_empty()) {
+        return Err(ApiError::bad_request("Provide either an API key or a username and password"));
+    }
+
+    let credentials = StoredCredentials {
+        url,
+        username: request.username,
+        password: request.password,
+        api_key: api_key.map(|k| k.trim().to_string()),
+    };
+
+    match open_session(state.client.clone(), &credentials).await {
+        Ok(unifi) => {
+            let site_count = state.connect(unifi).await;
+
+            // Remember the login so background work keeps running after a restart
+            if let Some(ref store) = state.credentials {
+                if let Err(e) = store.save(&credentials) {
+                    warn!("⚠️ {}", e);
+                }
+            }
+
+            Ok(Json(ApiResponse {
+                success: true,
+                error: None,
+                message: Some(format!("Connected successfully to UniFi OS ({} site{})",
+                    site_count, if site_count == 1 { "" } else { "s" })),
+            }))
+        }
+        Err(UnifiError::LoginFailed(status)) => {
+            error!("❌ Login failed: HTTP {}", status);
+            Err(ApiError::unauthorized(if credentials.api_key.is_some() {
+                    format!("API key rejected: {}. Check the key is still valid in UniFi Network.", status)
+                } else {
+                    format!("Authentication failed: {}. Try using the UniFi OS auth endpoint.", status)
+                }))
+        }
+        Err(e) => {
+            error!("❌ Connection error: {}", e);
+            Err(ApiError::bad_gateway(format!("{}. Verify the UniFi controller is accessible.", e)))
+        }
+    }
+}
+
+/// List UniFi sites
+///
+/// Returns the sites discovered on the controller at login.
+/// Use a site's name as the `site` parameter on device and rule endpoints.
+#[utoipa::path(
+    get,
+    path = "/api/sites",
+    tag = "sites",
+    responses(
+        (status = 200, description = "Sites retrieved successfully", body = SitesResponse),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 409, description = "Not connected to a controller", body = ApiResponse)
+    )
+)]
+async fn get_sites(State(state): State<AppState>) -> Result<Json<SitesResponse>, ApiError> {
+    state.unifi().await?;
+
+    let mut sites: Vec<SiteInfo> = state.sites.lock().await
+        .iter()
+        .map(|s| SiteInfo {
+            name: s.name.clone(),
+            description: s.desc.clone(),
+        })
+        .collect();
+    if sites.is_empty() {
+        // Discovery failed or isn't supported, so only the default site is known
+        sites.push(SiteInfo {
+            name: state.config.default_site.clone(),
+            description: "Default".to_string(),
+        });
+    }
+
+    Ok(Json(SitesResponse {
+        success: true,
+        sites,
+    }))
+}
+
+/// Get network devices
+///
+/// Discovers all devices connected to the UniFi network.
+/// Requires authentication first.
+#[utoipa::path(
+    get,
+    path = "/api/devices",
+    tag = "devices",
+    params(SiteQuery),
+    responses(
+        (status = 200, description = "Devices retrieved successfully", body = DevicesResponse),
+        (status = 400, description = "Unknown site", body = ApiResponse),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 409, description = "Not connected to a controller", body = ApiResponse),
+        (status = 502, description = "The controller failed to list devices", body = ApiResponse)
+    )
+)]
+async fn get_devices(
+    State(state): State<AppState>,
+    Query(query): Query<SiteQuery>,
+) -> Result<Json<DevicesResponse>, ApiError> {
+    let unifi = state.unifi().await?;
+    let site = state.resolve_site(query.site.as_deref()).await.map_err(ApiError::bad_request)?;
+
+    debug!("🔍 Discovering devices at: {} (site {})", unifi.base_url(), site);
+
+    match unifi.list_clients(&site).await {
+        Ok(clients) => {
+            let profiles = state.rules_db.lock().await.profiles.clone();
+            let devices: Vec<DeviceInfo> = clients
+                .iter()
+                .filter(|c| !c.mac.is_empty()) // Only include devices with MAC addresses
+                .map(|c| DeviceInfo {
+                    mac: c.mac.clone(),
+                    name: c.friendly_name(),
+                    device_type: c.vendor(),
+                    profile: profiles.iter()
+                        .find(|p| p.devices.iter().any(|d| d.eq_ignore_ascii_case(&c.mac)))
+                        .map(|p| p.id.clone()),
+                })
+                .collect();
+
+            debug!("✅ Found {} devices", devices.len());
+            Ok(Json(DevicesResponse {
+                success: true,
+                devices,
+            }))
+        }
+        Err(e) => {
+            error!("❌ Device discovery error: {}", e);
+            Err(e.into())
+        }
+    }
+}
+
+/// Search the app catalog
+///
+/// Lists the apps that can be blocked, from the controller's DPI database
+/// once connected, plus the apps, aliases and bundles defined in apps.toml.
+/// Use an app's `name`, one of its aliases or a bundle name in block rules.
+#[utoipa::path(
+    get,
+    path = "/api/apps",
+    tag = "apps",
+    params(AppsQuery),
+    responses(
+        (status = 200, description = "Apps retrieved successfully", body = AppsResponse)
+    )
+)]
+async fn get_apps(
+    State(state): State<AppState>,
+    Query(query): Query<AppsQuery>,
+) -> impl IntoResponse {
+    let catalog = state.apps.lock().await;
+    Json(AppsResponse {
+        success: true,
+        source: catalog.source.clone(),
+        apps: catalog.search(query.q.as_deref(), query.category.as_deref()),
+        bundles: catalog.bundles().to_vec(),
+    })
+}
+
+/// Reload the app catalog
+///
+/// Re-reads apps.toml and fetches the DPI application list from the
+/// controller again.
+#[utoipa::path(
+    post,
+    path = "/api/apps/refresh",
+    tag = "apps",
+    responses(
+        (status = 200, description = "Catalog reloaded", body = ApiResponse),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Role may not refresh the catalog", body = ApiResponse),
+        (status = 409, description = "Not connected to a controller", body = ApiResponse),
+        (status = 500, description = "apps.toml is invalid", body = ApiResponse),
+        (status = 502, description = "The controller failed to return its DPI database", body = ApiResponse)
+    )
+)]
+async fn refresh_apps(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    user.require(Permission::Sync)?;
+
+    if let Err(e) = state.apps.lock().await.reload_overlay() {
+        return Err(ApiError::internal(e));
+    }
+
+    let unifi = state.unifi().await?;
+
+    let site = state.default_site().await;
+    match state.apps.lock().await.refresh(&unifi, &site).await {
+        Ok(count) => Ok(Json(ApiResponse {
+            success: true,
+            error: None,
+            message: Some(format!("Loaded {} apps from the controller", count)),
+        })),
+        Err(e) => Err(ApiError::bad_gateway(format!("Failed to load the app catalog: {}", e))),
+    }
+}
+
+/// List child profiles
+///
+/// Returns every profile with the devices it groups.
+#[utoipa::path(
+    get,
+    path = "/api/profiles",
+    tag = "profiles",
+    responses(
+        (status = 200, description = "Profiles retrieved successfully", body = ProfilesResponse)
+    )
+)]
+async fn get_profiles(State(state): State<AppState>) -> impl IntoResponse {
+    Json(ProfilesResponse {
+        success: true,
+        profiles: state.rules_db.lock().await.profiles.clone(),
+    })
+}
+
+/// Create a child profile
+///
+/// Groups devices under a named child so rules can target the child.
+/// The profile ID is derived from the name.
+#[utoipa::path(
+    post,
+    path = "/api/profiles",
+    tag = "profiles",
+    request_body = ProfileRequest,
+    responses(
+        (status = 200, description = "Profile created", body = ApiResponse),
+        (status = 400, description = "Invalid profile", body = ApiResponse),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Role may not manage profiles", body = ApiResponse),
+        (status = 409, description = "A profile with this ID already exists", body = ApiResponse)
+    )
+)]
+async fn create_profile(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    Json(request): Json<ProfileRequest>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    user.require(Permission::ManageProfiles)?;
+
+    let id = profiles::profile_id(&request.name);
+    if id.is_empty() {
+        return Err(ApiError::bad_request("Profile name is required"));
+    }
+    let devices = profiles::normalize_macs(&request.devices).map_err(ApiError::bad_request)?;
+
+    let profile = Profile {
+        id: id.clone(),
+        name: request.name.trim().to_string(),
+        avatar: request.avatar.filter(|a| !a.trim().is_empty()),
+        devices,
+    };
+    match state.rules_db.lock().await.add_profile(profile) {
+        Ok(_) => {
+            info!("👧 {} created profile {}", user, id);
+            Ok(Json(ApiResponse {
+                success: true,
+                error: None,
+                message: Some(format!("Profile {} created", id)),
+            }))
+        }
+        Err(e) => Err(ApiError::conflict(e)),
+    }
+}
+
+/// Update a child profile
+///
+/// Changes a profile's name, avatar or devices. Rules targeting the
+/// profile are moved to the new device list on the controller, so a new
+/// phone is covered by every existing block right away. If some rules can't
+/// be moved, the profile keeps its old devices and the update can be retried.
+#[utoipa::path(
+    post,
+    path = "/api/profiles/update",
+    tag = "profiles",
+    request_body = ProfileRequest,
+    responses(
+        (status = 200, description = "Profile updated", body = ApiResponse),
+        (status = 400, description = "Invalid profile", body = ApiResponse),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Role may not manage profiles", body = ApiResponse),
+        (status = 404, description = "Profile not found", body = ApiResponse),
+        (status = 409, description = "Rules would be left without devices", body = ApiResponse),
+        (status = 502, description = "Rules could not be updated on the controller; devices left unchanged", body = ApiResponse)
+    )
+)]
+async fn update_profile(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    Json(request): Json<ProfileRequest>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    user.require(Permission::ManageProfiles)?;
+
+    let profile_id = request.id.unwrap_or_default();
+    let devices = profiles::normalize_macs(&request.devices).map_err(ApiError::bad_request)?;
+    if request.name.trim().is_empty() {
+        return Err(ApiError::bad_request("Profile name is required"));
+    }
+
+    // The controller is only called once the lock is released, so a slow one
+    // doesn't hold up the scheduler and other requests
+    let (existing, stale_rules) = {
+        let rules_db = state.rules_db.lock().await;
+        let existing = rules_db.get_profile(&profile_id)
+            .cloned()
+            .ok_or_else(|| ApiError::not_found("Profile not found"))?;
+        let profile_rules: Vec<ActiveRule> = rules_db.rules
+            .iter()
+            .filter(|r| r.profile.as_deref() == Some(profile_id.as_str()))
+            .cloned()
+            .collect();
+        // A rule with no devices would block the whole network
+        if devices.is_empty() && !profile_rules.is_empty() {
+            return Err(ApiError::conflict(format!("{} still has rules, so it needs at least one device", existing.name)));
+        }
+        // Rules are compared one by one, so one left behind by an earlier
+        // failed update is retried even if the device list is the same
+        let stale_rules: Vec<ActiveRule> = profile_rules.into_iter().filter(|r| r.devices != devices).collect();
+        (existing, stale_rules)
+    };
+
+    let mut retargeted = Vec::new();
+    let mut failures = Vec::new();
+    if !stale_rules.is_empty() {
+        let unifi = state.unifi().await?;
+        for rule in stale_rules {
+            let mut block = rule.controller_objects();
+            let enabled = rule.unifi_enabled.unwrap_or(true);
+            match rule.strategy.retarget(&unifi, &rule.site, &mut block, &devices, enabled).await {
+                Ok(()) => retargeted.push((rule, block.unifi_paused_macs)),
+                Err(e) => {
+                    state.audit(AuditEntry::new(&user, AuditAction::Update).rule(&rule.id, &rule).failed(&e)).await;
+                    failures.push(format!("Rule {}: {}", rule.id, e));
+                }
+            }
+        }
+    }
+
+    let mut rules_db = state.rules_db.lock().await;
+    for (rule, paused_macs) in retargeted {
+        let mut entry = AuditEntry::new(&user, AuditAction::Update).previous(&rule)
+            .message(format!("Devices of profile {} changed", profile_id));
+        if let Some(stored) = rules_db.rules.iter_mut().find(|r| r.id == rule.id) {
+            stored.devices = devices.clone();
+            stored.unifi_paused_macs = paused_macs;
+            entry = entry.rule(&rule.id, &*stored);
+        }
+        state.audit(entry).await;
+        info!("🎯 Rule {} now targets {} devices of {}", rule.id, devices.len(), profile_id);
+    }
+    if let Some(profile) = rules_db.profiles.iter_mut().find(|p| p.id == profile_id) {
+        profile.name = request.name.trim().to_string();
+        profile.avatar = request.avatar.filter(|a| !a.trim().is_empty());
+        // Keep the old devices until every rule follows the new ones, so
+        // the profile never claims devices its rules don't cover
+        if failures.is_empty() {
+            profile.devices = devices;
+        }
+    }
+    if let Err(e) = rules_db.save() {
+        return Err(ApiError::internal(e));
+    }
+    info!("👧 {} updated profile {}", user, existing.id);
+
+    if !failures.is_empty() {
+        return Err(ApiError::bad_gateway(format!(
+            "Devices not changed because some rules could not be updated, try again: {}",
+            failures.join(", ")
+        )));
+    }
+    Ok(Json(ApiResponse {
+        success: true,
+        error: None,
+        message: Some("Profile updated".to_string()),
+    }))
+}
+
+/// Delete a child profile
+///
+/// Only profiles without rules can be deleted.
+#[utoipa::path(
+    post,
+    path = "/api/profiles/delete",
+    tag = "profiles",
+    request_body = ProfileIdRequest,
+    responses(
+        (status = 200, description = "Profile deleted", body = ApiResponse),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Role may not manage profiles", body = ApiResponse),
+        (status = 404, description = "Profile not found", body = ApiResponse),
+        (status = 409, description = "Rules still target the profile", body = ApiResponse)
+    )
+)]
+async fn delete_profile(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    Json(request): Json<ProfileIdRequest>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    user.require(Permission::ManageProfiles)?;
+
+    let mut rules_db = state.rules_db.lock().await;
+    if rules_db.get_profile(&request.id).is_none() {
+        return Err(ApiError::not_found("Profile not found"));
+    }
+    match rules_db.remove_profile(&request.id) {
+        Ok(profile) => {
+            info!("👧 {} deleted profile {}", user, profile.id);
+            Ok(Json(ApiResponse {
+                success: true,
+                error: None,
+                message: Some(format!("Profile {} deleted", profile.name)),
+            }))
+        }
+        // The profile still has rules
+        Err(e) => Err(ApiError::conflict(e)),
+    }
+}
+
+/// Create a blocking rule
+///
+/// Creates a new rule to block specified apps and/or domains with flexible scheduling.
+/// Supports permanent blocks, duration-based blocks, time-based blocks, and recurring schedules.
+/// Deprecated in favour of `POST /api/v1/rules`, which returns the created rule.
+#[utoipa::path(
+    post,
+    path = "/api/block",
+    tag = "rules",
+    request_body = BlockRule,
+    responses(
+        (status = 200, description = "Rule created successfully", body = ApiResponse),
+        (status = 400, description = "Invalid rule configuration", body = ApiResponse),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Role may not create rules", body = ApiResponse),
+        (status = 409, description = "Rule ID already in use, or not connected to a controller", body = ApiResponse),
+        (status = 502, description = "The controller rejected the rule", body = ApiResponse)
+    )
+)]
+#[deprecated(note = "use POST /api/v1/rules")]
+async fn create_block_rule(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    Json(rule): Json<BlockRule>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    add_block_rule(&state, &user, rule).await?;
+    Ok(Json(ApiResponse {
+        success: true,
+        error: None,
+        message: Some("Block rule created successfully".to_string()),
+    }))
+}
+
+// Validate a new rule, create it on the controller and store it
+async fn add_block_rule(state: &AppState, user: &CurrentUser, mut rule: BlockRule) -> Result<ActiveRule, ApiError> {
+    info!("🚫 {} creating block rule for apps: {:?}", user, rule.apps);
+
+    user.require(Permission::CreateRule)?;
+
+    let unifi = state.unifi().await?;
+
+    if rule.id.trim().is_empty() {
+        rule.id = new_id();
+    }
+    if rule.created.trim().is_empty() {
+        rule.created = chrono::Utc::now().to_rfc3339();
+    }
+    if rule.status.trim().is_empty() {
+        rule.status = "active".to_string();
+    }
+    if state.rules_db.lock().await.get_rules().iter().any(|r| r.id == rule.id) {
+        return Err(ApiError::conflict(format!("Rule {} already exists", rule.id)));
+    }
+
+    let site = state.resolve_site(rule.site.as_deref()).await.map_err(ApiError::bad_request)?;
+
+    // Timed rules need something to count down from
+    let timing_error = match rule.rule_type.as_str() {
+        "duration" if rule.duration.unwrap_or(0) == 0 => {
+            Some("Duration rules require a duration in hours")
+        }
+        "duration" if chrono::DateTime::parse_from_rfc3339(&rule.created).is_err() => {
+            Some("Duration rules require a valid ISO 8601 created timestamp")
+        }
+        "until" if rule.end_time.as_deref()
+            .map(|t| chrono::DateTime::parse_from_rfc3339(t).is_err())
+            .unwrap_or(true) => {
+            Some("Until rules require a valid ISO 8601 endTime")
+        }
+        _ => None,
+    };
+    if let Some(error) = timing_error {
+        return Err(ApiError::bad_request(error.to_string()));
+    }
+
+    // Recurring rules get an explicit window, either custom or from a preset
+    let schedule = if rule.rule_type == "schedule" {
+        let schedule = match (&rule.schedule, &rule.schedule_type) {
+            (Some(schedule), _) => Some(schedule.clone()),
+            (None, Some(schedule_type)) => {
+                let timezone = rule.timezone.clone().unwrap_or_else(schedule::default_timezone);
+                Schedule::preset(schedule_type, &timezone)
+            }
+            (None, None) => None,
+        };
+        match schedule.map(|s| s.validate().map(|_| s)) {
+            Some(Ok(schedule)) => Some(schedule),
+            Some(Err(e)) => {
+                return Err(ApiError::bad_request(format!("Invalid schedule: {}", e)));
+            }
+            None => {
+                return Err(ApiError::bad_request("Scheduled rules require a schedule or a known scheduleType (bedtime, homework)"));
+            }
+        }
+    } else {
+        None
+    };
+
+    if rule.pause && !(rule.apps.is_empty() && rule.domains.is_empty()) {
+        return Err(ApiError::bad_request("A pause blocks all internet access, so it can't also list apps or domains"));
+    }
+    if !rule.pause && rule.apps.is_empty() && rule.domains.is_empty() {
+        return Err(ApiError::bad_request("No apps or domains selected for blocking"));
+    }
+
+    let domains = blocking::normalize_domains(&rule.domains).map_err(ApiError::bad_request)?;
+
+    // Convert app names to UniFi app IDs, refusing names the catalog doesn't know
+    let app_ids = match state.apps.lock().await.resolve(&rule.apps) {
+        Ok(app_ids) => app_ids,
+        Err(unknown) => {
+            return Err(ApiError::bad_request(format!("Unknown apps: {}. Search /api/apps for the right name.", unknown.join(", "))));
+        }
+    };
+
+    // Profile rules take the profile's devices, and follow them as they change
+    let device_macs: Vec<String> = if let Some(ref profile_id) = rule.profile {
+        match state.rules_db.lock().await.get_profile(profile_id) {
+            Some(profile) if !profile.devices.is_empty() => profile.devices.clone(),
+            Some(profile) => {
+                return Err(ApiError::bad_request(format!("Profile {} has no devices", profile.name)));
+            }
+            None => {
+                return Err(ApiError::bad_request(format!("Unknown profile: {}", profile_id)));
+            }
+        }
+    } else if targets_all_devices(&rule.devices) {
+        vec![]
+    } else {
+        rule.devices.iter().map(|d| d.trim().to_lowercase()).collect()
+    };
+
+    // Scheduled rules outside their window start out disabled
+    let enabled = rule.status == "active"
+        && schedule.as_ref().map(|s| s.is_active_at(chrono::Utc::now())).unwrap_or(true);
+
+    let strategy = rule.strategy.unwrap_or(state.block_strategy);
+    if !domains.is_empty() && strategy == BlockStrategy::FirewallRules {
+        return Err(ApiError::bad_request("Blocking domains needs traffic rules (UniFi Network 7 and later)"));
+    }
+    if rule.pause && device_macs.is_empty() {
+        return Err(ApiError::bad_request("Pausing needs specific devices or a profile, not the whole network"));
+    }
+
+    let plan = BlockPlan {
+        rule_id: &rule.id,
+        apps: &rule.apps,
+        app_ids: &app_ids,
+        domains: &domains,
+        device_macs: &device_macs,
+        pause: rule.pause,
+        enabled,
+    };
+
+    let created = match strategy.create(&unifi, &site, &plan).await {
+        Ok(created) => created,
+        Err(error_msg) => {
+            error!("❌ {}", error_msg);
+            state.audit(AuditEntry::new(user, AuditAction::Create).rule(&rule.id, &rule).failed(&error_msg)).await;
+            return Err(ApiError::bad_gateway(error_msg));
+        }
+    };
+
+    // Store rule in our state
+    let active_rule = ActiveRule {
+        id: rule.id.clone(),
+        apps: rule.apps.clone(),
+        domains,
+        pause: rule.pause,
+        rule_type: rule.rule_type.clone(),
+        devices: if device_macs.is_empty() { rule.devices.clone() } else { device_macs },
+        site,
+        status: rule.status.clone(),
+        created: rule.created.clone(),
+        duration: rule.duration,
+        end_time: rule.end_time.clone(),
+        schedule_type: rule.schedule_type.clone(),
+        schedule,
+        strategy,
+        profile: rule.profile.clone(),
+        unifi_rule_id: created.unifi_rule_id,
+        unifi_group_id: created.unifi_group_id,
+        unifi_domain_rule_id: created.unifi_domain_rule_id,
+        unifi_paused_macs: created.unifi_paused_macs,
+        unifi_enabled: Some(enabled),
+    };
+
+    state.rules_db.lock().await.add_rule(active_rule.clone()).map_err(ApiError::internal)?;
+    state.audit(AuditEntry::new(user, AuditAction::Create).rule(&active_rule.id, &active_rule)).await;
+
+    info!("✅ Block rule created successfully");
+    Ok(active_rule)
+}
+
+/// Remove a blocking rule
+///
+/// Removes a specific blocking rule by ID. This will unblock the apps
+/// for the specified devices and remove the rule from the UniFi controller.
+/// Deprecated in favour of `DELETE /api/v1/rules/{id}`.
+#[utoipa::path(
+    post,
+    path = "/api/unblock",
+    tag = "rules",
+    request_body = UnblockRequest,
+    responses(
+        (status = 200, description = "Rule removed successfully", body = ApiResponse),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Role may not remove this rule", body = ApiResponse),
+        (status = 404, description = "Rule not found", body = ApiResponse),
+        (status = 409, description = "Not connected to a controller", body = ApiResponse),
+        (status = 502, description = "The controller failed to remove the rule", body = ApiResponse)
+    )
+)]
+#[deprecated(note = "use DELETE /api/v1/rules/{id}")]
+async fn unblock_rule(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    Json(request): Json<UnblockRequest>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    let message = remove_block_rule(&state, &user, &request.rule_id).await?;
+    Ok(Json(ApiResponse {
+        success: true,
+        error: None,
+        message: Some(message.to_string()),
+    }))
+}
+
+// Remove a rule from the controller and then from our storage
+async fn remove_block_rule(state: &AppState, user: &CurrentUser, rule_id: &str) -> Result<&'static str, ApiError> {
+    info!("🔓 {} unblocking rule: {}", user, rule_id);
+
+    let rule = state.rules_db.lock().await.get_rules()
+        .iter()
+        .find(|r| r.id == rule_id)
+        .cloned()
+        .ok_or_else(|| ApiError::not_found("Rule not found"))?;
+    user.require(rule.removal_permission())?;
+
+    if !rule.on_controller() {
+        // Nothing on the controller, just remove it from local state
+        state.rules_db.lock().await.remove_rule(rule_id).map_err(ApiError::internal)?;
+        state.audit(AuditEntry::new(user, AuditAction::Unblock).rule(rule_id, &rule).message("Not on the controller, removed locally")).await;
+        return Ok("Rule removed from local state");
+    }
+
+    // Delete the controller rules and device group before forgetting them
+    if let Err(e) = state.remove_from_unifi(&rule).await {
+        error!("❌ Failed to delete UniFi rule: {}", e);
+        let error: ApiError = match e {
+            UnifiError::NotAuthenticated => e.into(),
+            e => ApiError::bad_gateway(format!("Failed to delete rule from UniFi: {}", e)),
+        };
+        state.audit(AuditEntry::new(user, AuditAction::Unblock).rule(rule_id, &rule).failed(&error.message)).await;
+        return Err(error);
+    }
+    if let Err(e) = state.rules_db.lock().await.remove_rule(rule_id) {
+        error!("❌ Rule {} was deleted from UniFi but its removal wasn't saved: {}", rule_id, e);
+        state.audit(AuditEntry::new(user, AuditAction::Unblock).rule(rule_id, &rule).failed(&e)).await;
+        return Err(ApiError::internal(e));
+    }
+    state.audit(AuditEntry::new(user, AuditAction::Unblock).rule(rule_id, &rule)).await;
+    info!("✅ Rule unblocked successfully");
+    Ok("Rule unblocked successfully")
+}
+
+/// Remove all blocking rules
+///
+/// Removes all active blocking rules at once. This is useful for
+/// emergency situations where you need to quickly unblock everything.
+#[utoipa::path(
+    post,
+    path = "/api/unblock-all",
+    tag = "rules",
+    responses(
+        (status = 200, description = "All rules removed successfully", body = ApiResponse),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Requires the parent role", body = ApiResponse),
+        (status = 409, description = "Not connected to a controller", body = ApiResponse),
+        (status = 502, description = "Some rules could not be removed from the controller", body = ApiResponse)
+    )
+)]
+async fn unblock_all_rules(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    info!("🔓 {} unblocking all rules", user);
+
+    user.require(Permission::UnblockAll)?;
+
+    state.unifi().await?;
+
+    // The controller is called without holding the lock, so only the rules
+    // seen here are removed; one created meanwhile stays
+    let removed = state.rules_db.lock().await.get_rules().clone();
+    let mut failed_deletions = Vec::new();
+
+    // Delete all UniFi rules and their device groups
+    for rule in removed.iter() {
+        if let Err(e) = state.remove_from_unifi(rule).await {
+            failed_deletions.push(format!("Rule {}: {}", rule.id, e));
+        }
+    }
+
+    // Clear the rules from persistent storage
+    let removed_ids: Vec<String> = removed.iter().map(|r| r.id.clone()).collect();
+    let entry = AuditEntry::new(&user, AuditAction::UnblockAll).snapshot(&removed);
+    if let Err(e) = state.rules_db.lock().await.remove_rules(&removed_ids) {
+        error!("❌ Failed to save the removal of all rules: {}", e);
+        state.audit(entry.failed(format!("Rules not saved as removed: {}", e))).await;
+        return Err(ApiError::internal(e));
+    }
+
+    state.audit(if failed_deletions.is_empty() {
+        entry.message(format!("Removed {} rules", removed.len()))
+    } else {
+        entry.failed(format!("Some rules failed to delete: {}", failed_deletions.join(", ")))
+    }).await;
+
+    if failed_deletions.is_empty() {
+        Ok(Json(ApiResponse {
+            success: true,
+            error: None,
+            message: Some("All rules unblocked successfully".to_string()),
+        }))
+    } else {
+        Err(ApiError::bad_gateway(format!("Some rules failed to delete: {}", failed_deletions.join(", "))))
+    }
+}
+
+/// Get all active rules
+///
+/// Returns a list of all currently active blocking rules.
+/// This includes rule details, schedules, and target devices.
+#[utoipa::path(
+    get,
+    path = "/api/rules",
+    tag = "rules",
+    params(SiteQuery),
+    responses(
+        (status = 200, description = "Rules retrieved successfully", body = RulesResponse)
+    )
+)]
+async fn get_rules(
+    State(state): State<AppState>,
+    Query(query): Query<SiteQuery>,
+) -> impl IntoResponse {
+    let rules_db = state.rules_db.lock().await;
+    let rules = rules_db.get_rules()
+        .iter()
+        .filter(|r| query.site.as_ref().map(|site| &r.site == site).unwrap_or(true))
+        .cloned()
+        .collect();
+    
+    Json(RulesResponse {
+        success: true,
+        rules,
+    })
+}
+
+/// Sync rules with UniFi controller
 ///
 /// Synchronizes local rule database with UniFi controller firewall rules.
 /// This ensures consistency between our tool and the UniFi controller.
+/// Without a `site` parameter every site that has rules is synchronized.
+#[utoipa::path(
+    post,
+    path = "/api/sync",
+    tag = "rules",
+    params(SiteQuery),
+    responses(
+        (status = 200, description = "Rules synchronized successfully", body = ApiResponse),
+        (status = 400, description = "Unknown site", body = ApiResponse),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Role may not synchronize rules", body = ApiResponse),
+        (status = 409, description = "Not connected to a controller", body = ApiResponse),
+        (status = 502, description = "The controller failed to list rules", body = ApiResponse)
+    )
+)]
+async fn sync_rules(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    Query(query): Query<SiteQuery>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    info!("🔄 Manual rule synchronization requested by {}", user);
+
+    user.require(Permission::Sync)?;
+    state.unifi().await?;
+
+    let sites = match query.site {
+        Some(ref site) => vec![state.resolve_site(Some(site)).await.map_err(ApiError::bad_request)?],
+        None => {
+            let mut sites: Vec<String> = state.rules_db.lock().await.rules.iter().map(|r| r.site.clone()).collect();
+            sites.sort();
+            sites.dedup();
+            sites
+        }
+    };
+
+    for site in sites.iter() {
+        if let Err(e) = state.sync_rules_with_unifi(site).await {
+            let error = ApiError::new(e.status, format!("Site {}: {}", site, e.message));
+            state.audit(AuditEntry::new(&user, AuditAction::Sync).failed(&error.message)).await;
+            return Err(error);
+        }
+    }
+    state.audit(AuditEntry::new(&user, AuditAction::Sync).message(format!("Synchronized sites: {}", sites.join(", ")))).await;
+
+    Ok(Json(ApiResponse {
+        success: true,
+        error: None,
+        message: Some("Rules synchronized successfully".to_string()),
+    }))
+}
+
+/// Clean orphaned rules
+///
+/// Removes UniFi firewall rules created by this tool but no longer tracked in our database,
+/// and unpauses devices it blocked for pauses that are gone.
+/// This helps maintain a clean UniFi configuration.
+/// Without a `site` parameter every known site is cleaned.
+#[utoipa::path(
+    post,
+    path = "/api/cleanup",
+    tag = "rules",
+    params(SiteQuery),
+    responses(
+        (status = 200, description = "Orphaned rules cleaned successfully", body = ApiResponse),
+        (status = 400, description = "Unknown site", body = ApiResponse),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Requires the parent role", body = ApiResponse),
+        (status = 409, description = "Not connected to a controller", body = ApiResponse),
+        (status = 502, description = "The controller failed to remove orphaned rules", body = ApiResponse)
+    )
+)]
+async fn cleanup_rules(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    Query(query): Query<SiteQuery>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    info!("🧹 Manual rule cleanup requested by {}", user);
+
+    user.require(Permission::Cleanup)?;
+    state.unifi().await?;
+
+    let sites = match query.site {
+        Some(ref site) => vec![state.resolve_site(Some(site)).await.map_err(ApiError::bad_request)?],
+        None => state.known_sites().await,
+    };
+
+    let mut count = 0;
+    for site in sites.iter() {
+        match state.cleanup_orphaned_rules(site).await {
+            Ok(cleaned) => count += cleaned,
+            Err(e) => {
+                let error = format!("Site {}: {}", site, e);
+                state.audit(AuditEntry::new(&user, AuditAction::Cleanup).failed(format!("{} (after cleaning {} orphaned rules)", error, count))).await;
+                return Err(ApiError::bad_gateway(error));
+            }
+        }
+    }
+    state.audit(AuditEntry::new(&user, AuditAction::Cleanup).message(format!("Cleaned {} orphaned rules", count))).await;
+
+    Ok(Json(ApiResponse {
+        success: true,
+        error: None,
+        message: Some(format!("Cleaned {} orphaned rules", count)),
+    }))
+}
+
+/// List rules
+///
+/// Returns every rule, optionally only those on one site.
+#[utoipa::path(
+    get,
+    path = "/api/v1/rules",
+    tag = "rules",
+    params(SiteQuery),
+    responses(
+        (status = 200, description = "Rules retrieved", body = Vec<ActiveRule>),
+        (status = 401, description = "Not signed in", body = ApiResponse)
+    )
+)]
+async fn list_rules(
+    State(state): State<AppState>,
+    Query(query): Query<SiteQuery>,
+) -> Json<Vec<ActiveRule>> {
+    let rules_db = state.rules_db.lock().await;
+    Json(rules_db.get_rules()
+        .iter()
+        .filter(|r| query.site.as_ref().map(|site| &r.site == site).unwrap_or(true))
+        .cloned()
+        .collect())
+}
+
+/// Create a rule
+///
+/// Blocks apps, domains or, with `pause`, all internet access. The ID and
+/// creation time are generated when omitted. Returns the stored rule.
+#[utoipa::path(
+    post,
+    path = "/api/v1/rules",
+    tag = "rules",
+    request_body = BlockRule,
+    responses(
+        (status = 201, description = "Rule created", body = ActiveRule),
+        (status = 400, description = "Invalid rule configuration", body = ApiResponse),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Role may not create rules", body = ApiResponse),
+        (status = 409, description = "Rule ID already in use, or not connected to a controller", body = ApiResponse),
+        (status = 502, description = "The controller rejected the rule", body = ApiResponse)
+    )
+)]
+async fn create_rule(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    Json(rule): Json<BlockRule>,
+) -> Result<Response, ApiError> {
+    let rule = add_block_rule(&state, &user, rule).await?;
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION, format!("/api/v1/rules/{}", rule.id))],
+        Json(rule),
+    ).into_response())
+}
+
+/// Get a rule
+#[utoipa::path(
+    get,
+    path = "/api/v1/rules/{id}",
+    tag = "rules",
+    params(("id" = String, Path, description = "Rule ID")),
+    responses(
+        (status = 200, description = "Rule found", body = ActiveRule),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 404, description = "Rule not found", body = ApiResponse)
+    )
+)]
+async fn get_rule(
+    State(state): State<AppState>,
+    UrlPath(rule_id): UrlPath<String>,
+) -> Result<Json<ActiveRule>, ApiError> {
+    state.rules_db.lock().await
+        .get_rules()
+        .iter()
+        .find(|r| r.id == rule_id)
+        .cloned()
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found("Rule not found"))
+}
+
+/// Update a rule
+///
+/// Changes a rule's apps, devices, duration or end time in place, without
+/// lifting the block in between. Returns the updated rule.
+#[utoipa::path(
+    patch,
+    path = "/api/v1/rules/{id}",
+    tag = "rules",
+    params(("id" = String, Path, description = "Rule ID")),
+    request_body = RuleUpdate,
+    responses(
+        (status = 200, description = "Rule updated", body = ActiveRule),
+        (status = 400, description = "Change doesn't fit the rule", body = ApiResponse),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Role may not change this rule", body = ApiResponse),
+        (status = 404, description = "Rule not found", body = ApiResponse),
+        (status = 409, description = "Not connected to a controller", body = ApiResponse),
+        (status = 502, description = "The controller rejected the change, which was rolled back", body = ApiResponse)
+    )
+)]
+async fn patch_rule(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    UrlPath(rule_id): UrlPath<String>,
+    Json(update): Json<RuleUpdate>,
+) -> Result<Json<ActiveRule>, ApiError> {
+    edit_block_rule(&state, &user, &rule_id, update).await.map(Json)
+}
+
+// Apply an edit to the controller, then to the stored rule. If the controller
+// refuses, whatever it already accepted is reverted and the rule is left as
+// it was. The rules lock isn't held while the controller is called.
+async fn edit_block_rule(state: &AppState, user: &CurrentUser, rule_id: &str, update: RuleUpdate) -> Result<ActiveRule, ApiError> {
+    let original = state.rules_db.lock().await.get_rules()
+        .iter()
+        .find(|r| r.id == rule_id)
+        .cloned()
+        .ok_or_else(|| ApiError::not_found("Rule not found"))?;
+
+    // An edit can weaken a rule as much as removing it
+    user.require(Permission::CreateRule)?;
+    user.require(original.removal_permission())?;
+
+    let mut updated = original.clone();
+
+    // Timed rules are lifted by our scheduler, so these only change the local record
+    if let Some(duration) = update.duration {
+        if original.rule_type != "duration" {
+            return Err(ApiError::bad_request("Only duration rules have a duration"));
+        }
+        if duration == 0 {
+            return Err(ApiError::bad_request("Duration must be at least one hour"));
+        }
+        updated.duration = Some(duration);
+    }
+    if let Some(end_time) = update.end_time {
+        if original.rule_type != "until" {
+            return Err(ApiError::bad_request("Only until rules have an endTime"));
+        }
+        if chrono::DateTime::parse_from_rfc3339(&end_time).is_err() {
+            return Err(ApiError::bad_request("endTime must be an ISO 8601 timestamp"));
+        }
+        updated.end_time = Some(end_time);
+    }
+
+    let mut app_ids = None;
+    if let Some(apps) = update.apps {
+        if original.apps.is_empty() {
+            return Err(ApiError::bad_request("This rule blocks no apps; create a new rule to block apps"));
+        }
+        if apps.is_empty() {
+            return Err(ApiError::bad_request("A rule needs at least one app; remove the rule instead"));
+        }
+        let resolved = state.apps.lock().await.resolve(&apps).map_err(|unknown| {
+            ApiError::bad_request(format!("Unknown apps: {}. Search /api/apps for the right name.", unknown.join(", ")))
+        })?;
+        updated.apps = apps;
+        app_ids = Some(resolved);
+    }
+
+    let mut device_macs = None;
+    if let Some(devices) = update.devices {
+        if let Some(ref profile_id) = original.profile {
+            return Err(ApiError::bad_request(format!("This rule follows profile {}; change the profile's devices instead", profile_id)));
+        }
+        let macs = if targets_all_devices(&devices) {
+            vec![]
+        } else {
+            profiles::normalize_macs(&devices).map_err(ApiError::bad_request)?
+        };
+        if original.pause && macs.is_empty() {
+            return Err(ApiError::bad_request("Pausing needs specific devices or a profile, not the whole network"));
+        }
+        // Firewall rules for all devices sit in a different ruleset and have no device group
+        if original.strategy == BlockStrategy::FirewallRules
+            && original.unifi_rule_id.is_some()
+            && macs.is_empty() != original.device_macs().is_empty()
+        {
+            return Err(ApiError::bad_request("Firewall rules can't switch between all devices and specific devices; create a new rule"));
+        }
+        updated.devices = if macs.is_empty() { devices } else { macs.clone() };
+        device_macs = Some(macs);
+    }
+
+    if original.on_controller() && (app_ids.is_some() || device_macs.is_some()) {
+        let pushed = match state.unifi().await {
+            Ok(unifi) => push_rule_edit(&unifi, &original, &updated, app_ids.as_deref(), device_macs.as_deref())
+                .await
+                .map_err(ApiError::bad_gateway),
+            Err(e) => Err(e.into()),
+        };
+        match pushed {
+            Ok(paused_macs) => updated.unifi_paused_macs = paused_macs,
+            Err(e) => {
+                error!("❌ Could not update rule {} on the controller: {}", rule_id, e.message);
+                if let Ok(unifi) = state.unifi().await {
+                    let original_app_ids = match app_ids {
+                        Some(_) => state.apps.lock().await.resolve(&original.apps).ok(),
+                        None => None,
+                    };
+                    let original_macs = device_macs.as_ref().map(|_| original.device_macs());
+                    if let Err(revert_error) = push_rule_edit(&unifi, &updated, &original, original_app_ids.as_deref(), original_macs.as_deref()).await {
+                        warn!("⚠️ Could not revert rule {} on the controller: {}", rule_id, revert_error);
+                    }
+                }
+                state.audit(AuditEntry::new(user, AuditAction::Update).rule(rule_id, &updated).previous(&original).failed(&e.message)).await;
+                return Err(e);
+            }
+        }
+    }
+
+    // Copy over only the edited fields, so a status change made meanwhile stays
+    let updated = state.rules_db.lock().await
+        .update_rule(rule_id, |stored| {
+            stored.duration = updated.duration;
+            stored.end_time = updated.end_time;
+            stored.apps = updated.apps;
+            stored.devices = updated.devices;
+            stored.unifi_paused_macs = updated.unifi_paused_macs;
+        })
+        .map_err(ApiError::internal)?
+        .ok_or_else(|| ApiError::not_found("Rule not found"))?;
+    state.audit(AuditEntry::new(user, AuditAction::Update).rule(rule_id, &updated).previous(&original)).await;
+    info!("✏️ {} updated rule {}", user, rule_id);
+    Ok(updated)
+}
+
+// Send a rule's new apps and devices to the controller, going from the state
+// in `from` to `to`. Returns the devices now paused by the station manager.
+async fn push_rule_edit(
+    unifi: &UnifiClient,
+    from: &ActiveRule,
+    to: &ActiveRule,
+    app_ids: Option<&[String]>,
+    device_macs: Option<&[String]>,
+) -> Result<Vec<String>, String> {
+    let mut block = from.controller_objects();
+    if let Some(app_ids) = app_ids {
+        from.strategy.update_apps(unifi, &from.site, &block, &to.apps, app_ids).await?;
+    }
+    if let Some(device_macs) = device_macs {
+        let enabled = from.unifi_enabled.unwrap_or(true);
+        from.strategy.retarget(unifi, &from.site, &mut block, device_macs, enabled).await?;
+    }
+    Ok(block.unifi_paused_macs)
+}
+
+/// Disable a rule
+///
+/// Lifts the block but keeps the rule, so it can be enabled again later.
+/// Timed rules keep counting down while disabled.
+#[utoipa::path(
+    post,
+    path = "/api/v1/rules/{id}/disable",
+    tag = "rules",
+    params(("id" = String, Path, description = "Rule ID")),
+    responses(
+        (status = 200, description = "Rule disabled", body = ActiveRule),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Role may not lift this rule", body = ApiResponse),
+        (status = 404, description = "Rule not found", body = ApiResponse),
+        (status = 409, description = "Not connected to a controller", body = ApiResponse),
+        (status = 502, description = "The controller failed to disable the rule", body = ApiResponse)
+    )
+)]
+async fn disable_rule(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    UrlPath(rule_id): UrlPath<String>,
+) -> Result<Json<ActiveRule>, ApiError> {
+    set_rule_active(&state, &user, &rule_id, false).await.map(Json)
+}
+
+/// Enable a rule
+///
+/// Puts a disabled rule back in force. Scheduled rules only block again
+/// during their window.
+#[utoipa::path(
+    post,
+    path = "/api/v1/rules/{id}/enable",
+    tag = "rules",
+    params(("id" = String, Path, description = "Rule ID")),
+    responses(
+        (status = 200, description = "Rule enabled", body = ActiveRule),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Role may not create rules", body = ApiResponse),
+        (status = 404, description = "Rule not found", body = ApiResponse),
+        (status = 409, description = "Not connected to a controller", body = ApiResponse),
+        (status = 502, description = "The controller failed to enable the rule", body = ApiResponse)
+    )
+)]
+async fn enable_rule(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    UrlPath(rule_id): UrlPath<String>,
+) -> Result<Json<ActiveRule>, ApiError> {
+    set_rule_active(&state, &user, &rule_id, true).await.map(Json)
+}
+
+// Switch a rule's status between active and disabled. The controller is
+// updated first, without holding the rules lock, so the stored status never
+// claims a state it isn't in.
+async fn set_rule_active(state: &AppState, user: &CurrentUser, rule_id: &str, active: bool) -> Result<ActiveRule, ApiError> {
+    let status = if active { "active" } else { "disabled" };
+    let (mut rule, enforced) = {
+        let rules_db = state.rules_db.lock().await;
+        let rule = rules_db.get_rules()
+            .iter()
+            .find(|r| r.id == rule_id)
+            .cloned()
+            .ok_or_else(|| ApiError::not_found("Rule not found"))?;
+        let mut switched = rule.clone();
+        switched.status = status.to_string();
+        // Scheduled rules enabled outside their window, or with extra time
+        // granted, stay off until the window opens or the time is up
+        let enforced = rules_db.enforced(&switched, chrono::Utc::now());
+        (rule, enforced)
+    };
+
+    // Disabling lifts the block, so it needs the same role as removing
+    if active {
+        user.require(Permission::CreateRule)?;
+    } else {
+        user.require(rule.removal_permission())?;
+    }
+
+    if rule.status == status {
+        return Ok(rule);
+    }
+    rule.status = status.to_string();
+    let action = if active { AuditAction::Enable } else { AuditAction::Disable };
+
+    if rule.on_controller() && rule.unifi_enabled != Some(enforced) {
+        if let Err(e) = state.set_unifi_rule_enabled(&rule, enforced).await {
+            error!("❌ Failed to {} rule {} on UniFi: {}", if active { "enable" } else { "disable" }, rule_id, e);
+            let error: ApiError = match e {
+                UnifiError::NotAuthenticated => e.into(),
+                e => ApiError::bad_gateway(format!("Failed to update rule on UniFi: {}", e)),
+            };
+            state.audit(AuditEntry::new(user, action).rule(rule_id, &rule).failed(&error.message)).await;
+            return Err(error);
+        }
+        rule.unifi_enabled = Some(enforced);
+    }
+
+    // Only the status changes, so an edit made meanwhile isn't undone
+    let rule = state.rules_db.lock().await
+        .update_rule(rule_id, |stored| {
+            stored.status = rule.status;
+            stored.unifi_enabled = rule.unifi_enabled;
+        })
+        .map_err(ApiError::internal)?
+        .ok_or_else(|| ApiError::not_found("Rule not found"))?;
+    state.audit(AuditEntry::new(user, action).rule(rule_id, &rule)).await;
+    info!("{} {} {} rule {}", if active { "▶️" } else { "⏸️" }, user, if active { "enabled" } else { "disabled" }, rule_id);
+    Ok(rule)
+}
+
+/// Delete a rule
+///
+/// Removes the rule from the controller, lifting the block, then forgets it.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/rules/{id}",
+    tag = "rules",
+    params(("id" = String, Path, description = "Rule ID")),
+    responses(
+        (status = 204, description = "Rule deleted"),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Role may not remove this rule", body = ApiResponse),
+        (status = 404, description = "Rule not found", body = ApiResponse),
+        (status = 409, description = "Not connected to a controller", body = ApiResponse),
+        (status = 502, description = "The controller failed to remove the rule", body = ApiResponse)
+    )
+)]
+async fn delete_rule(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    UrlPath(rule_id): UrlPath<String>,
+) -> Result<StatusCode, ApiError> {
+    remove_block_rule(&state, &user, &rule_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// List extra time
+///
+/// Returns the grants still running.
+#[utoipa::path(
+    get,
+    path = "/api/v1/grants",
+    tag = "rules",
+    responses(
+        (status = 200, description = "Running grants", body = Vec<Grant>),
+        (status = 401, description = "Not signed in", body = ApiResponse)
+    )
+)]
+async fn list_grants(State(state): State<AppState>) -> Json<Vec<Grant>> {
+    let now = chrono::Utc::now();
+    Json(state.rules_db.lock().await.grants
+        .iter()
+        .filter(|g| g.is_active_at(now))
+        .cloned()
+        .collect())
+}
+
+/// Grant extra time
+///
+/// Lifts a rule's block, or the blocks of all of a profile's active rules,
+/// for a number of minutes. The blocks come back by themselves afterwards,
+/// also across restarts. Returns one grant per lifted rule.
+#[utoipa::path(
+    post,
+    path = "/api/v1/grants",
+    tag = "rules",
+    request_body = GrantRequest,
+    responses(
+        (status = 201, description = "Extra time granted", body = Vec<Grant>),
+        (status = 400, description = "Invalid grant", body = ApiResponse),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Role may not grant extra time", body = ApiResponse),
+        (status = 404, description = "Rule or profile not found", body = ApiResponse),
+        (status = 409, description = "Not connected to a controller", body = ApiResponse),
+        (status = 502, description = "The controller failed to lift a block, nothing was granted", body = ApiResponse)
+    )
+)]
+async fn create_grant(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    Json(request): Json<GrantRequest>,
+) -> Result<Response, ApiError> {
+    let granted = grant_extra_time(&state, &user, request.rule_id.as_deref(), request.profile.as_deref(), request.minutes).await?;
+    Ok((StatusCode::CREATED, Json(granted)).into_response())
+}
+
+// Lift one rule, or all active rules of a profile, for some minutes
+async fn grant_extra_time(
+    state: &AppState,
+    user: &CurrentUser,
+    rule_id: Option<&str>,
+    profile: Option<&str>,
+    minutes: u32,
+) -> Result<Vec<Grant>, ApiError> {
+    // Extra time is bounded and ends by itself, so co-parents may give it
+    // even on rules they can't remove
+    user.require(Permission::GrantTime)?;
+    if minutes == 0 || minutes > grants::MAX_GRANT_MINUTES {
+        return Err(ApiError::bad_request(format!("minutes must be between 1 and {}", grants::MAX_GRANT_MINUTES)));
+    }
+
+    // Record the grants first, so reconcile leaves the blocks off while
+    // they are being lifted
+    let (rules, granted, replaced) = {
+        let mut rules_db = state.rules_db.lock().await;
+        let rules: Vec<ActiveRule> = match (rule_id, profile) {
+            (Some(rule_id), None) => {
+                let rule = rules_db.get_rules()
+                    .iter()
+                    .find(|r| r.id == rule_id)
+                    .cloned()
+                    .ok_or_else(|| ApiError::not_found("Rule not found"))?;
+                if rule.status != "active" {
+                    return Err(ApiError::bad_request("The rule is disabled, so there is no block to lift"));
+                }
+                vec![rule]
+            }
+            (None, Some(profile_id)) => {
+                let profile = rules_db.get_profile(profile_id)
+                    .ok_or_else(|| ApiError::not_found("Profile not found"))?;
+                let rules: Vec<ActiveRule> = rules_db.get_rules()
+                    .iter()
+                    .filter(|r| r.profile.as_deref() == Some(profile_id) && r.status == "active")
+                    .cloned()
+                    .collect();
+                if rules.is_empty() {
+                    return Err(ApiError::bad_request(format!("{} has no active rules", profile.name)));
+                }
+                rules
+            }
+            _ => return Err(ApiError::bad_request("Give either ruleId or profile")),
+        };
+
+        let now = chrono::Utc::now();
+        let mut granted = Vec::new();
+        let mut replaced = Vec::new();
+        for rule in &rules {
+            let previous = rules_db.grants
+                .iter()
+                .position(|g| g.rule_id == rule.id)
+                .map(|pos| rules_db.grants.remove(pos));
+            // Asking again adds to the time already granted
+            let start = previous.as_ref()
+                .and_then(|g| g.until())
+                .filter(|until| *until > now)
+                .unwrap_or(now);
+            let grant = Grant {
+                rule_id: rule.id.clone(),
+                profile: profile.map(String::from),
+                granted_by: user.username.clone(),
+                granted_at: now.to_rfc3339(),
+                until: (start + chrono::Duration::minutes(minutes as i64)).to_rfc3339(),
+            };
+            rules_db.grants.push(grant.clone());
+            granted.push(grant);
+            replaced.extend(previous);
+        }
+        (rules, granted, replaced)
+    };
+
+    // Lift the blocks now rather than on the next scheduler tick. If the
+    // controller refuses one, the others are put back and nothing is granted.
+    let mut lifted: Vec<&ActiveRule> = Vec::new();
+    for rule in &rules {
+        if !rule.on_controller() || rule.unifi_enabled == Some(false) {
+            continue;
+        }
+        if let Err(e) = state.set_unifi_rule_enabled(rule, false).await {
+            error!("❌ Failed to lift rule {} for extra time: {}", rule.id, e);
+            for lifted_rule in &lifted {
+                if let Err(e) = state.set_unifi_rule_enabled(lifted_rule, true).await {
+                    warn!("⚠️ Could not restore rule {}: {} (will retry)", lifted_rule.id, e);
+                }
+            }
+            {
+                let mut rules_db = state.rules_db.lock().await;
+                rules_db.grants.retain(|g| !granted.contains(g));
+                // Unless someone granted time on the rule meanwhile
+                for previous in replaced {
+                    if !rules_db.grants.iter().any(|g| g.rule_id == previous.rule_id) {
+                        rules_db.grants.push(previous);
+                    }
+                }
+            }
+            let error: ApiError = match e {
+                UnifiError::NotAuthenticated => e.into(),
+                e => ApiError::bad_gateway(format!("Failed to lift rule {} on UniFi: {}", rule.id, e)),
+            };
+            state.audit(AuditEntry::new(user, AuditAction::Grant).rule(&rule.id, rule).failed(&error.message)).await;
+            return Err(error);
+        }
+        lifted.push(rule);
+    }
+    {
+        let mut rules_db = state.rules_db.lock().await;
+        for rule in &lifted {
+            if let Some(stored) = rules_db.rules.iter_mut().find(|r| r.id == rule.id) {
+                stored.unifi_enabled = Some(false);
+            }
+        }
+        rules_db.save().map_err(ApiError::internal)?;
+    }
+
+    for (rule, grant) in rules.iter().zip(&granted) {
+        state.audit(AuditEntry::new(user, AuditAction::Grant)
+            .rule(&rule.id, rule)
+            .message(format!("{} extra minutes, blocked again at {}", minutes, grant.until))).await;
+    }
+    info!("⏱️ {} granted {} extra minutes on rules {}", user, minutes,
+        rules.iter().map(|r| r.id.as_str()).collect::<Vec<_>>().join(", "));
+    Ok(granted)
+}
+
+/// End extra time early
+///
+/// Puts a rule's block back before its grant runs out.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/grants/{rule_id}",
+    tag = "rules",
+    params(("rule_id" = String, Path, description = "Rule the time was granted on")),
+    responses(
+        (status = 204, description = "Grant ended"),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Role may not grant extra time", body = ApiResponse),
+        (status = 404, description = "No running grant for this rule", body = ApiResponse),
+        (status = 409, description = "Not connected to a controller", body = ApiResponse),
+        (status = 502, description = "The controller failed to restore the block", body = ApiResponse)
+    )
+)]
+async fn delete_grant(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    UrlPath(rule_id): UrlPath<String>,
+) -> Result<StatusCode, ApiError> {
+    user.require(Permission::GrantTime)?;
+
+    let now = chrono::Utc::now();
+    let (grant, rule) = {
+        let mut rules_db = state.rules_db.lock().await;
+        let pos = rules_db.grants
+            .iter()
+            .position(|g| g.rule_id == rule_id && g.is_active_at(now))
+            .ok_or_else(|| ApiError::not_found("No running grant for this rule"))?;
+        let grant = rules_db.grants.remove(pos);
+        let rule = rules_db.get_rules()
+            .iter()
+            .find(|r| r.id == rule_id)
+            .map(|r| (r.clone(), rules_db.enforced(r, now)));
+        (grant, rule)
+    };
+
+    let mut restored = None;
+    if let Some((rule, enabled)) = rule {
+        if rule.on_controller() && rule.unifi_enabled != Some(enabled) {
+            if let Err(e) = state.set_unifi_rule_enabled(&rule, enabled).await {
+                {
+                    let mut rules_db = state.rules_db.lock().await;
+                    if !rules_db.grants.iter().any(|g| g.rule_id == rule_id) {
+                        rules_db.grants.push(grant);
+                    }
+                }
+                let error: ApiError = match e {
+                    UnifiError::NotAuthenticated => e.into(),
+                    e => ApiError::bad_gateway(format!("Failed to restore rule on UniFi: {}", e)),
+                };
+                state.audit(AuditEntry::new(&user, AuditAction::EndGrant).rule(&rule_id, &rule).failed(&error.message)).await;
+                return Err(error);
+            }
+            restored = Some(enabled);
+        }
+    }
+    {
+        let mut rules_db = state.rules_db.lock().await;
+        if let Some(enabled) = restored {
+            if let Some(stored) = rules_db.rules.iter_mut().find(|r| r.id == rule_id) {
+                stored.unifi_enabled = Some(enabled);
+            }
+        }
+        rules_db.save().map_err(ApiError::internal)?;
+    }
+    state.audit(AuditEntry::new(&user, AuditAction::EndGrant)
+        .rule(&rule_id, &grant)
+        .message(format!("Ended early, {} had granted time until {}", grant.granted_by, grant.until))).await;
+
+    info!("⏱️ {} ended extra time on rule {}", user, rule_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// List budgets
+///
+/// Returns the daily allowances with the time used so far today.
+#[utoipa::path(
+    get,
+    path = "/api/v1/budgets",
+    tag = "profiles",
+    responses(
+        (status = 200, description = "Budgets retrieved", body = Vec<Budget>),
+        (status = 401, description = "Not signed in", body = ApiResponse)
+    )
+)]
+async fn list_budgets(State(state): State<AppState>) -> Json<Vec<Budget>> {
+    Json(state.rules_db.lock().await.budgets.clone())
+}
+
+/// Add a budget
+///
+/// Gives a profile a daily allowance of time on some apps, or online at all.
+/// Time is counted while the profile's devices use the apps; once it runs
+/// out an until rule blocks them until the allowance resets.
+#[utoipa::path(
+    post,
+    path = "/api/v1/budgets",
+    tag = "profiles",
+    request_body = BudgetRequest,
+    responses(
+        (status = 201, description = "Budget created", body = Budget),
+        (status = 400, description = "Invalid budget", body = ApiResponse),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Role may not manage profiles", body = ApiResponse),
+        (status = 409, description = "The profile already has a budget for these apps", body = ApiResponse)
+    )
+)]
+async fn create_budget(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    Json(request): Json<BudgetRequest>,
+) -> Result<Response, ApiError> {
+    user.require(Permission::ManageProfiles)?;
+
+    let site = state.resolve_site(request.site.as_deref()).await.map_err(ApiError::bad_request)?;
+    if let Err(unknown) = state.apps.lock().await.resolve(&request.apps) {
+        return Err(ApiError::bad_request(format!("Unknown apps: {}. Search /api/apps for the right name.", unknown.join(", "))));
+    }
+
+    let mut rules_db = state.rules_db.lock().await;
+    if rules_db.get_profile(&request.profile).is_none() {
+        return Err(ApiError::bad_request(format!("Unknown profile: {}", request.profile)));
+    }
+    let mut apps = request.apps.clone();
+    apps.sort();
+    if rules_db.budgets.iter().any(|b| {
+        let mut existing = b.apps.clone();
+        existing.sort();
+        b.profile == request.profile && existing == apps
+    }) {
+        return Err(ApiError::conflict("The profile already has a budget for these apps"));
+    }
+
+    let mut budget = Budget {
+        id: new_id(),
+        profile: request.profile,
+        apps: request.apps,
+        minutes_per_day: request.minutes_per_day,
+        reset_at: request.reset_at.unwrap_or_else(|| "00:00".to_string()),
+        timezone: request.timezone.unwrap_or_else(schedule::default_timezone),
+        site,
+        used_seconds: 0,
+        day: String::new(),
+        rule_id: None,
+        last_bytes: None,
+        last_checked: None,
+    };
+    budget.validate().map_err(ApiError::bad_request)?;
+    budget.roll_over(chrono::Utc::now());
+
+    rules_db.budgets.push(budget.clone());
+    if let Err(e) = rules_db.save() {
+        state.audit(AuditEntry::new(&user, AuditAction::Budget).snapshot(&budget).failed(&e)).await;
+        return Err(ApiError::internal(e));
+    }
+    state.audit(AuditEntry::new(&user, AuditAction::Budget)
+        .snapshot(&budget)
+        .message(format!("Created for {}, {} minutes a day", budget.profile, budget.minutes_per_day))).await;
+    info!("⌛ {} gave {} a budget of {} minutes a day", user, budget.profile, budget.minutes_per_day);
+
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION, format!("/api/v1/budgets/{}", budget.id))],
+        Json(budget),
+    ).into_response())
+}
+
+/// Delete a budget
+///
+/// Stops counting time. A block already in place for today stays until the
+/// reset, or until it is removed like any other rule.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/budgets/{id}",
+    tag = "profiles",
+    params(("id" = String, Path, description = "Budget ID")),
+    responses(
+        (status = 204, description = "Budget deleted"),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Role may not manage profiles", body = ApiResponse),
+        (status = 404, description = "Budget not found", body = ApiResponse)
+    )
+)]
+async fn delete_budget(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    UrlPath(budget_id): UrlPath<String>,
+) -> Result<StatusCode, ApiError> {
+    user.require(Permission::ManageProfiles)?;
+
+    let mut rules_db = state.rules_db.lock().await;
+    let pos = rules_db.budgets
+        .iter()
+        .position(|b| b.id == budget_id)
+        .ok_or_else(|| ApiError::not_found("Budget not found"))?;
+    let budget = rules_db.budgets.remove(pos);
+    if let Err(e) = rules_db.save() {
+        state.audit(AuditEntry::new(&user, AuditAction::Budget).snapshot(&budget).failed(&e)).await;
+        return Err(ApiError::internal(e));
+    }
+    state.audit(AuditEntry::new(&user, AuditAction::Budget).snapshot(&budget).message("Deleted")).await;
+
+    info!("⌛ {} deleted budget {}", user, budget_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn kids_page() -> impl IntoResponse {
+    Html(include_str!("../kids.html"))
+}
+
+// Find the controller's client behind a request's address. Only works for
+// devices reaching the app directly on the LAN, not through a proxy.
+async fn identify_device(state: &AppState, addr: SocketAddr) -> Result<(String, ClientDevice), ApiError> {
+    let unifi = state.unifi().await?;
+    let ip = addr.ip().to_canonical().to_string();
+    for site in state.known_sites().await {
+        let clients = unifi.list_clients(&site).await?;
+        if let Some(client) = clients.into_iter().find(|c| c.ip.as_deref() == Some(ip.as_str())) {
+            return Ok((site, client));
+        }
+    }
+    Err(ApiError::not_found(format!("No device with address {} is connected to the network", ip)))
+}
+
+// Whether a rule blocks the given device, directly, through its profile or network-wide
+fn rule_applies_to(rule: &ActiveRule, site: &str, mac: &str) -> bool {
+    rule.site == site
+        && (targets_all_devices(&rule.devices) || rule.devices.iter().any(|d| d.eq_ignore_ascii_case(mac)))
+}
+
+/// What's blocked for this device
+///
+/// For children: looks the calling device up by its address and lists the
+/// rules aimed at it and its recent requests. No sign-in needed.
 #[utoipa::path(
-    post,
-    path = "/api/sync",
-    tag = "rules",
+    get,
+    path = "/api/kids/status",
+    tag = "kids",
     responses(
-        (status = 200, description = "Rules synchronized successfully", body = ApiResponse),
-        (status = 401, description = "Not authenticated", body = ApiResponse)
+        (status = 200, description = "Device found", body = KidStatus),
+        (status = 404, description = "The address doesn't belong to a connected device", body = ApiResponse),
+        (status = 409, description = "Not connected to a controller", body = ApiResponse)
     )
 )]
-async fn sync_rules(State(state): State<AppState>) -> impl IntoResponse {
-    println!("🔄 Manual rule synchronization requested");
-    
-    match state.sync_rules_with_unifi().await {
-        Ok(_) => Json(ApiResponse {
-            success: true,
-            error: None,
-            message: Some("Rules synchronized successfully".to_string()),
-        }),
-        Err(e) => Json(ApiResponse {
-            success: false,
-            error: Some(e),
-            message: None,
+async fn kid_status(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> Result<Json<KidStatus>, ApiError> {
+    let (site, device) = identify_device(&state, addr).await?;
+    let mac = device.mac.to_lowercase();
+    let now = chrono::Utc::now();
+
+    let rules_db = state.rules_db.lock().await;
+    let rules = rules_db.get_rules()
+        .iter()
+        .filter(|r| rule_applies_to(r, &site, &mac))
+        .map(|r| KidRule {
+            id: r.id.clone(),
+            apps: r.apps.clone(),
+            domains: r.domains.clone(),
+            pause: r.pause,
+            blocking: rules_db.enforced(r, now),
+            ends: r.expires_at().map(|t| t.to_rfc3339()),
         })
+        .collect();
+    let requests = rules_db.access_requests
+        .iter()
+        .rev()
+        .filter(|r| r.mac == mac)
+        .take(10)
+        .cloned()
+        .collect();
+
+    Ok(Json(KidStatus {
+        mac,
+        device_name: device.friendly_name(),
+        rules,
+        requests,
+    }))
+}
+
+/// Ask for a rule to be lifted
+///
+/// For children: sends an unblock request for one of the rules aimed at the
+/// calling device, for a parent to approve or deny. No sign-in needed.
+#[utoipa::path(
+    post,
+    path = "/api/kids/requests",
+    tag = "kids",
+    request_body = KidAccessRequest,
+    responses(
+        (status = 201, description = "Request sent", body = AccessRequest),
+        (status = 400, description = "Missing reason or invalid minutes", body = ApiResponse),
+        (status = 404, description = "Unknown device, or no such rule for it", body = ApiResponse),
+        (status = 409, description = "Already waiting for an answer on this rule, or not connected to a controller", body = ApiResponse),
+        (status = 429, description = "Too many requests from this device waiting for an answer", body = ApiResponse)
+    )
+)]
+async fn create_kid_request(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(request): Json<KidAccessRequest>,
+) -> Result<Response, ApiError> {
+    let reason = request.reason.trim();
+    if reason.is_empty() {
+        return Err(ApiError::bad_request("Say why you need it"));
+    }
+    if reason.chars().count() > access::MAX_REASON_LEN {
+        return Err(ApiError::bad_request(format!("Keep the reason under {} characters", access::MAX_REASON_LEN)));
     }
+    let minutes = request.minutes.unwrap_or(access::DEFAULT_REQUEST_MINUTES);
+    if minutes == 0 || minutes > grants::MAX_GRANT_MINUTES {
+        return Err(ApiError::bad_request(format!("minutes must be between 1 and {}", grants::MAX_GRANT_MINUTES)));
+    }
+
+    let (site, device) = identify_device(&state, addr).await?;
+    let mac = device.mac.to_lowercase();
+
+    let mut rules_db = state.rules_db.lock().await;
+    let rule = rules_db.get_rules()
+        .iter()
+        .find(|r| r.id == request.rule_id && rule_applies_to(r, &site, &mac))
+        .cloned()
+        .ok_or_else(|| ApiError::not_found("No rule with that ID blocks this device"))?;
+    if rules_db.access_requests.iter().any(|r| {
+        r.rule_id == rule.id && r.mac == mac && r.status == AccessStatus::Pending
+    }) {
+        return Err(ApiError::conflict("You already asked, wait for an answer"));
+    }
+    if access::pending_for(&rules_db.access_requests, &mac) >= access::MAX_PENDING_PER_DEVICE {
+        return Err(ApiError::too_many_requests(format!(
+            "You already have {} requests waiting, wait for an answer first",
+            access::MAX_PENDING_PER_DEVICE
+        )));
+    }
+
+    let access_request = AccessRequest {
+        id: new_id(),
+        rule_id: rule.id.clone(),
+        mac,
+        device_name: device.friendly_name(),
+        apps: rule.apps.clone(),
+        reason: reason.to_string(),
+        minutes,
+        status: AccessStatus::Pending,
+        requested_at: chrono::Utc::now().to_rfc3339(),
+        decided_by: None,
+        decided_at: None,
+        granted_minutes: None,
+        note: None,
+    };
+    rules_db.access_requests.push(access_request.clone());
+    rules_db.save().map_err(ApiError::internal)?;
+
+    info!("🙋 {} asked for {} minutes on rule {}: {}",
+        access_request.device_name.as_deref().unwrap_or(&access_request.mac), minutes, rule.id, reason);
+    Ok((StatusCode::CREATED, Json(access_request)).into_response())
 }
 
-/// Clean orphaned rules
+/// List access requests
 ///
-/// Removes UniFi firewall rules created by this tool but no longer tracked in our database.
-/// This helps maintain a clean UniFi configuration.
+/// Returns children's unblock requests, newest first. Use
+/// `?status=pending` for the queue still waiting for an answer.
+#[utoipa::path(
+    get,
+    path = "/api/v1/access-requests",
+    tag = "kids",
+    params(AccessRequestQuery),
+    responses(
+        (status = 200, description = "Requests retrieved", body = Vec<AccessRequest>),
+        (status = 401, description = "Not signed in", body = ApiResponse)
+    )
+)]
+async fn list_access_requests(
+    State(state): State<AppState>,
+    Query(query): Query<AccessRequestQuery>,
+) -> Json<Vec<AccessRequest>> {
+    Json(state.rules_db.lock().await.access_requests
+        .iter()
+        .rev()
+        .filter(|r| query.status.map(|status| r.status == status).unwrap_or(true))
+        .cloned()
+        .collect())
+}
+
+// A request that is still waiting for an answer
+async fn pending_access_request(state: &AppState, request_id: &str) -> Result<AccessRequest, ApiError> {
+    let access_request = state.rules_db.lock().await.access_requests
+        .iter()
+        .find(|r| r.id == request_id)
+        .cloned()
+        .ok_or_else(|| ApiError::not_found("Request not found"))?;
+    if access_request.status != AccessStatus::Pending {
+        return Err(ApiError::conflict("The request was already answered"));
+    }
+    Ok(access_request)
+}
+
+// Record a parent's answer on a request. The status is checked again under
+// the lock, so of two answers given at once only the first counts.
+async fn decide_access_request(
+    state: &AppState,
+    user: &CurrentUser,
+    request_id: &str,
+    status: AccessStatus,
+    granted_minutes: Option<u32>,
+    note: Option<String>,
+) -> Result<AccessRequest, ApiError> {
+    user.require(Permission::GrantTime)?;
+    let mut rules_db = state.rules_db.lock().await;
+    let access_request = rules_db.access_requests
+        .iter_mut()
+        .find(|r| r.id == request_id)
+        .ok_or_else(|| ApiError::not_found("Request not found"))?;
+    if access_request.status != AccessStatus::Pending {
+        return Err(ApiError::conflict("The request was already answered"));
+    }
+    access_request.status = status;
+    access_request.decided_by = Some(user.username.clone());
+    access_request.decided_at = Some(chrono::Utc::now().to_rfc3339());
+    access_request.granted_minutes = granted_minutes;
+    access_request.note = note.map(|n| n.trim().to_string()).filter(|n| !n.is_empty());
+    let decided = access_request.clone();
+    rules_db.save().map_err(ApiError::internal)?;
+    Ok(decided)
+}
+
+// Put an approval back in the queue when the time couldn't be granted
+async fn reopen_access_request(state: &AppState, request_id: &str) {
+    let mut rules_db = state.rules_db.lock().await;
+    if let Some(access_request) = rules_db.access_requests.iter_mut().find(|r| r.id == request_id) {
+        access_request.status = AccessStatus::Pending;
+        access_request.decided_by = None;
+        access_request.decided_at = None;
+        access_request.granted_minutes = None;
+        access_request.note = None;
+    }
+    if let Err(e) = rules_db.save() {
+        warn!("⚠️ Failed to reopen request {}: {}", request_id, e);
+    }
+}
+
+/// Approve an access request
+///
+/// Grants extra time on the requested rule, the minutes asked for unless
+/// the decision names others.
 #[utoipa::path(
     post,
-    path = "/api/cleanup",
-    tag = "rules",
+    path = "/api/v1/access-requests/{id}/approve",
+    tag = "kids",
+    params(("id" = String, Path, description = "Request ID")),
+    request_body = AccessDecision,
     responses(
-        (status = 200, description = "Orphaned rules cleaned successfully", body = ApiResponse),
-        (status = 401, description = "Not authenticated", body = ApiResponse)
+        (status = 200, description = "Request approved and time granted", body = AccessRequest),
+        (status = 400, description = "Invalid minutes, or the rule is disabled", body = ApiResponse),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Role may not grant extra time", body = ApiResponse),
+        (status = 404, description = "Request or its rule not found", body = ApiResponse),
+        (status = 409, description = "Already answered, or not connected to a controller", body = ApiResponse),
+        (status = 502, description = "The controller failed to lift the block", body = ApiResponse)
     )
 )]
-async fn cleanup_rules(State(state): State<AppState>) -> impl IntoResponse {
-    println!("🧹 Manual rule cleanup requested");
-    
-    match state.cleanup_orphaned_rules().await {
-        Ok(count) => Json(ApiResponse {
-            success: true,
-            error: None,
-            message: Some(format!("Cleaned {} orphaned rules", count)),
-        }),
-        Err(e) => Json(ApiResponse {
-            success: false,
-            error: Some(e),
-            message: None,
-        })
+async fn approve_access_request(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    UrlPath(request_id): UrlPath<String>,
+    Json(decision): Json<AccessDecision>,
+) -> Result<Json<AccessRequest>, ApiError> {
+    let access_request = pending_access_request(&state, &request_id).await?;
+    let minutes = decision.minutes.unwrap_or(access_request.minutes);
+    // Answer first, so a second approval or a deny arriving meanwhile gets a
+    // conflict instead of granting the time twice or being overwritten
+    let decided = decide_access_request(&state, &user, &request_id, AccessStatus::Approved, Some(minutes), decision.note).await?;
+    if let Err(e) = grant_extra_time(&state, &user, Some(&access_request.rule_id), None, minutes).await {
+        reopen_access_request(&state, &request_id).await;
+        state.audit(AuditEntry::new(&user, AuditAction::Approve)
+            .rule(&access_request.rule_id, &access_request)
+            .failed(&e.message)).await;
+        return Err(e);
+    }
+
+    state.audit(AuditEntry::new(&user, AuditAction::Approve)
+        .rule(&decided.rule_id, &decided)
+        .message(format!("{} minutes for {}", minutes, decided.device_name.as_deref().unwrap_or(&decided.mac)))).await;
+    info!("✅ {} approved request {} for {} minutes", user, request_id, minutes);
+    Ok(Json(decided))
+}
+
+/// Deny an access request
+#[utoipa::path(
+    post,
+    path = "/api/v1/access-requests/{id}/deny",
+    tag = "kids",
+    params(("id" = String, Path, description = "Request ID")),
+    request_body = AccessDecision,
+    responses(
+        (status = 200, description = "Request denied", body = AccessRequest),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Role may not answer requests", body = ApiResponse),
+        (status = 404, description = "Request not found", body = ApiResponse),
+        (status = 409, description = "Already answered", body = ApiResponse)
+    )
+)]
+async fn deny_access_request(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    UrlPath(request_id): UrlPath<String>,
+    Json(decision): Json<AccessDecision>,
+) -> Result<Json<AccessRequest>, ApiError> {
+    user.require(Permission::GrantTime)?;
+    pending_access_request(&state, &request_id).await?;
+
+    let decided = decide_access_request(&state, &user, &request_id, AccessStatus::Denied, None, decision.note).await?;
+    state.audit(AuditEntry::new(&user, AuditAction::Deny).rule(&decided.rule_id, &decided)).await;
+    info!("🚫 {} denied request {}", user, request_id);
+    Ok(Json(decided))
+}
+
+/// Read the audit log
+///
+/// Every change made to the rules, by whom and with what result, newest
+/// first. Entries older than the retention settings are dropped.
+#[utoipa::path(
+    get,
+    path = "/api/audit",
+    tag = "audit",
+    params(AuditQuery),
+    responses(
+        (status = 200, description = "A page of audit log entries", body = AuditPage),
+        (status = 400, description = "Invalid page", body = ApiResponse),
+        (status = 401, description = "Not signed in", body = ApiResponse),
+        (status = 403, description = "Requires the parent role", body = ApiResponse)
+    )
+)]
+async fn get_audit_log(
+    State(state): State<AppState>,
+    Extension(user): Extension<CurrentUser>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<AuditPage>, ApiError> {
+    user.require(Permission::ViewAudit)?;
+
+    let page = query.page.unwrap_or(1);
+    let per_page = query.per_page.unwrap_or(50);
+    if page == 0 {
+        return Err(ApiError::bad_request("Pages start at 1"));
+    }
+    if per_page == 0 || per_page > MAX_AUDIT_PAGE {
+        return Err(ApiError::bad_request(format!("perPage must be between 1 and {}", MAX_AUDIT_PAGE)));
     }
+
+    let entries = state.audit.lock().await.entries().map_err(ApiError::internal)?;
+    let matching: Vec<AuditEntry> = entries
+        .into_iter()
+        .rev()
+        .filter(|e| query.rule_id.as_ref().map(|id| e.rule_id.as_ref() == Some(id)).unwrap_or(true))
+        .filter(|e| query.actor.as_ref().map(|actor| &e.actor == actor).unwrap_or(true))
+        .filter(|e| query.action.map(|action| e.action == action).unwrap_or(true))
+        .collect();
+    let total = matching.len();
+
+    Ok(Json(AuditPage {
+        entries: matching.into_iter().skip((page - 1).saturating_mul(per_page)).take(per_page).collect(),
+        page,
+        per_page,
+        total,
+    }))
 }
 
 #[tokio::main]
 async fn main() {
-    let state = AppState::new();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("❌ Invalid configuration: {}", e);
+            std::process::exit(2);
+        }
+    };
+    env_logger::Builder::new()
+        .parse_filters(&config.log_level)
+        .format_target(false)
+        .target(env_logger::Target::Stdout)
+        .init();
+    if let Some(ref file) = config.file {
+        info!("⚙️ Loaded configuration from {}", file);
+    }
+    if let Err(e) = fs::create_dir_all(&config.data_dir) {
+        error!("❌ Cannot create data directory {}: {}", config.data_dir.display(), e);
+        std::process::exit(1);
+    }
+    let listen_addr = config.listen_addr();
+    let rules_file = config.data_file(RULES_DB_FILE);
+
+    let state = match AppState::new(config) {
+        Ok(state) => state,
+        Err(e) => {
+            error!("❌ Refusing to start: {}", e);
+            // Other state files (accounts, apps.toml) name themselves in the error
+            if e.contains(RULES_DB_FILE) {
+                error!("   Restore {} from a backup or remove it to start with no rules", rules_file.display());
+            }
+            std::process::exit(1);
+        }
+    };
 
     // Perform initial sync on startup if authenticated
-    println!("🚀 Starting Parental UniFi Quick Set...");
+    info!("🚀 Starting Parental UniFi Quick Set...");
+    if state.restore_session().await {
+        for site in state.known_sites().await {
+            if let Err(e) = state.sync_rules_with_unifi(&site).await {
+                warn!("⚠️ Startup sync failed for site {}: {}", site, e.message);
+            }
+        }
+    }
 
+    // Expire timed rules and flip scheduled rules in the background
+    tokio::spawn(scheduler::run(state.clone()));
+
+    // The pre-v1 rule endpoints stay routed for existing scripts
+    #[allow(deprecated)]
     let app = Router::new()
         .route("/", get(index))
+        .route("/kids", get(kids_page))
+        .route("/api/kids/status", get(kid_status))
+        .route("/api/kids/requests", post(create_kid_request))
+        .route("/api/v1/access-requests", get(list_access_requests))
+        .route("/api/v1/access-requests/:id/approve", post(approve_access_request))
+        .route("/api/v1/access-requests/:id/deny", post(deny_access_request))
+        .route("/api/audit", get(get_audit_log))
+        .route("/api/auth/status", get(auth_status))
+        .route("/api/auth/setup", post(auth_setup))
+        .route("/api/auth/login", post(auth_login))
+        .route("/api/auth/logout", post(auth_logout))
+        .route("/api/auth/users", post(create_account))
+        .route("/api/auth/users/role", post(set_account_role))
+        .route("/api/auth/tokens", post(create_api_token))
+        .route("/api/auth/tokens/revoke", post(revoke_api_token))
         .route("/api/login", post(login_handler))
+        .route("/api/sites", get(get_sites))
         .route("/api/devices", get(get_devices))
+        .route("/api/apps", get(get_apps))
+        .route("/api/apps/refresh", post(refresh_apps))
+        .route("/api/profiles", get(get_profiles).post(create_profile))
+        .route("/api/profiles/update", post(update_profile))
+        .route("/api/profiles/delete", post(delete_profile))
         .route("/api/block", post(create_block_rule))
         .route("/api/unblock", post(unblock_rule))
         .route("/api/unblock-all", post(unblock_all_rules))
         .route("/api/rules", get(get_rules))
+        .route("/api/v1/rules", get(list_rules).post(create_rule))
+        .route("/api/v1/rules/:id", get(get_rule).patch(patch_rule).delete(delete_rule))
+        .route("/api/v1/rules/:id/enable", post(enable_rule))
+        .route("/api/v1/rules/:id/disable", post(disable_rule))
+        .route("/api/v1/grants", get(list_grants).post(create_grant))
+        .route("/api/v1/grants/:rule_id", delete(delete_grant))
+        .route("/api/v1/budgets", get(list_budgets).post(create_budget))
+        .route("/api/v1/budgets/:id", delete(delete_budget))
         .route("/api/sync", post(sync_rules))
         .route("/api/cleanup", post(cleanup_rules))
         .route("/api-docs/openapi.json", get(openapi_json))
         .route("/docs", get(docs_page))
+        .layer(middleware::from_fn_with_state(state.clone(), auth::require_login))
         .with_state(state);
 
-    println!("🚀 Parental UniFi Quick Set running on http://0.0.0.0:3000");
-    println!("📱 Mobile-friendly interface with beautiful styling");
-    println!("🛡️ Easy parental controls for your UniFi network");
-    println!("📚 API Documentation available at http://0.0.0.0:3000/docs");
-    println!("💾 Persistent rule storage enabled");
-    println!("🔄 Automatic rule synchronization with UniFi");
+    info!("🚀 Parental UniFi Quick Set running on http://{}", listen_addr);
+    info!("📱 Mobile-friendly interface with beautiful styling");
+    info!("🛡️ Easy parental controls for your UniFi network");
+    info!("📚 API Documentation available at http://{}/docs", listen_addr);
+    info!("💾 Persistent rule storage in {}", rules_file.display());
+    info!("🔄 Automatic rule synchronization with UniFi");
+    info!("⏰ Rule scheduler checking every {}s", scheduler::TICK_INTERVAL_SECS);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000")
-        .await
-        .unwrap();
+    let listener = match tokio::net::TcpListener::bind(listen_addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("❌ Cannot listen on {}: {}", listen_addr, e);
+            std::process::exit(1);
+        }
+    };
         
-    axum::serve(listener, app).await.unwrap();
-}
\ No newline at end of file
+    // Children's devices are recognised by their address
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_partial_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    // App state with its files in `dir`, not connected to a controller
+    pub(crate) fn test_state(dir: &Path) -> AppState {
+        AppState::new(Config { data_dir: dir.to_path_buf(), ..Config::default() }).unwrap()
+    }
+
+    // Connect the state to a mock UniFi OS console
+    pub(crate) async fn connect(state: &AppState, server: &MockServer) {
+        Mock::given(method("POST"))
+            .and(path("/api/auth/login"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(server)
+            .await;
+        let unifi = UnifiClient::login(Client::new(), &server.uri(), "admin", "secret").await.unwrap();
+        *state.unifi.lock().await = Some(unifi);
+    }
+
+    fn envelope(data: serde_json::Value) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(serde_json::json!({ "meta": { "rc": "ok" }, "data": data }))
+    }
+
+    pub(crate) fn parent() -> CurrentUser {
+        CurrentUser { username: "mom".to_string(), role: Role::Parent }
+    }
+
+    // A permanent traffic rule with the given fields on top
+    pub(crate) fn rule(fields: serde_json::Value) -> ActiveRule {
+        let mut rule = serde_json::json!({
+            "id": "1",
+            "apps": ["youtube"],
+            "rule_type": "permanent",
+            "devices": [],
+            "site": "default",
+            "status": "active",
+            "created": chrono::Utc::now().to_rfc3339(),
+            "strategy": "traffic_rules",
+        });
+        rule.as_object_mut().unwrap().extend(fields.as_object().unwrap().clone());
+        serde_json::from_value(rule).unwrap()
+    }
+
+    pub(crate) fn traffic_rule(id: &str) -> serde_json::Value {
+        serde_json::json!({
+            "_id": id, "description": "[PUC] youtube", "action": "BLOCK", "matching_target": "APP", "enabled": true
+        })
+    }
+
+    // A database saved twice, so there is a backup holding the first save
+    fn saved_database(dir: &Path) -> (PathBuf, String) {
+        let path = dir.join(RULES_DB_FILE);
+        let mut db = RuleDatabase::new(path.clone());
+        db.created_at = "2024-01-01T00:00:00+00:00".to_string();
+        db.save().unwrap();
+        db.save().unwrap();
+        (path, db.created_at)
+    }
+
+    #[test]
+    fn load_recovers_from_backup_when_the_database_is_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, created_at) = saved_database(dir.path());
+        fs::write(&path, "{\"rules\": [").unwrap();
+
+        let db = RuleDatabase::load(path.clone()).unwrap();
+        assert_eq!(db.created_at, created_at);
+        assert_eq!(db.path, path);
+        // The unreadable copy is kept for inspection, not rotated over the backup
+        assert_eq!(fs::read_to_string(storage::corrupt_path(&path)).unwrap(), "{\"rules\": [");
+        assert!(storage::backup_path(&path).exists());
+    }
+
+    #[test]
+    fn load_recovers_from_backup_when_the_database_is_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, created_at) = saved_database(dir.path());
+        fs::remove_file(&path).unwrap();
+
+        let db = RuleDatabase::load(path.clone()).unwrap();
+        assert_eq!(db.created_at, created_at);
+        assert!(!storage::corrupt_path(&path).exists());
+    }
+
+    #[test]
+    fn load_starts_empty_only_when_there_is_nothing_to_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(RULES_DB_FILE);
+        assert!(RuleDatabase::load(path.clone()).unwrap().rules.is_empty());
+
+        // An unreadable database without a backup must not be replaced
+        fs::write(&path, "not json").unwrap();
+        assert!(RuleDatabase::load(path.clone()).is_err());
+        fs::write(storage::backup_path(&path), "not json either").unwrap();
+        assert!(RuleDatabase::load(path.clone()).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not json");
+    }
+
+    #[tokio::test]
+    async fn profile_update_retries_rules_an_earlier_update_left_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path());
+        let server = MockServer::start().await;
+        connect(&state, &server).await;
+        let old_devices = vec!["aa:bb:cc:00:00:01".to_string()];
+        {
+            let mut rules_db = state.rules_db.lock().await;
+            rules_db.add_profile(Profile {
+                id: "emma".to_string(),
+                name: "Emma".to_string(),
+                avatar: None,
+                devices: old_devices.clone(),
+            }).unwrap();
+            for id in ["1", "2"] {
+                rules_db.rules.push(rule(serde_json::json!({
+                    "id": id, "profile": "emma", "devices": old_devices, "unifi_rule_id": format!("t{}", id)
+                })));
+            }
+        }
+
+        let rules_url = "/proxy/network/v2/api/site/default/trafficrules";
+        Mock::given(method("GET"))
+            .and(path(rules_url))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([traffic_rule("t1"), traffic_rule("t2")])))
+            .mount(&server)
+            .await;
+        // Rule 1 is only updated once; the second update leaves it alone
+        Mock::given(method("PUT"))
+            .and(path(format!("{}/t1", rules_url)))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path(format!("{}/t2", rules_url)))
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path(format!("{}/t2", rules_url)))
+            .and(body_partial_json(serde_json::json!({ "target_devices": [{ "client_mac": "aa:bb:cc:00:00:02" }] })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let request = || Json(ProfileRequest {
+            id: Some("emma".to_string()),
+            name: "Emma".to_string(),
+            avatar: None,
+            devices: vec!["AA:BB:CC:00:00:02".to_string()],
+        });
+        let new_devices = vec!["aa:bb:cc:00:00:02".to_string()];
+
+        let error = update_profile(State(state.clone()), Extension(parent()), request()).await.unwrap_err();
+        assert_eq!(error.status, StatusCode::BAD_GATEWAY);
+        {
+            let rules_db = state.rules_db.lock().await;
+            assert_eq!(rules_db.get_profile("emma").unwrap().devices, old_devices);
+            assert_eq!(rules_db.rules[0].devices, new_devices);
+            assert_eq!(rules_db.rules[1].devices, old_devices);
+        }
+
+        assert!(update_profile(State(state.clone()), Extension(parent()), request()).await.unwrap().success);
+        let rules_db = state.rules_db.lock().await;
+        assert_eq!(rules_db.get_profile("emma").unwrap().devices, new_devices);
+        assert!(rules_db.rules.iter().all(|r| r.devices == new_devices));
+    }
+
+    fn access_request(id: &str, rule_id: &str) -> AccessRequest {
+        AccessRequest {
+            id: id.to_string(),
+            rule_id: rule_id.to_string(),
+            mac: "aa:bb:cc:00:00:01".to_string(),
+            device_name: None,
+            apps: vec!["youtube".to_string()],
+            reason: "homework".to_string(),
+            minutes: 30,
+            status: AccessStatus::Pending,
+            requested_at: chrono::Utc::now().to_rfc3339(),
+            decided_by: None,
+            decided_at: None,
+            granted_minutes: None,
+            note: None,
+        }
+    }
+
+    fn decision() -> Json<AccessDecision> {
+        Json(AccessDecision { minutes: None, note: None })
+    }
+
+    #[tokio::test]
+    async fn access_request_is_answered_only_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path());
+        {
+            let mut rules_db = state.rules_db.lock().await;
+            // Not on the controller, so granting needs no mock
+            rules_db.rules.push(rule(serde_json::json!({ "id": "1" })));
+            rules_db.access_requests.push(access_request("a", "1"));
+            rules_db.access_requests.push(access_request("b", "1"));
+        }
+
+        // Two approvals at once grant the time once
+        let (first, second) = tokio::join!(
+            approve_access_request(State(state.clone()), Extension(parent()), UrlPath("a".to_string()), decision()),
+            approve_access_request(State(state.clone()), Extension(parent()), UrlPath("a".to_string()), decision()),
+        );
+        assert_eq!(first.is_ok() as u8 + second.is_ok() as u8, 1);
+        let error = first.err().or(second.err()).unwrap();
+        assert_eq!(error.status, StatusCode::CONFLICT);
+        assert_eq!(state.rules_db.lock().await.grants.len(), 1);
+
+        // An approval after a deny doesn't overwrite it
+        let denied = deny_access_request(State(state.clone()), Extension(parent()), UrlPath("b".to_string()), decision()).await.unwrap();
+        assert_eq!(denied.status, AccessStatus::Denied);
+        let error = decide_access_request(&state, &parent(), "b", AccessStatus::Approved, Some(30), None).await.unwrap_err();
+        assert_eq!(error.status, StatusCode::CONFLICT);
+        let rules_db = state.rules_db.lock().await;
+        assert_eq!(rules_db.access_requests[1].status, AccessStatus::Denied);
+    }
+
+    #[tokio::test]
+    async fn failed_approval_goes_back_to_pending() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path());
+        {
+            let mut rules_db = state.rules_db.lock().await;
+            rules_db.rules.push(rule(serde_json::json!({ "id": "1", "status": "disabled" })));
+            rules_db.access_requests.push(access_request("a", "1"));
+        }
+
+        let error = approve_access_request(State(state.clone()), Extension(parent()), UrlPath("a".to_string()), decision())
+            .await
+            .unwrap_err();
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        let rules_db = state.rules_db.lock().await;
+        assert_eq!(rules_db.access_requests[0].status, AccessStatus::Pending);
+        assert!(rules_db.access_requests[0].decided_by.is_none());
+    }
+
+    #[tokio::test]
+    async fn unblocking_does_not_hold_the_rules_lock_while_the_controller_answers() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path());
+        let server = MockServer::start().await;
+        connect(&state, &server).await;
+        state.rules_db.lock().await.rules.push(rule(serde_json::json!({ "id": "1", "unifi_rule_id": "t1" })));
+        Mock::given(method("DELETE"))
+            .and(path("/proxy/network/v2/api/site/default/trafficrules/t1"))
+            .respond_with(ResponseTemplate::new(200).set_delay(std::time::Duration::from_millis(500)))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let unblock = tokio::spawn({
+            let state = state.clone();
+            async move { remove_block_rule(&state, &parent(), "1").await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let rules_db = tokio::time::timeout(std::time::Duration::from_millis(100), state.rules_db.lock())
+            .await
+            .expect("rules database locked during the controller call");
+        assert_eq!(rules_db.rules.len(), 1);
+        drop(rules_db);
+
+        unblock.await.unwrap().unwrap();
+        assert!(state.rules_db.lock().await.rules.is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_rule_edit_is_reverted_and_leaves_the_rule_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path());
+        let server = MockServer::start().await;
+        connect(&state, &server).await;
+        let old_devices = vec!["aa:bb:cc:00:00:01".to_string()];
+        state.rules_db.lock().await.rules.push(rule(serde_json::json!({ "devices": old_devices, "unifi_rule_id": "t1" })));
+
+        let rule_url = "/proxy/network/v2/api/site/default/trafficrules/t1";
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/v2/api/site/default/trafficrules"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([traffic_rule("t1")])))
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path(rule_url))
+            .and(body_partial_json(serde_json::json!({ "target_devices": [{ "client_mac": "aa:bb:cc:00:00:02" }] })))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+        // The controller is put back the way it was
+        Mock::given(method("PUT"))
+            .and(path(rule_url))
+            .and(body_partial_json(serde_json::json!({ "target_devices": [{ "client_mac": "aa:bb:cc:00:00:01" }] })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let update = RuleUpdate {
+            apps: None,
+            devices: Some(vec!["AA:BB:CC:00:00:02".to_string()]),
+            duration: None,
+            end_time: None,
+        };
+        let error = patch_rule(State(state.clone()), Extension(parent()), UrlPath("1".to_string()), Json(update))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.status, StatusCode::BAD_GATEWAY);
+        assert_eq!(state.rules_db.lock().await.rules[0].devices, old_devices);
+    }
+
+    #[tokio::test]
+    async fn disabling_and_enabling_switch_the_rule_on_the_controller() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path());
+        let server = MockServer::start().await;
+        connect(&state, &server).await;
+        state.rules_db.lock().await.rules.push(rule(serde_json::json!({ "unifi_rule_id": "t1", "unifi_enabled": true })));
+
+        let rule_url = "/proxy/network/v2/api/site/default/trafficrules/t1";
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/v2/api/site/default/trafficrules"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([traffic_rule("t1")])))
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path(rule_url))
+            .and(body_partial_json(serde_json::json!({ "enabled": false })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path(rule_url))
+            .and(body_partial_json(serde_json::json!({ "enabled": true })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let disabled = disable_rule(State(state.clone()), Extension(parent()), UrlPath("1".to_string())).await.unwrap();
+        assert_eq!(disabled.status, "disabled");
+        assert_eq!(disabled.unifi_enabled, Some(false));
+        assert_eq!(state.rules_db.lock().await.rules[0].status, "disabled");
+
+        let enabled = enable_rule(State(state.clone()), Extension(parent()), UrlPath("1".to_string())).await.unwrap();
+        assert_eq!(enabled.status, "active");
+        assert_eq!(enabled.unifi_enabled, Some(true));
+    }
+
+    #[tokio::test]
+    async fn disabling_fails_without_changing_the_rule_when_the_controller_does() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path());
+        state.rules_db.lock().await.rules.push(rule(serde_json::json!({ "unifi_rule_id": "t1", "unifi_enabled": true })));
+
+        // Not connected is something the caller can fix by logging in
+        let error = disable_rule(State(state.clone()), Extension(parent()), UrlPath("1".to_string())).await.err().unwrap();
+        assert_eq!(error.status, StatusCode::CONFLICT);
+
+        let server = MockServer::start().await;
+        connect(&state, &server).await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/v2/api/site/default/trafficrules"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+        let error = disable_rule(State(state.clone()), Extension(parent()), UrlPath("1".to_string())).await.err().unwrap();
+        assert_eq!(error.status, StatusCode::BAD_GATEWAY);
+
+        let rules_db = state.rules_db.lock().await;
+        assert_eq!(rules_db.rules[0].status, "active");
+        assert_eq!(rules_db.rules[0].unifi_enabled, Some(true));
+    }
+
+    #[tokio::test]
+    async fn unblocking_reports_a_rule_removal_that_could_not_be_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path());
+        {
+            let mut rules_db = state.rules_db.lock().await;
+            rules_db.rules.push(rule(serde_json::json!({})));
+            // Nothing can be written into a directory that doesn't exist
+            rules_db.path = dir.path().join("missing").join(RULES_DB_FILE);
+        }
+
+        let error = remove_block_rule(&state, &parent(), "1").await.unwrap_err();
+        assert_eq!(error.status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn cleanup_unpauses_devices_whose_pause_is_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path());
+        let server = MockServer::start().await;
+        connect(&state, &server).await;
+        {
+            let mut rules_db = state.rules_db.lock().await;
+            for (id, mac) in [("1", "aa:bb:cc:00:00:01"), ("2", "aa:bb:cc:00:00:02")] {
+                rules_db.rules.push(rule(serde_json::json!({
+                    "id": id, "apps": [], "pause": true, "devices": [mac], "unifi_paused_macs": [mac], "strategy": "firewall_rules"
+                })));
+            }
+            rules_db.save().unwrap();
+            // Forgotten without being unpaused, as when unblocking everything fails halfway
+            rules_db.rules.retain(|r| r.id != "1");
+        }
+
+        for list in ["rest/firewallrule", "rest/firewallgroup"] {
+            Mock::given(method("GET"))
+                .and(path(format!("/proxy/network/api/s/default/{}", list)))
+                .respond_with(envelope(serde_json::json!([])))
+                .mount(&server)
+                .await;
+        }
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/v2/api/site/default/trafficrules"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([])))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/proxy/network/api/s/default/cmd/stamgr"))
+            .and(body_partial_json(serde_json::json!({ "cmd": "unblock-sta", "mac": "aa:bb:cc:00:00:01" })))
+            .respond_with(envelope(serde_json::json!([])))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        assert_eq!(state.cleanup_orphaned_rules("default").await.unwrap(), 1);
+        assert_eq!(
+            state.rules_db.lock().await.paused_stations,
+            vec![PausedStation { site: "default".to_string(), mac: "aa:bb:cc:00:00:02".to_string() }]
+        );
+        // Nothing is left to unpause
+        assert_eq!(state.cleanup_orphaned_rules("default").await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn sync_keeps_stored_controller_rules_and_links_each_one_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path());
+        let server = MockServer::start().await;
+        connect(&state, &server).await;
+        {
+            let mut rules_db = state.rules_db.lock().await;
+            rules_db.rules.push(rule(serde_json::json!({ "id": "1", "unifi_rule_id": "t2" })));
+            rules_db.rules.push(rule(serde_json::json!({ "id": "2" })));
+            rules_db.rules.push(rule(serde_json::json!({ "id": "3" })));
+            rules_db.rules.push(rule(serde_json::json!({ "id": "4", "unifi_rule_id": "gone" })));
+        }
+
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/s/default/rest/firewallrule"))
+            .respond_with(envelope(serde_json::json!([])))
+            .mount(&server)
+            .await;
+        // Every rule blocks youtube, so the names alone can't tell them apart
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/v2/api/site/default/trafficrules"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([traffic_rule("t1"), traffic_rule("t2")])))
+            .mount(&server)
+            .await;
+
+        state.sync_rules_with_unifi("default").await.unwrap();
+        let rules_db = state.rules_db.lock().await;
+        let linked: Vec<Option<&str>> = rules_db.rules.iter().map(|r| r.unifi_rule_id.as_deref()).collect();
+        assert_eq!(linked, [Some("t2"), Some("t1"), None, None]);
+    }
+
+    #[test]
+    fn ids_are_unique_even_within_a_millisecond() {
+        let ids: Vec<String> = (0..1000).map(|_| new_id()).collect();
+        let mut unique = ids.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), ids.len());
+        assert!(ids.iter().all(|id| id.parse::<i64>().is_ok()));
+    }
+
+    #[test]
+    fn timed_rules_stop_at_their_expiry() {
+        let created = chrono::DateTime::parse_from_rfc3339("2024-01-15T18:00:00Z").unwrap().with_timezone(&chrono::Utc);
+        let end = created + chrono::Duration::hours(2);
+        let timed = [
+            rule(serde_json::json!({ "rule_type": "duration", "duration": 2, "created": created.to_rfc3339() })),
+            rule(serde_json::json!({ "rule_type": "until", "end_time": end.to_rfc3339() })),
+        ];
+        let mut db = RuleDatabase::new(PathBuf::new());
+        for timed_rule in timed {
+            assert_eq!(timed_rule.expires_at(), Some(end));
+            let just_before = end - chrono::Duration::milliseconds(1);
+            assert!(timed_rule.should_enforce(just_before));
+            assert!(!timed_rule.should_enforce(end));
+
+            db.rules = vec![timed_rule];
+            assert!(db.expired_rules(just_before).is_empty());
+            assert_eq!(db.expired_rules(end).len(), 1);
+        }
+
+        let permanent = rule(serde_json::json!({}));
+        assert_eq!(permanent.expires_at(), None);
+        assert!(permanent.should_enforce(end + chrono::Duration::days(365)));
+        // An until rule without a readable end time never expires by itself
+        assert_eq!(rule(serde_json::json!({ "rule_type": "until", "end_time": "tonight" })).expires_at(), None);
+    }
+
+    fn site(name: &str) -> Site {
+        Site { name: name.to_string(), desc: String::new() }
+    }
+
+    #[tokio::test]
+    async fn sites_resolve_to_the_default_until_the_controller_lists_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path());
+        assert_eq!(state.resolve_site(None).await.unwrap(), "default");
+        assert_eq!(state.resolve_site(Some("  ")).await.unwrap(), "default");
+        // Nothing to check against before login
+        assert_eq!(state.resolve_site(Some("office")).await.unwrap(), "office");
+        assert_eq!(state.known_sites().await, ["default"]);
+    }
+
+    #[tokio::test]
+    async fn sites_are_checked_against_the_discovered_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path());
+        *state.sites.lock().await = vec![site("default"), site("office")];
+
+        assert_eq!(state.resolve_site(None).await.unwrap(), "default");
+        assert_eq!(state.resolve_site(Some(" office ")).await.unwrap(), "office");
+        assert_eq!(state.resolve_site(Some("garage")).await.unwrap_err(), "Unknown UniFi site: garage");
+
+        // Sites of stored rules are swept too, each once
+        state.rules_db.lock().await.rules.push(rule(serde_json::json!({ "id": "1", "site": "office" })));
+        state.rules_db.lock().await.rules.push(rule(serde_json::json!({ "id": "2", "site": "old" })));
+        assert_eq!(state.known_sites().await, ["default", "office", "old"]);
+    }
+
+    #[tokio::test]
+    async fn a_controller_with_one_site_is_used_without_naming_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path());
+        *state.sites.lock().await = vec![site("x7k2m9qp")];
+
+        assert_eq!(state.resolve_site(None).await.unwrap(), "x7k2m9qp");
+        assert!(state.resolve_site(Some("default")).await.is_err());
+        assert_eq!(state.known_sites().await, ["x7k2m9qp"]);
+    }
+
+    #[tokio::test]
+    async fn audit_log_is_read_newest_first_a_page_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path());
+        let dad = CurrentUser { username: "dad".to_string(), role: Role::CoParent };
+        for (user, action, rule_id) in [
+            (parent(), AuditAction::Create, "1"),
+            (dad.clone(), AuditAction::Create, "2"),
+            (parent(), AuditAction::Disable, "1"),
+            (dad.clone(), AuditAction::Unblock, "2"),
+            (parent(), AuditAction::Unblock, "1"),
+        ] {
+            state.audit(AuditEntry::new(&user, action).rule(rule_id, &rule_id)).await;
+        }
+        let query = |page, per_page, rule_id: Option<&str>, actor: Option<&str>, action| AuditQuery {
+            page,
+            per_page,
+            rule_id: rule_id.map(String::from),
+            actor: actor.map(String::from),
+            action,
+        };
+        let read = |query| get_audit_log(State(state.clone()), Extension(parent()), Query(query));
+        let actions = |page: &AuditPage| page.entries.iter().map(|e| e.action).collect::<Vec<_>>();
+
+        let page = read(query(None, None, None, None, None)).await.unwrap();
+        assert_eq!((page.page, page.per_page, page.total), (1, 50, 5));
+        assert_eq!(page.entries[0].action, AuditAction::Unblock);
+        assert_eq!(page.entries[4].action, AuditAction::Create);
+
+        let page = read(query(Some(2), Some(2), None, None, None)).await.unwrap();
+        assert_eq!(actions(&page), [AuditAction::Disable, AuditAction::Create]);
+        assert_eq!(page.total, 5);
+        let page = read(query(Some(4), Some(2), None, None, None)).await.unwrap();
+        assert!(page.entries.is_empty());
+
+        let page = read(query(None, None, Some("1"), None, None)).await.unwrap();
+        assert_eq!(actions(&page), [AuditAction::Unblock, AuditAction::Disable, AuditAction::Create]);
+        let page = read(query(None, None, None, Some("dad"), Some(AuditAction::Unblock))).await.unwrap();
+        assert_eq!(page.total, 1);
+        assert_eq!(page.entries[0].rule_id.as_deref(), Some("2"));
+
+        for invalid in [
+            query(Some(0), None, None, None, None),
+            query(None, Some(0), None, None, None),
+            query(None, Some(MAX_AUDIT_PAGE + 1), None, None, None),
+        ] {
+            assert_eq!(read(invalid).await.err().unwrap().status, StatusCode::BAD_REQUEST);
+        }
+        // Only parents may read it
+        let error = get_audit_log(State(state.clone()), Extension(dad), Query(query(None, None, None, None, None))).await.err().unwrap();
+        assert_eq!(error.status, StatusCode::FORBIDDEN);
+    }
+}
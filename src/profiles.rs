@@ -0,0 +1,52 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// A child, grouping the devices their blocks apply to
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+#[schema(example = json!({
+    "id": "emma",
+    "name": "Emma",
+    "avatar": "🦄",
+    "devices": ["aa:bb:cc:dd:ee:ff"]
+}))]
+pub struct Profile {
+    /// Unique identifier, derived from the name
+    pub id: String,
+    /// Display name
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Emoji or image URL shown next to the name
+    pub avatar: Option<String>,
+    #[serde(default)]
+    /// Lowercased MAC addresses of the child's devices
+    pub devices: Vec<String>,
+}
+
+// Identifier for a new profile: the name in lowercase with dashes
+pub fn profile_id(name: &str) -> String {
+    let id: String = name
+        .trim()
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '-' })
+        .collect();
+    id.split('-').filter(|part| !part.is_empty()).collect::<Vec<_>>().join("-")
+}
+
+/// Lowercase, validate and de-duplicate a list of MAC addresses
+pub fn normalize_macs(devices: &[String]) -> Result<Vec<String>, String> {
+    let mut macs: Vec<String> = Vec::new();
+    for device in devices {
+        let mac = device.trim().to_lowercase();
+        let valid = mac.len() == 17
+            && mac.split(':').count() == 6
+            && mac.split(':').all(|part| part.len() == 2 && part.chars().all(|c| c.is_ascii_hexdigit()));
+        if !valid {
+            return Err(format!("Invalid MAC address: {}", device));
+        }
+        if !macs.contains(&mac) {
+            macs.push(mac);
+        }
+    }
+    Ok(macs)
+}
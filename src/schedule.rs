@@ -0,0 +1,179 @@
+use chrono::{DateTime, Datelike, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+// Timezone used when a schedule doesn't name one
+pub fn default_timezone() -> String {
+    std::env::var("TZ").unwrap_or_else(|_| "UTC".to_string())
+}
+
+/// Recurring weekly time window during which a rule is enforced
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+#[schema(example = json!({
+    "days": ["mon", "tue", "wed", "thu", "fri"],
+    "start": "15:00",
+    "end": "18:00",
+    "timezone": "America/New_York"
+}))]
+pub struct Schedule {
+    /// Days the window starts on (mon..sun). Empty means every day
+    #[serde(default)]
+    pub days: Vec<String>,
+    /// Local start time (HH:MM)
+    pub start: String,
+    /// Local end time (HH:MM). An end before the start spans midnight
+    pub end: String,
+    /// IANA timezone name (e.g. America/New_York)
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+}
+
+impl Schedule {
+    /// Built-in schedules offered by the UI
+    pub fn preset(name: &str, timezone: &str) -> Option<Schedule> {
+        let (days, start, end) = match name {
+            // 8 PM - 7 AM every night
+            "bedtime" => (vec![], "20:00", "07:00"),
+            // 3 PM - 6 PM on school days
+            "homework" => (vec!["mon", "tue", "wed", "thu", "fri"], "15:00", "18:00"),
+            _ => return None,
+        };
+        Some(Schedule {
+            days: days.into_iter().map(String::from).collect(),
+            start: start.to_string(),
+            end: end.to_string(),
+            timezone: timezone.to_string(),
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.timezone
+            .parse::<Tz>()
+            .map_err(|_| format!("Unknown timezone: {}", self.timezone))?;
+        parse_time(&self.start)?;
+        parse_time(&self.end)?;
+        for day in self.days.iter() {
+            parse_day(day)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the window covers the given instant
+    pub fn is_active_at(&self, now: DateTime<Utc>) -> bool {
+        let (tz, start, end) = match (
+            self.timezone.parse::<Tz>(),
+            parse_time(&self.start),
+            parse_time(&self.end),
+        ) {
+            (Ok(tz), Ok(start), Ok(end)) => (tz, start, end),
+            _ => return false,
+        };
+
+        let local = now.with_timezone(&tz);
+        let time = local.time();
+        let today = local.weekday();
+
+        if start < end {
+            self.runs_on(today) && time >= start && time < end
+        } else if start > end {
+            // Overnight window: the evening part belongs to today, the morning
+            // part to the window that started yesterday.
+            (self.runs_on(today) && time >= start) || (self.runs_on(today.pred()) && time < end)
+        } else {
+            // Identical start and end covers the whole day
+            self.runs_on(today)
+        }
+    }
+
+    fn runs_on(&self, day: Weekday) -> bool {
+        self.days.is_empty()
+            || self.days.iter().any(|d| parse_day(d).map(|d| d == day).unwrap_or(false))
+    }
+}
+
+pub fn parse_time(value: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(value, "%H:%M")
+        .map_err(|_| format!("Invalid time '{}', expected HH:MM", value))
+}
+
+fn parse_day(value: &str) -> Result<Weekday, String> {
+    value
+        .parse::<Weekday>()
+        .map_err(|_| format!("Invalid day '{}', expected mon..sun", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(days: &[&str], start: &str, end: &str, timezone: &str) -> Schedule {
+        Schedule {
+            days: days.iter().map(|d| d.to_string()).collect(),
+            start: start.to_string(),
+            end: end.to_string(),
+            timezone: timezone.to_string(),
+        }
+    }
+
+    fn at(utc: &str) -> DateTime<Utc> {
+        utc.parse().unwrap()
+    }
+
+    #[test]
+    fn overnight_window_belongs_to_the_day_it_starts() {
+        // Friday nights only; 2024-01-05 is a Friday
+        let friday_night = schedule(&["fri"], "22:00", "02:00", "UTC");
+        assert!(!friday_night.is_active_at(at("2024-01-05T21:59:00Z")));
+        assert!(friday_night.is_active_at(at("2024-01-05T22:00:00Z")));
+        assert!(friday_night.is_active_at(at("2024-01-06T00:00:00Z")));
+        assert!(friday_night.is_active_at(at("2024-01-06T01:59:00Z")));
+        assert!(!friday_night.is_active_at(at("2024-01-06T02:00:00Z")));
+        // Saturday evening isn't part of it, nor is Friday morning
+        assert!(!friday_night.is_active_at(at("2024-01-06T23:00:00Z")));
+        assert!(!friday_night.is_active_at(at("2024-01-05T01:00:00Z")));
+    }
+
+    #[test]
+    fn window_follows_local_time_across_dst_changes() {
+        // 2024-03-10: New York springs forward at 02:00 EST to 03:00 EDT
+        let homework = schedule(&[], "15:00", "18:00", "America/New_York");
+        assert!(!homework.is_active_at(at("2024-03-10T18:59:00Z")));
+        assert!(homework.is_active_at(at("2024-03-10T19:00:00Z")));
+        assert!(!homework.is_active_at(at("2024-03-10T22:00:00Z")));
+
+        // A night window spanning the change ends at 07:00 local, not an hour late
+        let bedtime = Schedule::preset("bedtime", "America/New_York").unwrap();
+        assert!(bedtime.is_active_at(at("2024-03-10T07:30:00Z"))); // 03:30 EDT
+        assert!(bedtime.is_active_at(at("2024-03-10T10:59:00Z")));
+        assert!(!bedtime.is_active_at(at("2024-03-10T11:00:00Z")));
+
+        // 2024-11-03: 01:00-02:00 happens twice when falling back, and both count
+        let small_hours = schedule(&[], "01:00", "02:00", "America/New_York");
+        assert!(small_hours.is_active_at(at("2024-11-03T05:30:00Z"))); // 01:30 EDT
+        assert!(small_hours.is_active_at(at("2024-11-03T06:30:00Z"))); // 01:30 EST
+        assert!(!small_hours.is_active_at(at("2024-11-03T07:00:00Z")));
+    }
+
+    #[test]
+    fn empty_day_set_means_every_day() {
+        let every_day = schedule(&[], "15:00", "18:00", "UTC");
+        // A full week starting Monday 2024-01-01
+        for day in 1..=7 {
+            let afternoon = at(&format!("2024-01-{:02}T16:00:00Z", day));
+            let morning = at(&format!("2024-01-{:02}T09:00:00Z", day));
+            assert!(every_day.is_active_at(afternoon), "{}", afternoon);
+            assert!(!every_day.is_active_at(morning), "{}", morning);
+        }
+
+        let all_day = schedule(&[], "00:00", "00:00", "UTC");
+        assert!(all_day.is_active_at(at("2024-01-03T12:00:00Z")));
+    }
+
+    #[test]
+    fn invalid_schedule_is_never_active() {
+        let broken = schedule(&[], "15:00", "18:00", "Mars/Olympus_Mons");
+        assert!(broken.validate().is_err());
+        assert!(!broken.is_active_at(at("2024-01-01T16:00:00Z")));
+    }
+}
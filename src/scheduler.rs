@@ -0,0 +1,421 @@
+use crate::access;
+use crate::audit::{AuditAction, AuditEntry};
+use crate::auth::{CurrentUser, Role};
+use crate::blocking::{self, BlockStrategy, DeviceGroup};
+use crate::budgets::{self, Budget};
+use crate::error::ApiError;
+use crate::unifi::{UnifiClient, UnifiError};
+use crate::{AppState, BlockRule};
+use log::{debug, info, warn};
+use std::time::Duration;
+
+// How often the scheduler wakes up to expire and re-evaluate rules
+pub const TICK_INTERVAL_SECS: u64 = 30;
+
+// Background task that lifts timed rules once their end time has passed and
+// switches scheduled rules on and off at their window boundaries
+pub async fn run(state: AppState) {
+    let mut interval = tokio::time::interval(Duration::from_secs(TICK_INTERVAL_SECS));
+    // A tick that runs late shouldn't be followed by a burst of catch-up ticks
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        expire_rules(&state).await;
+        end_grants(&state).await;
+        track_budgets(&state).await;
+        reconcile_enabled(&state).await;
+        refresh_device_groups(&state).await;
+        expire_access_requests(&state).await;
+    }
+}
+
+async fn expire_rules(state: &AppState) {
+    let now = chrono::Utc::now();
+    let expired = state.rules_db.lock().await.expired_rules(now);
+    let scheduler = system_user("scheduler");
+
+    for rule in expired {
+        // Remove the controller rule first; if that fails the local record is kept
+        // so the next tick retries instead of leaving an untracked block behind.
+        if rule.on_controller() {
+            if let Err(e) = state.remove_from_unifi(&rule).await {
+                warn!("⚠️ Could not expire rule {}: {} (will retry)", rule.id, e);
+                continue;
+            }
+        }
+
+        match state.rules_db.lock().await.remove_rule(&rule.id) {
+            Ok(None) => continue,
+            Ok(Some(_)) => {}
+            Err(e) => warn!("⚠️ Failed to record expiry of rule {}: {}", rule.id, e),
+        }
+        info!("⏰ Rule {} expired, unblocked apps: {}", rule.id, rule.apps.join(", "));
+        state.audit(AuditEntry::new(&scheduler, AuditAction::Expire).rule(&rule.id, &rule)).await;
+    }
+}
+
+// Forget extra time that has run out and put the blocks back
+async fn end_grants(state: &AppState) {
+    let now = chrono::Utc::now();
+    let (ended, rules) = {
+        let mut rules_db = state.rules_db.lock().await;
+        let ended = rules_db.take_expired_grants(now);
+        if ended.is_empty() {
+            return;
+        }
+        if let Err(e) = rules_db.save() {
+            warn!("⚠️ Failed to record ended grants: {}", e);
+        }
+        let rules: Vec<_> = ended.iter()
+            .map(|g| rules_db.get_rules().iter().find(|r| r.id == g.rule_id).map(|r| (r.clone(), rules_db.enforced(r, now))))
+            .collect();
+        (ended, rules)
+    };
+
+    // Done here rather than left to reconcile so the audit log can say
+    // whether the block came back; reconcile retries any that fail
+    for (grant, rule) in ended.iter().zip(rules) {
+        info!("⏱️ Extra time on rule {} is over", grant.rule_id);
+        let entry = AuditEntry::new(&system_user("scheduler"), AuditAction::EndGrant)
+            .rule(&grant.rule_id, grant)
+            .message(format!("Extra time granted by {} ran out", grant.granted_by));
+        let Some((rule, enabled)) = rule else {
+            state.audit(entry).await;
+            continue;
+        };
+        if !rule.on_controller() || rule.unifi_enabled == Some(enabled) {
+            state.audit(entry).await;
+            continue;
+        }
+        match state.set_unifi_rule_enabled(&rule, enabled).await {
+            Ok(()) => {
+                let mut rules_db = state.rules_db.lock().await;
+                if let Some(stored) = rules_db.rules.iter_mut().find(|r| r.id == rule.id) {
+                    stored.unifi_enabled = Some(enabled);
+                }
+                if let Err(e) = rules_db.save() {
+                    warn!("⚠️ Failed to record state of rule {}: {}", rule.id, e);
+                }
+                state.audit(entry).await;
+            }
+            Err(e) => {
+                warn!("⚠️ Could not restore rule {} after extra time: {} (will retry)", rule.id, e);
+                state.audit(entry.failed(format!("Failed to restore rule on UniFi: {} (will retry)", e))).await;
+            }
+        }
+    }
+}
+
+// Account recorded for changes the app makes by itself. It acts with a
+// parent's authority, since a parent set up what it carries out.
+fn system_user(name: &str) -> CurrentUser {
+    CurrentUser {
+        username: name.to_string(),
+        role: Role::Parent,
+    }
+}
+
+// Count time spent against daily budgets, and block once one is used up
+async fn track_budgets(state: &AppState) {
+    let now = chrono::Utc::now();
+    let budgets: Vec<(Budget, Vec<String>)> = {
+        let mut rules_db = state.rules_db.lock().await;
+        let mut new_day = false;
+        for budget in rules_db.budgets.iter_mut() {
+            if budget.roll_over(now) {
+                info!("🌅 Budget {} of {} starts over", budget.id, budget.profile);
+                new_day = true;
+            }
+        }
+        if new_day {
+            if let Err(e) = rules_db.save() {
+                warn!("⚠️ Failed to record budget reset: {}", e);
+            }
+        }
+
+        // Budgets already used up today have their block and nothing to count
+        rules_db.budgets
+            .iter()
+            .filter(|b| b.rule_id.is_none())
+            .filter_map(|b| rules_db.get_profile(&b.profile).map(|p| (b.clone(), p.devices.clone())))
+            .filter(|(_, devices)| !devices.is_empty())
+            .collect()
+    };
+    if budgets.is_empty() {
+        return;
+    }
+    let Ok(unifi) = state.unifi().await else {
+        return;
+    };
+
+    for (mut budget, devices) in budgets {
+        let in_use = match budget_in_use(state, &unifi, &mut budget, &devices).await {
+            Ok(in_use) => in_use,
+            Err(e) => {
+                warn!("⚠️ Could not check budget {}: {} (will retry)", budget.id, e);
+                continue;
+            }
+        };
+        // Ticks run late when the controller is slow, so charge the real time
+        let elapsed = budget.seconds_since_check(now, TICK_INTERVAL_SECS);
+        budget.last_checked = Some(now);
+        if in_use {
+            budget.used_seconds += elapsed;
+        }
+
+        if budget.remaining_seconds() == 0 {
+            match block_for_rest_of_day(state, &budget, now).await {
+                Ok(rule_id) => {
+                    info!("⌛ Budget {} of {} is used up, blocked by rule {}", budget.id, budget.profile, rule_id);
+                    budget.rule_id = Some(rule_id);
+                }
+                Err(e) => warn!("⚠️ Budget {} is used up but blocking failed: {} (will retry)", budget.id, e.message),
+            }
+        }
+
+        // Keep the new totals unless the budget was deleted or reset meanwhile
+        let mut rules_db = state.rules_db.lock().await;
+        if let Some(stored) = rules_db.budgets.iter_mut().find(|b| b.id == budget.id && b.day == budget.day) {
+            stored.last_bytes = budget.last_bytes;
+            stored.last_checked = budget.last_checked;
+            if in_use || budget.rule_id.is_some() {
+                stored.used_seconds = budget.used_seconds;
+                stored.rule_id = budget.rule_id.clone();
+                if let Err(e) = rules_db.save() {
+                    warn!("⚠️ Failed to record use of budget {}: {}", budget.id, e);
+                }
+            }
+        }
+    }
+}
+
+// Whether the profile's devices used the budget's apps since the last tick,
+// judged by their DPI byte counters growing, or for budgets without apps
+// whether any of the devices is online
+async fn budget_in_use(state: &AppState, unifi: &UnifiClient, budget: &mut Budget, devices: &[String]) -> Result<bool, UnifiError> {
+    if budget.apps.is_empty() {
+        let clients = unifi.list_clients(&budget.site).await?;
+        return Ok(clients.iter().any(|c| devices.iter().any(|mac| c.mac.eq_ignore_ascii_case(mac))));
+    }
+
+    let app_ids: Vec<u64> = state.apps.lock().await
+        .resolve(&budget.apps)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|id| id.parse().ok())
+        .collect();
+    let total: u64 = unifi.list_client_app_traffic(&budget.site, devices).await?
+        .iter()
+        .filter(|client| devices.iter().any(|mac| client.mac.eq_ignore_ascii_case(mac)))
+        .flat_map(|client| client.by_app.iter())
+        .filter(|traffic| app_ids.contains(&traffic.dpi_id()))
+        .map(|traffic| traffic.rx_bytes + traffic.tx_bytes)
+        .sum();
+
+    // The first look after startup only takes a baseline, and counters that
+    // went down were reset by the controller
+    let in_use = matches!(budget.last_bytes, Some(last) if total >= last + budgets::ACTIVE_BYTES_PER_TICK);
+    budget.last_bytes = Some(total);
+    Ok(in_use)
+}
+
+// Block a used-up budget's apps, or the internet for budgets without apps,
+// with an until rule that ends at the next reset
+async fn block_for_rest_of_day(state: &AppState, budget: &Budget, now: chrono::DateTime<chrono::Utc>) -> Result<String, ApiError> {
+    let (_, next_reset) = budget.day_bounds(now)
+        .ok_or_else(|| ApiError::bad_request("Budget has an invalid reset time or timezone"))?;
+    let rule_id = format!("budget-{}-{}", budget.id, budget.day);
+
+    // Created on an earlier tick that failed to record it
+    if state.rules_db.lock().await.get_rules().iter().any(|r| r.id == rule_id) {
+        return Ok(rule_id);
+    }
+
+    let rule = BlockRule {
+        id: rule_id,
+        apps: budget.apps.clone(),
+        domains: vec![],
+        pause: budget.apps.is_empty(),
+        rule_type: "until".to_string(),
+        devices: vec![],
+        site: Some(budget.site.clone()),
+        status: String::new(),
+        created: String::new(),
+        duration: None,
+        end_time: Some(next_reset.to_rfc3339()),
+        schedule_type: None,
+        schedule: None,
+        timezone: None,
+        strategy: None,
+        profile: Some(budget.profile.clone()),
+    };
+    crate::add_block_rule(state, &system_user("budget"), rule).await.map(|rule| rule.id)
+}
+
+// Push the enabled flag to any UniFi rule whose desired state has changed
+async fn reconcile_enabled(state: &AppState) {
+    let now = chrono::Utc::now();
+    let pending: Vec<_> = {
+        let rules_db = state.rules_db.lock().await;
+        rules_db.get_rules()
+            .iter()
+            .map(|r| (r, rules_db.enforced(r, now)))
+            .filter(|(r, enabled)| r.on_controller() && r.unifi_enabled != Some(*enabled))
+            .map(|(r, enabled)| (r.clone(), enabled))
+            .collect()
+    };
+
+    for (rule, enabled) in pending {
+        if let Err(e) = state.set_unifi_rule_enabled(&rule, enabled).await {
+            warn!("⚠️ Could not update rule {}: {} (will retry)", rule.id, e);
+            continue;
+        }
+
+        let mut rules_db = state.rules_db.lock().await;
+        if let Some(stored) = rules_db.rules.iter_mut().find(|r| r.id == rule.id) {
+            stored.unifi_enabled = Some(enabled);
+        }
+        if let Err(e) = rules_db.save() {
+            warn!("⚠️ Failed to record state of rule {}: {}", rule.id, e);
+        }
+        info!("{} Rule {} {} on controller", if enabled { "🔒" } else { "🔓" }, rule.id,
+            if enabled { "enabled" } else { "disabled" });
+    }
+}
+
+// Keep the address groups of firewall rules on the devices' current addresses
+async fn refresh_device_groups(state: &AppState) {
+    let mut by_site: Vec<(String, Vec<DeviceGroup>)> = Vec::new();
+    for rule in state.rules_db.lock().await.get_rules() {
+        let Some(ref group_id) = rule.unifi_group_id else {
+            continue;
+        };
+        if rule.strategy != BlockStrategy::FirewallRules {
+            continue;
+        }
+        let entry = (group_id.clone(), rule.devices.clone());
+        match by_site.iter_mut().find(|(site, _)| *site == rule.site) {
+            Some((_, groups)) => groups.push(entry),
+            None => by_site.push((rule.site.clone(), vec![entry])),
+        }
+    }
+    if by_site.is_empty() {
+        return;
+    }
+    let Ok(unifi) = state.unifi().await else {
+        return;
+    };
+
+    for (site, groups) in by_site {
+        match blocking::refresh_device_groups(&unifi, &site, &groups).await {
+            Ok(0) => {}
+            Ok(changed) => debug!("🔁 Updated {} device groups on site {}", changed, site),
+            Err(e) => warn!("⚠️ Could not refresh device groups on site {}: {} (will retry)", site, e),
+        }
+    }
+}
+
+// Forget children's requests that were answered a while ago
+async fn expire_access_requests(state: &AppState) {
+    let mut rules_db = state.rules_db.lock().await;
+    let expired = access::expire_decided(&mut rules_db.access_requests, chrono::Utc::now());
+    if expired == 0 {
+        return;
+    }
+    match rules_db.save() {
+        Ok(()) => debug!("🧹 Dropped {} old access requests", expired),
+        Err(e) => warn!("⚠️ Failed to record dropped access requests: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{connect, parent, rule, test_state, traffic_rule};
+    use wiremock::matchers::{body_partial_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn extra_time_lifts_the_block_until_the_grant_runs_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path());
+        let server = MockServer::start().await;
+        connect(&state, &server).await;
+        state.rules_db.lock().await.rules.push(rule(serde_json::json!({ "unifi_rule_id": "t1", "unifi_enabled": true })));
+
+        let rule_url = "/proxy/network/v2/api/site/default/trafficrules/t1";
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/v2/api/site/default/trafficrules"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([traffic_rule("t1")])))
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path(rule_url))
+            .and(body_partial_json(serde_json::json!({ "enabled": false })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path(rule_url))
+            .and(body_partial_json(serde_json::json!({ "enabled": true })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let granted = crate::grant_extra_time(&state, &parent(), Some("1"), None, 30).await.unwrap();
+        assert_eq!(granted.len(), 1);
+        {
+            let rules_db = state.rules_db.lock().await;
+            assert_eq!(rules_db.grants, granted);
+            assert_eq!(rules_db.rules[0].unifi_enabled, Some(false));
+        }
+
+        // Still running, so nothing ends yet
+        end_grants(&state).await;
+        assert_eq!(state.rules_db.lock().await.grants, granted);
+
+        state.rules_db.lock().await.grants[0].until = (chrono::Utc::now() - chrono::Duration::minutes(1)).to_rfc3339();
+
+        end_grants(&state).await;
+        let rules_db = state.rules_db.lock().await;
+        assert!(rules_db.grants.is_empty());
+        assert_eq!(rules_db.rules[0].unifi_enabled, Some(true));
+    }
+
+    #[tokio::test]
+    async fn expired_rules_are_removed_from_the_controller_and_forgotten() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path());
+        let server = MockServer::start().await;
+        connect(&state, &server).await;
+        let past = (chrono::Utc::now() - chrono::Duration::minutes(1)).to_rfc3339();
+        let future = (chrono::Utc::now() + chrono::Duration::hours(1)).to_rfc3339();
+        {
+            let mut rules_db = state.rules_db.lock().await;
+            rules_db.rules.push(rule(serde_json::json!({ "id": "1", "rule_type": "until", "end_time": past, "unifi_rule_id": "t1" })));
+            rules_db.rules.push(rule(serde_json::json!({ "id": "2", "rule_type": "until", "end_time": past, "unifi_rule_id": "t2" })));
+            rules_db.rules.push(rule(serde_json::json!({ "id": "3", "rule_type": "until", "end_time": future, "unifi_rule_id": "t3" })));
+            rules_db.rules.push(rule(serde_json::json!({ "id": "4", "rule_type": "until", "end_time": past })));
+        }
+
+        let rules_url = "/proxy/network/v2/api/site/default/trafficrules";
+        Mock::given(method("DELETE"))
+            .and(path(format!("{}/t1", rules_url)))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path(format!("{}/t2", rules_url)))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+
+        expire_rules(&state).await;
+        // Rule 2 is kept so the next tick retries removing it
+        let remaining: Vec<String> = state.rules_db.lock().await.rules.iter().map(|r| r.id.clone()).collect();
+        assert_eq!(remaining, ["2", "3"]);
+    }
+}
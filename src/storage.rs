@@ -0,0 +1,124 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// Previous good copy of a file, kept by `write_with_backup`
+pub fn backup_path(path: &Path) -> PathBuf {
+    with_suffix(path, "bak")
+}
+
+// Where an unreadable file is moved so it can be inspected later
+pub fn corrupt_path(path: &Path) -> PathBuf {
+    with_suffix(path, "corrupt")
+}
+
+/// Replace `path` so that readers see either the old or the new contents,
+/// never a truncated mix, even if the process dies mid-write.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp = write_temp(path, contents)?;
+    if let Err(e) = fs::rename(&tmp, path) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    sync_dir(path);
+    Ok(())
+}
+
+/// Like `write_atomic`, but the current file is first rotated to `.bak`.
+/// A crash between the two renames leaves only the backup, which loaders
+/// fall back to.
+pub fn write_with_backup(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp = write_temp(path, contents)?;
+    if path.exists() {
+        if let Err(e) = fs::rename(path, backup_path(path)) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+    }
+    if let Err(e) = fs::rename(&tmp, path) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    sync_dir(path);
+    Ok(())
+}
+
+// Write and fsync a temp file next to `path`, so the rename stays on one filesystem
+fn write_temp(path: &Path, contents: &[u8]) -> io::Result<PathBuf> {
+    let tmp = with_suffix(path, "tmp");
+    let result = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        });
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    Ok(tmp)
+}
+
+// Persist the renames themselves. Directories can't be opened on every
+// platform, so failing to do so is not an error.
+fn sync_dir(path: &Path) {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn atomic_write_replaces_contents_without_leaving_a_temp_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+
+        write_atomic(&path, b"first").unwrap();
+        write_atomic(&path, b"second").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+        assert!(!with_suffix(&path, "tmp").exists());
+        assert!(!backup_path(&path).exists());
+    }
+
+    #[test]
+    fn failed_atomic_write_removes_its_temp_file() {
+        let dir = tempfile::tempdir().unwrap();
+        // A directory can't be replaced by a file, so the rename fails
+        let path = dir.path().join("state.json");
+        fs::create_dir(&path).unwrap();
+
+        assert!(write_atomic(&path, b"contents").is_err());
+        assert!(!with_suffix(&path, "tmp").exists());
+        assert!(path.is_dir());
+    }
+
+    #[test]
+    fn write_with_backup_keeps_the_previous_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+
+        write_with_backup(&path, b"first").unwrap();
+        assert!(!backup_path(&path).exists());
+        write_with_backup(&path, b"second").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+        assert_eq!(fs::read_to_string(backup_path(&path)).unwrap(), "first");
+        assert!(!with_suffix(&path, "tmp").exists());
+    }
+}
@@ -0,0 +1,976 @@
+use log::info;
+use reqwest::{header, Client, Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+// UniFi OS hands out a CSRF token at login that must accompany every
+// mutating request, and rotates it through the "updated" header.
+const CSRF_HEADER: &str = "x-csrf-token";
+const UPDATED_CSRF_HEADER: &str = "x-updated-csrf-token";
+// Header carrying API keys issued by newer UniFi Network versions
+const API_KEY_HEADER: &str = "x-api-key";
+
+// Site every controller starts out with, used when no site is chosen
+pub const DEFAULT_SITE: &str = "default";
+
+#[derive(Debug)]
+pub enum UnifiError {
+    /// No controller session has been established yet
+    NotAuthenticated,
+    /// The controller rejected the credentials
+    LoginFailed(StatusCode),
+    /// The session cookie is no longer accepted
+    SessionExpired,
+    /// The request never got a response
+    Request(reqwest::Error),
+    /// The controller answered with a non-success status
+    Status(StatusCode, String),
+    /// The response body wasn't what we expected
+    InvalidResponse(String),
+    /// A successful response whose envelope reports an error
+    Api(String),
+}
+
+impl fmt::Display for UnifiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnifiError::NotAuthenticated => write!(f, "Not logged in to UniFi"),
+            UnifiError::LoginFailed(status) => write!(f, "Authentication failed: {}", status),
+            UnifiError::SessionExpired => write!(f, "UniFi session expired"),
+            UnifiError::Request(e) => write!(f, "Connection failed: {}", e),
+            UnifiError::Status(status, body) if body.is_empty() => write!(f, "HTTP {}", status),
+            UnifiError::Status(status, body) => write!(f, "HTTP {} - {}", status, body),
+            UnifiError::InvalidResponse(msg) => write!(f, "Invalid UniFi response: {}", msg),
+            UnifiError::Api(msg) => write!(f, "UniFi error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for UnifiError {}
+
+impl From<reqwest::Error> for UnifiError {
+    fn from(e: reqwest::Error) -> Self {
+        UnifiError::Request(e)
+    }
+}
+
+// Envelope wrapping every classic controller API response
+#[derive(Deserialize)]
+struct ApiEnvelope<T> {
+    #[serde(default)]
+    meta: ApiMeta,
+    #[serde(default = "Vec::new")]
+    data: Vec<T>,
+}
+
+// `rc` is "ok" or "error", with an `api.err.*` code in `msg` for errors
+#[derive(Deserialize, Default)]
+struct ApiMeta {
+    #[serde(default)]
+    rc: String,
+    msg: Option<String>,
+}
+
+impl ApiMeta {
+    fn error(&self) -> Option<&str> {
+        (self.rc == "error").then(|| self.msg.as_deref().unwrap_or("unknown error"))
+    }
+}
+
+/// Firewall rule as stored by the controller (`rest/firewallrule`)
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FirewallRule {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub name: String,
+    pub ruleset: String,
+    pub rule_index: u32,
+    pub action: String,
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub protocol_match_excepted: bool,
+    #[serde(default)]
+    pub logging: bool,
+    #[serde(default)]
+    pub state_established: bool,
+    #[serde(default)]
+    pub state_invalid: bool,
+    #[serde(default)]
+    pub state_new: bool,
+    #[serde(default)]
+    pub state_related: bool,
+    #[serde(default)]
+    pub ipsec: String,
+    #[serde(default)]
+    pub src_firewallgroup_ids: Vec<String>,
+    #[serde(default)]
+    pub src_mac_address: String,
+    #[serde(default)]
+    pub src_address: String,
+    #[serde(default)]
+    pub src_port: String,
+    #[serde(default)]
+    pub dst_firewallgroup_ids: Vec<String>,
+    #[serde(default)]
+    pub dst_address: String,
+    #[serde(default)]
+    pub dst_port: String,
+    #[serde(default)]
+    pub icmp_typename: String,
+    #[serde(default)]
+    pub app_category_ids: Vec<String>,
+    // Controller fields we don't model, kept so updates round-trip them
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Firewall group as stored by the controller (`rest/firewallgroup`)
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FirewallGroup {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub name: String,
+    pub group_type: String,
+    #[serde(default)]
+    pub group_members: Vec<String>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Traffic rule managed through the v2 API (`trafficrules`)
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TrafficRule {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub description: String,
+    /// BLOCK or ALLOW
+    pub action: String,
+    /// What the rule matches on: APP, DOMAIN, INTERNET, ...
+    pub matching_target: String,
+    #[serde(default)]
+    pub app_ids: Vec<u64>,
+    #[serde(default)]
+    pub app_category_ids: Vec<u64>,
+    #[serde(default)]
+    pub target_devices: Vec<TargetDevice>,
+    #[serde(default)]
+    pub schedule: TrafficSchedule,
+    #[serde(default)]
+    pub enabled: bool,
+    // Controller fields we don't model, kept so updates round-trip them
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl TrafficRule {
+    /// Blocking rule with the boilerplate fields the controller insists on
+    pub fn block(description: String, matching_target: &str) -> Self {
+        let extra = serde_json::json!({
+            "bandwidth_limit": {
+                "download_limit_kbps": 1024,
+                "enabled": false,
+                "upload_limit_kbps": 1024
+            },
+            "domains": [],
+            "ip_addresses": [],
+            "ip_ranges": [],
+            "network_ids": [],
+            "regions": []
+        });
+        Self {
+            description,
+            action: "BLOCK".to_string(),
+            matching_target: matching_target.to_string(),
+            extra: extra.as_object().cloned().unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Device a traffic rule applies to
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TargetDevice {
+    /// ALL_CLIENTS, CLIENT or NETWORK
+    #[serde(rename = "type")]
+    pub target_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_mac: Option<String>,
+}
+
+impl TargetDevice {
+    pub fn all_clients() -> Self {
+        Self { target_type: "ALL_CLIENTS".to_string(), client_mac: None }
+    }
+
+    pub fn client(mac: &str) -> Self {
+        Self { target_type: "CLIENT".to_string(), client_mac: Some(mac.to_string()) }
+    }
+}
+
+/// When a traffic rule is in effect, in the controller's local time
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TrafficSchedule {
+    /// ALWAYS, EVERY_DAY, EVERY_WEEK or CUSTOM
+    pub mode: String,
+    #[serde(default)]
+    pub repeat_on_days: Vec<String>,
+    #[serde(default)]
+    pub time_all_day: bool,
+    #[serde(default)]
+    pub time_range_start: String,
+    #[serde(default)]
+    pub time_range_end: String,
+}
+
+impl Default for TrafficSchedule {
+    fn default() -> Self {
+        Self {
+            mode: "ALWAYS".to_string(),
+            repeat_on_days: vec![],
+            time_all_day: false,
+            time_range_start: String::new(),
+            time_range_end: String::new(),
+        }
+    }
+}
+
+/// Application known to the controller's DPI engine
+#[derive(Deserialize, Clone, Debug)]
+pub struct DpiApplication {
+    pub id: u64,
+    pub name: String,
+    /// Category the app belongs to, when `id` is only the app part of the DPI ID
+    #[serde(default, alias = "cat")]
+    pub category_id: Option<u64>,
+}
+
+impl DpiApplication {
+    /// Full DPI ID as used by rules: the category in the upper 16 bits
+    pub fn dpi_id(&self) -> u64 {
+        match self.category_id {
+            Some(category_id) if self.id < 1 << 16 => (category_id << 16) | self.id,
+            _ => self.id,
+        }
+    }
+}
+
+/// Bytes a client has sent through one DPI application (`stat/stadpi`)
+#[derive(Deserialize, Clone, Debug)]
+pub struct AppTraffic {
+    pub app: u64,
+    pub cat: u64,
+    #[serde(default)]
+    pub rx_bytes: u64,
+    #[serde(default)]
+    pub tx_bytes: u64,
+}
+
+impl AppTraffic {
+    /// Full DPI ID, as in the app catalog
+    pub fn dpi_id(&self) -> u64 {
+        (self.cat << 16) | self.app
+    }
+}
+
+/// DPI counters of one client, which only ever grow until the controller resets them
+#[derive(Deserialize, Clone, Debug)]
+pub struct ClientAppTraffic {
+    #[serde(default)]
+    pub mac: String,
+    #[serde(default)]
+    pub by_app: Vec<AppTraffic>,
+}
+
+/// DPI application category (Games, Media streaming services, ...)
+#[derive(Deserialize, Clone, Debug)]
+pub struct DpiCategory {
+    pub id: u64,
+    pub name: String,
+}
+
+/// Connected client station (`stat/sta`)
+#[derive(Deserialize, Clone, Debug)]
+pub struct ClientDevice {
+    #[serde(default)]
+    pub mac: String,
+    pub ip: Option<String>,
+    pub hostname: Option<String>,
+    pub name: Option<String>,
+    pub display_name: Option<String>,
+    pub oui: Option<String>,
+    pub manufacturer: Option<String>,
+}
+
+impl ClientDevice {
+    /// Best human-readable name the controller knows for the device
+    pub fn friendly_name(&self) -> Option<String> {
+        self.hostname.clone()
+            .or_else(|| self.name.clone())
+            .or_else(|| self.display_name.clone())
+    }
+
+    pub fn vendor(&self) -> Option<String> {
+        self.oui.clone().or_else(|| self.manufacturer.clone())
+    }
+}
+
+/// Site managed by the controller (`self/sites`)
+#[derive(Deserialize, Clone, Debug)]
+pub struct Site {
+    /// Short name used in API paths
+    pub name: String,
+    /// Display name shown in the UniFi UI
+    #[serde(default)]
+    pub desc: String,
+}
+
+// How the client proves its identity to the controller
+#[derive(Clone)]
+enum Auth {
+    /// Local admin login, traded for a session cookie
+    Password { username: String, password: String },
+    /// API key sent with every request, no session involved
+    ApiKey(String),
+}
+
+// Cookies and CSRF token of the current login, shared by all clones
+#[derive(Default)]
+struct Session {
+    cookies: String,
+    csrf_token: Option<String>,
+}
+
+/// Authenticated session against a UniFi controller
+#[derive(Clone)]
+pub struct UnifiClient {
+    http: Client,
+    base_url: String,
+    auth: Auth,
+    session: Arc<Mutex<Session>>,
+}
+
+impl UnifiClient {
+    /// Log in with local admin credentials and capture the session cookies
+    pub async fn login(
+        http: Client,
+        base_url: &str,
+        username: &str,
+        password: &str,
+    ) -> Result<Self, UnifiError> {
+        let client = Self {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            auth: Auth::Password {
+                username: username.to_string(),
+                password: password.to_string(),
+            },
+            session: Arc::new(Mutex::new(Session::default())),
+        };
+        client.authenticate().await?;
+        Ok(client)
+    }
+
+    /// Use an API key and check that the controller accepts it
+    pub async fn with_api_key(http: Client, base_url: &str, api_key: &str) -> Result<Self, UnifiError> {
+        let client = Self {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            auth: Auth::ApiKey(api_key.to_string()),
+            session: Arc::new(Mutex::new(Session::default())),
+        };
+        match client.list_sites().await {
+            Ok(_) => {
+                info!("✅ API key accepted by {}", client.base_url);
+                Ok(client)
+            }
+            Err(UnifiError::Status(status, _)) if status == StatusCode::FORBIDDEN => {
+                Err(UnifiError::LoginFailed(status))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // (Re)establish the session with the stored credentials
+    async fn authenticate(&self) -> Result<(), UnifiError> {
+        let (username, password) = match self.auth {
+            Auth::Password { ref username, ref password } => (username, password),
+            // A rejected API key can't be refreshed, so report it as a failed login
+            Auth::ApiKey(_) => return Err(UnifiError::LoginFailed(StatusCode::UNAUTHORIZED)),
+        };
+
+        let login_url = if points_at_network_app(&self.base_url) {
+            // User provided proxy/network URL - use traditional controller login
+            format!("{}/api/login", self.base_url)
+        } else {
+            // Regular UniFi OS URL - use the UniFi OS auth endpoint
+            format!("{}/api/auth/login", self.base_url)
+        };
+
+        let login_data = serde_json::json!({
+            "username": username,
+            "password": password
+        });
+
+        let response = self.http.post(&login_url).json(&login_data).send().await?;
+        if !response.status().is_success() {
+            return Err(UnifiError::LoginFailed(response.status()));
+        }
+
+        // Only the name=value part of each Set-Cookie belongs in the Cookie header
+        let cookies = response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .filter_map(|hv| hv.to_str().ok())
+            .filter_map(|cookie| cookie.split(';').next())
+            .collect::<Vec<_>>()
+            .join("; ");
+        let csrf_token = response
+            .headers()
+            .get(CSRF_HEADER)
+            .and_then(|hv| hv.to_str().ok())
+            .map(|s| s.to_string());
+
+        *self.session.lock().await = Session { cookies, csrf_token };
+        info!("✅ Login successful via {}", login_url);
+        Ok(())
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    // UniFi OS consoles serve the Network application behind /proxy/network
+    fn network_url(&self, path: &str) -> String {
+        if points_at_network_app(&self.base_url) {
+            format!("{}{}", self.base_url, path)
+        } else {
+            format!("{}/proxy/network{}", self.base_url, path)
+        }
+    }
+
+    fn site_url(&self, site: &str, path: &str) -> String {
+        self.network_url(&format!("/api/s/{}/{}", site, path))
+    }
+
+    // Classic controller API call, unwrapping the {"meta", "data"} envelope
+    async fn request<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        url: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<Vec<T>, UnifiError> {
+        let text = self.fetch(method, url, body).await?;
+        let envelope = serde_json::from_str::<ApiEnvelope<T>>(&text)
+            .map_err(|e| UnifiError::InvalidResponse(e.to_string()))?;
+        if let Some(error) = envelope.meta.error() {
+            return Err(UnifiError::Api(error.to_string()));
+        }
+        Ok(envelope.data)
+    }
+
+    // v2 API call; these endpoints return the payload without an envelope
+    async fn request_v2<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        url: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<T, UnifiError> {
+        let text = self.fetch(method, url, body).await?;
+        let text = if text.trim().is_empty() { "null" } else { text.as_str() };
+        serde_json::from_str::<T>(text).map_err(|e| UnifiError::InvalidResponse(e.to_string()))
+    }
+
+    // Send a request, logging in again and retrying once if the session expired
+    async fn fetch(
+        &self,
+        method: Method,
+        url: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<String, UnifiError> {
+        match self.send(method.clone(), url, body).await {
+            Err(UnifiError::SessionExpired) => {
+                info!("🔄 UniFi session expired, logging in again");
+                self.authenticate().await?;
+                self.send(method, url, body).await
+            }
+            result => result,
+        }
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        url: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<String, UnifiError> {
+        let mutating = method != Method::GET;
+        let mut request = match self.auth {
+            Auth::ApiKey(ref api_key) => self.http.request(method, url).header(API_KEY_HEADER, api_key),
+            Auth::Password { .. } => {
+                let session = self.session.lock().await;
+                let mut request = self.http.request(method, url).header(header::COOKIE, &session.cookies);
+                if let (true, Some(token)) = (mutating, &session.csrf_token) {
+                    request = request.header(CSRF_HEADER, token);
+                }
+                request
+            }
+        };
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+
+        let response = request.send().await?;
+        if let Some(token) = response.headers().get(UPDATED_CSRF_HEADER).and_then(|hv| hv.to_str().ok()) {
+            self.session.lock().await.csrf_token = Some(token.to_string());
+        }
+
+        let status = response.status();
+        if status == StatusCode::UNAUTHORIZED {
+            return Err(UnifiError::SessionExpired);
+        }
+        let text = response.text().await?;
+        if !status.is_success() {
+            // Classic controllers report an expired session in the body
+            if text.contains("api.err.LoginRequired") {
+                return Err(UnifiError::SessionExpired);
+            }
+            // Prefer the envelope's error code to the raw body
+            let message = serde_json::from_str::<ApiEnvelope<serde_json::Value>>(&text)
+                .ok()
+                .and_then(|envelope| envelope.meta.error().map(str::to_string))
+                .unwrap_or_else(|| text.chars().take(200).collect());
+            return Err(UnifiError::Status(status, message));
+        }
+        Ok(text)
+    }
+
+    async fn create<T: Serialize + DeserializeOwned>(&self, site: &str, path: &str, item: &T) -> Result<T, UnifiError> {
+        let body = serde_json::to_value(item).map_err(|e| UnifiError::InvalidResponse(e.to_string()))?;
+        self.request::<T>(Method::POST, &self.site_url(site, path), Some(&body))
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| UnifiError::InvalidResponse(format!("{} returned no data", path)))
+    }
+
+    async fn delete(&self, site: &str, path: &str) -> Result<(), UnifiError> {
+        match self.request::<serde_json::Value>(Method::DELETE, &self.site_url(site, path), None).await {
+            // A 404 means it is already gone from the controller
+            Err(UnifiError::Status(StatusCode::NOT_FOUND, _)) => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    pub async fn list_sites(&self) -> Result<Vec<Site>, UnifiError> {
+        self.request(Method::GET, &self.network_url("/api/self/sites"), None).await
+    }
+
+    pub async fn list_clients(&self, site: &str) -> Result<Vec<ClientDevice>, UnifiError> {
+        self.request(Method::GET, &self.site_url(site, "stat/sta"), None).await
+    }
+
+    pub async fn list_firewall_rules(&self, site: &str) -> Result<Vec<FirewallRule>, UnifiError> {
+        self.request(Method::GET, &self.site_url(site, "rest/firewallrule"), None).await
+    }
+
+    pub async fn get_firewall_rule(&self, site: &str, id: &str) -> Result<FirewallRule, UnifiError> {
+        self.request(Method::GET, &self.site_url(site, &format!("rest/firewallrule/{}", id)), None)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| UnifiError::InvalidResponse(format!("firewall rule {} not found", id)))
+    }
+
+    pub async fn create_firewall_rule(&self, site: &str, rule: &FirewallRule) -> Result<FirewallRule, UnifiError> {
+        self.create(site, "rest/firewallrule", rule).await
+    }
+
+    pub async fn update_firewall_rule(&self, site: &str, rule: &FirewallRule) -> Result<(), UnifiError> {
+        let id = rule.id.as_deref()
+            .ok_or_else(|| UnifiError::InvalidResponse("firewall rule has no ID".to_string()))?;
+        let body = serde_json::to_value(rule).map_err(|e| UnifiError::InvalidResponse(e.to_string()))?;
+        self.request::<serde_json::Value>(Method::PUT, &self.site_url(site, &format!("rest/firewallrule/{}", id)), Some(&body))
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn delete_firewall_rule(&self, site: &str, id: &str) -> Result<(), UnifiError> {
+        self.delete(site, &format!("rest/firewallrule/{}", id)).await
+    }
+
+    pub async fn list_firewall_groups(&self, site: &str) -> Result<Vec<FirewallGroup>, UnifiError> {
+        self.request(Method::GET, &self.site_url(site, "rest/firewallgroup"), None).await
+    }
+
+    pub async fn create_firewall_group(&self, site: &str, group: &FirewallGroup) -> Result<FirewallGroup, UnifiError> {
+        self.create(site, "rest/firewallgroup", group).await
+    }
+
+    pub async fn get_firewall_group(&self, site: &str, id: &str) -> Result<FirewallGroup, UnifiError> {
+        self.request(Method::GET, &self.site_url(site, &format!("rest/firewallgroup/{}", id)), None)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| UnifiError::InvalidResponse(format!("firewall group {} not found", id)))
+    }
+
+    pub async fn update_firewall_group(&self, site: &str, group: &FirewallGroup) -> Result<(), UnifiError> {
+        let id = group.id.as_deref()
+            .ok_or_else(|| UnifiError::InvalidResponse("firewall group has no ID".to_string()))?;
+        let body = serde_json::to_value(group).map_err(|e| UnifiError::InvalidResponse(e.to_string()))?;
+        self.request::<serde_json::Value>(Method::PUT, &self.site_url(site, &format!("rest/firewallgroup/{}", id)), Some(&body))
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn delete_firewall_group(&self, site: &str, id: &str) -> Result<(), UnifiError> {
+        self.delete(site, &format!("rest/firewallgroup/{}", id)).await
+    }
+
+    /// Keep a client off the network entirely until it is unblocked
+    pub async fn block_station(&self, site: &str, mac: &str) -> Result<(), UnifiError> {
+        self.station_command(site, "block-sta", mac).await
+    }
+
+    pub async fn unblock_station(&self, site: &str, mac: &str) -> Result<(), UnifiError> {
+        self.station_command(site, "unblock-sta", mac).await
+    }
+
+    async fn station_command(&self, site: &str, cmd: &str, mac: &str) -> Result<(), UnifiError> {
+        let body = serde_json::json!({ "cmd": cmd, "mac": mac });
+        self.request::<serde_json::Value>(Method::POST, &self.site_url(site, "cmd/stamgr"), Some(&body))
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn list_client_app_traffic(&self, site: &str, macs: &[String]) -> Result<Vec<ClientAppTraffic>, UnifiError> {
+        let body = serde_json::json!({ "type": "by_app", "macs": macs });
+        self.request(Method::POST, &self.site_url(site, "stat/stadpi"), Some(&body)).await
+    }
+
+    pub async fn list_dpi_applications(&self, site: &str) -> Result<Vec<DpiApplication>, UnifiError> {
+        self.request_v2(Method::GET, &self.network_url(&format!("/v2/api/site/{}/dpi/applications", site)), None).await
+    }
+
+    pub async fn list_dpi_categories(&self, site: &str) -> Result<Vec<DpiCategory>, UnifiError> {
+        self.request_v2(Method::GET, &self.network_url(&format!("/v2/api/site/{}/dpi/categories", site)), None).await
+    }
+
+    fn traffic_rules_url(&self, site: &str, id: Option<&str>) -> String {
+        match id {
+            Some(id) => self.network_url(&format!("/v2/api/site/{}/trafficrules/{}", site, id)),
+            None => self.network_url(&format!("/v2/api/site/{}/trafficrules", site)),
+        }
+    }
+
+    pub async fn list_traffic_rules(&self, site: &str) -> Result<Vec<TrafficRule>, UnifiError> {
+        self.request_v2(Method::GET, &self.traffic_rules_url(site, None), None).await
+    }
+
+    // The v2 API has no single-rule GET, so look it up in the list
+    pub async fn get_traffic_rule(&self, site: &str, id: &str) -> Result<TrafficRule, UnifiError> {
+        self.list_traffic_rules(site)
+            .await?
+            .into_iter()
+            .find(|r| r.id.as_deref() == Some(id))
+            .ok_or_else(|| UnifiError::InvalidResponse(format!("traffic rule {} not found", id)))
+    }
+
+    pub async fn create_traffic_rule(&self, site: &str, rule: &TrafficRule) -> Result<TrafficRule, UnifiError> {
+        let body = serde_json::to_value(rule).map_err(|e| UnifiError::InvalidResponse(e.to_string()))?;
+        self.request_v2(Method::POST, &self.traffic_rules_url(site, None), Some(&body)).await
+    }
+
+    pub async fn update_traffic_rule(&self, site: &str, rule: &TrafficRule) -> Result<(), UnifiError> {
+        let id = rule.id.as_deref()
+            .ok_or_else(|| UnifiError::InvalidResponse("traffic rule has no ID".to_string()))?;
+        let body = serde_json::to_value(rule).map_err(|e| UnifiError::InvalidResponse(e.to_string()))?;
+        self.request_v2::<serde_json::Value>(Method::PUT, &self.traffic_rules_url(site, Some(id)), Some(&body))
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn delete_traffic_rule(&self, site: &str, id: &str) -> Result<(), UnifiError> {
+        match self.request_v2::<serde_json::Value>(Method::DELETE, &self.traffic_rules_url(site, Some(id)), None).await {
+            // A 404 means it is already gone from the controller
+            Err(UnifiError::Status(StatusCode::NOT_FOUND, _)) => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+}
+
+// A URL that already includes /proxy/network talks to the Network application
+// directly; anything else is treated as the root of a UniFi OS console.
+fn points_at_network_app(base_url: &str) -> bool {
+    base_url.contains("/proxy/network")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_json, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn logged_in(token: &str, csrf: &str) -> ResponseTemplate {
+        ResponseTemplate::new(200)
+            .insert_header("set-cookie", format!("TOKEN={}; Path=/; HttpOnly", token).as_str())
+            .insert_header(CSRF_HEADER, csrf)
+    }
+
+    fn envelope(data: serde_json::Value) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(serde_json::json!({ "meta": { "rc": "ok" }, "data": data }))
+    }
+
+    async fn login(server: &MockServer, base_path: &str) -> UnifiClient {
+        let base_url = format!("{}{}", server.uri(), base_path);
+        UnifiClient::login(Client::new(), &base_url, "admin", "secret").await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn unifi_os_console_uses_its_login_and_the_network_proxy() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/auth/login"))
+            .and(body_json(serde_json::json!({ "username": "admin", "password": "secret" })))
+            .respond_with(logged_in("abc", "csrf-1"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/self/sites"))
+            .and(header("cookie", "TOKEN=abc"))
+            .respond_with(envelope(serde_json::json!([{ "name": "default", "desc": "Home" }])))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = login(&server, "/").await;
+        let sites = client.list_sites().await.unwrap();
+        assert_eq!(sites[0].name, "default");
+        assert_eq!(sites[0].desc, "Home");
+    }
+
+    #[tokio::test]
+    async fn network_app_url_uses_the_classic_login_and_paths() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/proxy/network/api/login"))
+            .respond_with(logged_in("abc", "csrf-1"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/v2/api/site/kids/trafficrules"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([])))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = login(&server, "/proxy/network").await;
+        assert!(client.list_traffic_rules("kids").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejected_login_is_reported() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/auth/login"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&server)
+            .await;
+
+        let result = UnifiClient::login(Client::new(), &server.uri(), "admin", "wrong").await;
+        assert!(matches!(result, Err(UnifiError::LoginFailed(StatusCode::UNAUTHORIZED))));
+    }
+
+    #[tokio::test]
+    async fn envelope_errors_are_reported() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/auth/login"))
+            .respond_with(logged_in("abc", "csrf-1"))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/proxy/network/api/s/default/cmd/stamgr"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(
+                serde_json::json!({ "meta": { "rc": "error", "msg": "api.err.UnknownStation" }, "data": [] }),
+            ))
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path("/proxy/network/api/s/default/rest/firewallgroup/g1"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(
+                serde_json::json!({ "meta": { "rc": "error", "msg": "api.err.InvalidPayload" }, "data": [] }),
+            ))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/self/sites"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("<html>not the API</html>"))
+            .mount(&server)
+            .await;
+
+        let client = login(&server, "").await;
+        match client.block_station("default", "aa:bb:cc:dd:ee:ff").await {
+            Err(UnifiError::Api(msg)) => assert_eq!(msg, "api.err.UnknownStation"),
+            other => panic!("expected an API error, got {:?}", other),
+        }
+        let group = FirewallGroup {
+            id: Some("g1".to_string()),
+            ..Default::default()
+        };
+        match client.update_firewall_group("default", &group).await {
+            Err(UnifiError::Status(StatusCode::BAD_REQUEST, msg)) => assert_eq!(msg, "api.err.InvalidPayload"),
+            other => panic!("expected a status error, got {:?}", other),
+        }
+        assert!(matches!(client.list_sites().await, Err(UnifiError::InvalidResponse(_))));
+    }
+
+    #[tokio::test]
+    async fn expired_session_logs_in_again_and_replays_with_the_new_csrf_token() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/auth/login"))
+            .respond_with(logged_in("first", "csrf-1"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/auth/login"))
+            .respond_with(logged_in("second", "csrf-2"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/proxy/network/api/s/default/cmd/stamgr"))
+            .and(header("cookie", "TOKEN=first"))
+            .and(header(CSRF_HEADER, "csrf-1"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/proxy/network/api/s/default/cmd/stamgr"))
+            .and(header("cookie", "TOKEN=second"))
+            .and(header(CSRF_HEADER, "csrf-2"))
+            .and(body_json(serde_json::json!({ "cmd": "block-sta", "mac": "aa:bb:cc:dd:ee:ff" })))
+            .respond_with(envelope(serde_json::json!([])))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = login(&server, "").await;
+        client.block_station("default", "aa:bb:cc:dd:ee:ff").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn classic_login_required_body_logs_in_again() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/proxy/network/api/login"))
+            .respond_with(logged_in("abc", "csrf-1"))
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/self/sites"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(
+                serde_json::json!({ "meta": { "rc": "error", "msg": "api.err.LoginRequired" }, "data": [] }),
+            ))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/self/sites"))
+            .respond_with(envelope(serde_json::json!([{ "name": "default" }])))
+            .mount(&server)
+            .await;
+
+        let client = login(&server, "/proxy/network").await;
+        assert_eq!(client.list_sites().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rotated_csrf_token_is_used_for_the_next_request() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/auth/login"))
+            .respond_with(logged_in("abc", "csrf-1"))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/proxy/network/api/s/default/cmd/stamgr"))
+            .and(header(CSRF_HEADER, "csrf-1"))
+            .respond_with(envelope(serde_json::json!([])).insert_header(UPDATED_CSRF_HEADER, "csrf-2"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/proxy/network/api/s/default/cmd/stamgr"))
+            .and(header(CSRF_HEADER, "csrf-2"))
+            .respond_with(envelope(serde_json::json!([])))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = login(&server, "").await;
+        client.block_station("default", "aa:bb:cc:dd:ee:ff").await.unwrap();
+        client.unblock_station("default", "aa:bb:cc:dd:ee:ff").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn api_key_is_sent_instead_of_cookies() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/proxy/network/api/self/sites"))
+            .and(header(API_KEY_HEADER, "key-123"))
+            .respond_with(envelope(serde_json::json!([{ "name": "default" }])))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let client = UnifiClient::with_api_key(Client::new(), &server.uri(), "key-123").await.unwrap();
+        client.list_sites().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_app_traffic_is_read_per_device_and_app() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/auth/login"))
+            .respond_with(logged_in("abc", "csrf-1"))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/proxy/network/api/s/default/stat/stadpi"))
+            .and(body_json(serde_json::json!({ "type": "by_app", "macs": ["aa:bb:cc:00:00:01"] })))
+            .respond_with(envelope(serde_json::json!([{
+                "mac": "aa:bb:cc:00:00:01",
+                "by_app": [
+                    { "app": 5, "cat": 4, "rx_bytes": 1000, "tx_bytes": 200, "known_clients": 1 },
+                    { "app": 13, "cat": 10 }
+                ]
+            }])))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = login(&server, "/").await;
+        let traffic = client.list_client_app_traffic("default", &["aa:bb:cc:00:00:01".to_string()]).await.unwrap();
+        assert_eq!(traffic.len(), 1);
+        assert_eq!(traffic[0].mac, "aa:bb:cc:00:00:01");
+        let apps = &traffic[0].by_app;
+        assert_eq!(apps[0].dpi_id(), (4 << 16) | 5);
+        assert_eq!((apps[0].rx_bytes, apps[0].tx_bytes), (1000, 200));
+        // Byte counts are left out for apps without traffic
+        assert_eq!((apps[1].dpi_id(), apps[1].rx_bytes, apps[1].tx_bytes), ((10 << 16) | 13, 0, 0));
+    }
+}